@@ -13,6 +13,32 @@ impl<T> Clone for Id<T> {
     }
 }
 
+impl<T> Id<T> {
+    pub fn index(&self) -> usize {
+        self.id
+    }
+}
+
+impl<T> std::fmt::Debug for Id<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Id({})", self.id)
+    }
+}
+
+impl<T> PartialEq for Id<T> {
+    fn eq(&self, other: &Id<T>) -> bool {
+        self.id == other.id
+    }
+}
+
+impl<T> Eq for Id<T> {}
+
+impl<T> std::hash::Hash for Id<T> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.id.hash(state)
+    }
+}
+
 pub struct Arena<T> {
     items: Vec<T>,
 }
@@ -38,4 +64,29 @@ impl<T> Arena<T> {
     pub fn get_mut(&mut self, id: Id<T>) -> &mut T {
         &mut self.items[id.id]
     }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = Id<T>> {
+        (0..self.items.len()).map(|id| Id {
+            id,
+            marker: PhantomData,
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Id<T>, &T)> {
+        self.ids().zip(self.items.iter())
+    }
+}
+
+impl<T> Default for Arena<T> {
+    fn default() -> Arena<T> {
+        Arena::new()
+    }
 }
@@ -1,25 +1,144 @@
 use crate::arena;
 use crate::blob;
 use crate::blob::{Blob, Builder};
+use crate::canon;
+use crate::intern;
 use crate::lex;
 
+/// The name of ninja's built-in rule, whose edges have no command and
+/// merely group their inputs under the names of their outputs.
+pub const PHONY: &blob::View = b"phony";
+
+/// The name of ninja's built-in pool of depth 1, whose edges have
+/// direct access to the terminal.
+pub const CONSOLE: &blob::View = b"console";
+
+/// The ninja version whose manifest language this crate implements.
+pub const VERSION: &str = "1.11.1";
+
+/// The top-level variable naming the oldest ninja a file supports.
+pub const REQUIRED_VERSION: &blob::View = b"ninja_required_version";
+
+/// The top-level variable naming the directory for state files.
+pub const BUILDDIR: &blob::View = b"builddir";
+
+/// The state files kept in the build directory.
+pub const NINJA_LOG: &blob::View = b".ninja_log";
+pub const NINJA_DEPS: &blob::View = b".ninja_deps";
+
+/// Splits a version into its major and minor numbers, ignoring any
+/// suffix such as a patch level or `.git`.
+fn parse_version(version: &blob::View) -> (u32, u32) {
+    let number = |part: Option<&blob::View>| {
+        part.map(|part| {
+            part.iter()
+                .take_while(|b| b.is_ascii_digit())
+                .fold(0u32, |n, b| {
+                    n.saturating_mul(10).saturating_add(u32::from(b - b'0'))
+                })
+        })
+        .unwrap_or(0)
+    };
+    let mut parts = version.split(|b| *b == b'.');
+    let major = number(parts.next());
+    let minor = number(parts.next());
+    (major, minor)
+}
+
+/// Checks a `ninja_required_version` value against [`VERSION`]. As in
+/// ninja, only the major and minor numbers are compared.
+pub fn check_version(required: &blob::View) -> Result<(), AstError> {
+    if parse_version(required) > parse_version(VERSION.as_bytes()) {
+        let required = Blob::new(required);
+        return Err(AstError::IncompatibleVersion { required });
+    }
+    Ok(())
+}
+
 #[derive(Debug)]
 pub enum AstError {
     DuplicateBinding,
+    DuplicateRule { previous: Box<lex::Location> },
+    DuplicatePool { previous: Box<lex::Location> },
+    VariableCycle,
+    BuiltinRule,
+    BuiltinPool,
+    UnknownRuleBinding,
+    MissingCommand,
+    RspfileMismatch,
+    UnknownDeps { deps: Blob },
+    UnknownPool { pool: Blob },
+    IncompatibleVersion { required: Blob },
+}
+
+impl AstError {
+    /// For redefinitions, where the name was first defined.
+    pub fn previous(&self) -> Option<&lex::Location> {
+        match self {
+            AstError::DuplicateRule { previous } => Some(previous),
+            AstError::DuplicatePool { previous } => Some(previous),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for AstError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let message = match self {
+            AstError::DuplicateBinding => "duplicate variable",
+            AstError::DuplicateRule { .. } => "duplicate rule",
+            AstError::DuplicatePool { .. } => "duplicate pool",
+            AstError::VariableCycle => "cycle in rule variables",
+            AstError::BuiltinRule => "cannot redefine built-in rule 'phony'",
+            AstError::BuiltinPool => "cannot redefine built-in pool 'console'",
+            AstError::UnknownRuleBinding => "unexpected variable in rule",
+            AstError::MissingCommand => "expected 'command =' line",
+            AstError::RspfileMismatch => "rspfile and rspfile_content need to be both specified",
+            AstError::UnknownDeps { deps } => {
+                return write!(f, "unknown deps type '{}'", String::from_utf8_lossy(deps));
+            }
+            AstError::UnknownPool { pool } => {
+                return write!(f, "unknown pool name '{}'", String::from_utf8_lossy(pool));
+            }
+            AstError::IncompatibleVersion { required } => {
+                return write!(
+                    f,
+                    "ninja version ({}) incompatible with build file ninja_required_version version ({})",
+                    VERSION,
+                    String::from_utf8_lossy(required)
+                );
+            }
+        };
+        f.write_str(message)
+    }
 }
 
+impl std::error::Error for AstError {}
+
 pub struct Declarations {
     declarations: Vec<Declaration>,
+    rules: std::collections::HashMap<(arena::Id<Scope>, lex::Identifier), usize>,
+    pools: std::collections::HashMap<lex::Identifier, usize>,
 }
 
 impl Declarations {
     pub fn new() -> Declarations {
         Declarations {
             declarations: vec![],
+            rules: std::collections::HashMap::new(),
+            pools: std::collections::HashMap::new(),
         }
     }
 
+    /// Rules are scoped to the file that declares them, so a `subninja`
+    /// may redefine a rule from an enclosing file.
     pub fn add_rule(&mut self, rule: Rule) -> Result<(), AstError> {
+        if let Some(previous) = self.rule(rule.scope, rule.name) {
+            let previous = Box::new(previous.location.clone());
+            return Err(AstError::DuplicateRule { previous });
+        }
+        let key = (rule.scope, rule.name);
+        self.rules.insert(key, self.declarations.len());
         self.declarations.push(Declaration::Rule(rule));
         Ok(())
     }
@@ -34,7 +153,13 @@ impl Declarations {
         Ok(())
     }
 
+    /// Unlike rules, pools are global.
     pub fn add_pool(&mut self, pool: Pool) -> Result<(), AstError> {
+        if let Some(previous) = self.pool(pool.name) {
+            let previous = Box::new(previous.location.clone());
+            return Err(AstError::DuplicatePool { previous });
+        }
+        self.pools.insert(pool.name, self.declarations.len());
         self.declarations.push(Declaration::Pool(pool));
         Ok(())
     }
@@ -42,18 +167,82 @@ impl Declarations {
     pub fn count(&self) -> usize {
         self.declarations.len()
     }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Declaration> {
+        self.declarations.iter()
+    }
+
+    /// Finds a rule declared directly in the file scope `scope`.
+    pub fn rule(&self, scope: arena::Id<Scope>, name: lex::Identifier) -> Option<&Rule> {
+        let index = self.rules.get(&(scope, name))?;
+        match &self.declarations[*index] {
+            Declaration::Rule(rule) => Some(rule),
+            _ => None,
+        }
+    }
+
+    /// Finds the rule named by a build, searching the file scope the
+    /// build appeared in and then its parents.
+    pub fn find_rule(&self, scopes: &Scopes, build: &Build) -> Option<&Rule> {
+        let mut scope = scopes.get_scope(build.scope).parent;
+        while let Some(id) = scope {
+            if let Some(rule) = self.rule(id, build.rule) {
+                return Some(rule);
+            }
+            scope = scopes.get_scope(id).parent;
+        }
+        None
+    }
+
+    pub fn pool(&self, name: lex::Identifier) -> Option<&Pool> {
+        let index = self.pools.get(&name)?;
+        match &self.declarations[*index] {
+            Declaration::Pool(pool) => Some(pool),
+            _ => None,
+        }
+    }
+}
+
+impl std::default::Default for Declarations {
+    fn default() -> Declarations {
+        Declarations::new()
+    }
 }
 
 pub struct File {
     declarations: Declarations,
     scopes: Scopes,
+    builddir: Option<Blob>,
 }
 
 impl File {
-    pub fn new(declarations: Declarations, scopes: Scopes) -> File {
+    pub fn new(declarations: Declarations, scopes: Scopes, builddir: Option<Blob>) -> File {
         File {
             declarations,
             scopes,
+            builddir,
+        }
+    }
+
+    /// The top-level `builddir`, if set and not empty.
+    pub fn builddir(&self) -> Option<&blob::View> {
+        self.builddir.as_ref().map(|builddir| builddir.view())
+    }
+
+    /// The path of a state file such as [`NINJA_LOG`], which lives in
+    /// the build directory when there is one.
+    pub fn state_path(&self, name: &blob::View) -> Blob {
+        match &self.builddir {
+            Some(builddir) => {
+                let mut builder = Builder::new();
+                builder.extend(builddir);
+                if !builddir.ends_with(b"/") {
+                    builder.extend(b"/");
+                }
+                builder.extend(name);
+                builder.blob()
+            }
+            None => Blob::new(name),
         }
     }
 
@@ -64,6 +253,57 @@ impl File {
     pub fn declarations_mut(&mut self) -> &mut Declarations {
         &mut self.declarations
     }
+
+    pub fn scopes(&self) -> &Scopes {
+        &self.scopes
+    }
+
+    pub fn rules(&self) -> impl Iterator<Item = &Rule> {
+        self.declarations
+            .iter()
+            .filter_map(|declaration| match declaration {
+                Declaration::Rule(rule) => Some(rule),
+                _ => None,
+            })
+    }
+
+    pub fn builds(&self) -> impl Iterator<Item = &Build> {
+        self.declarations
+            .iter()
+            .filter_map(|declaration| match declaration {
+                Declaration::Build(build) => Some(build),
+                _ => None,
+            })
+    }
+
+    pub fn defaults(&self) -> impl Iterator<Item = &Default> {
+        self.declarations
+            .iter()
+            .filter_map(|declaration| match declaration {
+                Declaration::Default(default) => Some(default),
+                _ => None,
+            })
+    }
+
+    pub fn pools(&self) -> impl Iterator<Item = &Pool> {
+        self.declarations
+            .iter()
+            .filter_map(|declaration| match declaration {
+                Declaration::Pool(pool) => Some(pool),
+                _ => None,
+            })
+    }
+
+    /// Looks up a top-level variable.
+    pub fn binding(&self, identifier: lex::Identifier) -> Option<&blob::View> {
+        self.scopes.get(self.scopes.top, identifier)
+    }
+
+    /// Finds the rule named by a build, searching the file scope the
+    /// build appeared in and then its parents.
+    pub fn rule(&self, build: &Build) -> Option<&Rule> {
+        self.declarations.find_rule(&self.scopes, build)
+    }
 }
 
 pub enum Declaration {
@@ -73,14 +313,266 @@ pub enum Declaration {
     Pool(Pool),
 }
 
+/// The variables a rule may bind.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum ReservedBinding {
+    Command,
+    Depfile,
+    Deps,
+    MsvcDepsPrefix,
+    Description,
+    Dyndep,
+    Generator,
+    Restat,
+    Rspfile,
+    RspfileContent,
+    Pool,
+}
+
+impl ReservedBinding {
+    const NAMES: [(&'static [u8], ReservedBinding); 11] = [
+        (b"command", ReservedBinding::Command),
+        (b"depfile", ReservedBinding::Depfile),
+        (b"deps", ReservedBinding::Deps),
+        (b"msvc_deps_prefix", ReservedBinding::MsvcDepsPrefix),
+        (b"description", ReservedBinding::Description),
+        (b"dyndep", ReservedBinding::Dyndep),
+        (b"generator", ReservedBinding::Generator),
+        (b"restat", ReservedBinding::Restat),
+        (b"rspfile", ReservedBinding::Rspfile),
+        (b"rspfile_content", ReservedBinding::RspfileContent),
+        (b"pool", ReservedBinding::Pool),
+    ];
+
+    /// Every reserved binding, in a canonical order.
+    pub fn all() -> impl Iterator<Item = ReservedBinding> {
+        ReservedBinding::NAMES.iter().map(|(_, binding)| *binding)
+    }
+
+    pub fn from_name(name: &blob::View) -> Option<ReservedBinding> {
+        ReservedBinding::NAMES
+            .iter()
+            .find(|(reserved, _)| *reserved == name)
+            .map(|(_, binding)| *binding)
+    }
+
+    /// Checks the value of a binding that ninja interprets, if it has no
+    /// variable references. Only `deps` has values that can be wrong.
+    pub fn check(self, value: &Value) -> Result<(), AstError> {
+        match self {
+            ReservedBinding::Deps => Setting::new(Some(value), DepsType::parse).map(|_| ()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn name(self) -> &'static blob::View {
+        ReservedBinding::NAMES
+            .iter()
+            .find(|(_, binding)| *binding == self)
+            .map(|(name, _)| *name)
+            .unwrap_or(b"")
+    }
+}
+
+/// The formats of dependency information that `deps` can name.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DepsType {
+    /// A Makefile-style depfile, as written by `gcc -MD`.
+    Gcc,
+    /// The `/showIncludes` output of the MSVC compiler.
+    Msvc,
+}
+
+impl DepsType {
+    /// Interprets the value of `deps`, which may be empty for none.
+    pub fn parse(deps: &blob::View) -> Result<Option<DepsType>, AstError> {
+        match deps {
+            b"" => Ok(None),
+            b"gcc" => Ok(Some(DepsType::Gcc)),
+            b"msvc" => Ok(Some(DepsType::Msvc)),
+            _ => Err(AstError::UnknownDeps {
+                deps: Blob::new(deps),
+            }),
+        }
+    }
+}
+
+/// A rule binding that ninja interprets rather than passes to commands.
+/// Values without variable references are interpreted when the rule is
+/// parsed, but the rest can only be once a build evaluates them.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Setting<T> {
+    Literal(T),
+    Deferred,
+}
+
+impl<T> Setting<T> {
+    /// Interprets `value`, or the empty string if the rule leaves the
+    /// setting unbound.
+    fn new(
+        value: Option<&Value>,
+        interpret: impl FnOnce(&blob::View) -> Result<T, AstError>,
+    ) -> Result<Setting<T>, AstError> {
+        let literal = match value {
+            Some(value) => value.literal(),
+            None => Some(Blob::empty()),
+        };
+        match literal {
+            Some(literal) => interpret(&literal).map(Setting::Literal),
+            None => Ok(Setting::Deferred),
+        }
+    }
+}
+
+fn flag(value: &blob::View) -> Result<bool, AstError> {
+    Ok(!value.is_empty())
+}
+
+fn pool_name(value: &blob::View) -> Result<Option<Blob>, AstError> {
+    Ok(Some(Blob::new(value)).filter(|pool| !pool.is_empty()))
+}
+
+/// A rule's bindings are kept unevaluated, as they may refer to
+/// variables such as `$in` and `$out` that only have values in the
+/// context of a particular build.
 pub struct Rule {
     name: lex::Identifier,
+    bindings: std::collections::HashMap<lex::Identifier, Value>,
+    reserved: std::collections::HashMap<ReservedBinding, lex::Identifier>,
+    deps: Setting<Option<DepsType>>,
+    generator: Setting<bool>,
+    restat: Setting<bool>,
+    pool: Setting<Option<Blob>>,
     scope: arena::Id<Scope>,
+    location: lex::Location,
 }
 
 impl Rule {
-    pub fn new(name: lex::Identifier, scope: arena::Id<Scope>) -> Rule {
-        Rule { name, scope }
+    /// Every rule needs a `command`, and `rspfile` and
+    /// `rspfile_content` must be bound together. The settings in
+    /// [`ReservedBinding::check`] are interpreted here too.
+    pub fn new(
+        name: lex::Identifier,
+        new_bindings: Vec<(ReservedBinding, Binding)>,
+        scope: arena::Id<Scope>,
+        location: lex::Location,
+    ) -> Result<Rule, AstError> {
+        let mut bindings = std::collections::HashMap::new();
+        let mut reserved = std::collections::HashMap::new();
+        for (key, binding) in new_bindings {
+            if bindings.insert(binding.id, binding.value).is_some() {
+                return Err(AstError::DuplicateBinding);
+            }
+            reserved.insert(key, binding.id);
+        }
+
+        if !reserved.contains_key(&ReservedBinding::Command) {
+            return Err(AstError::MissingCommand);
+        }
+        if reserved.contains_key(&ReservedBinding::Rspfile)
+            != reserved.contains_key(&ReservedBinding::RspfileContent)
+        {
+            return Err(AstError::RspfileMismatch);
+        }
+
+        let value = |key| {
+            let identifier = reserved.get(&key)?;
+            bindings.get(identifier)
+        };
+        let deps = Setting::new(value(ReservedBinding::Deps), DepsType::parse)?;
+        let generator = Setting::new(value(ReservedBinding::Generator), flag)?;
+        let restat = Setting::new(value(ReservedBinding::Restat), flag)?;
+        let pool = Setting::new(value(ReservedBinding::Pool), pool_name)?;
+
+        Ok(Rule {
+            name,
+            bindings,
+            reserved,
+            deps,
+            generator,
+            restat,
+            pool,
+            scope,
+            location,
+        })
+    }
+
+    pub fn location(&self) -> &lex::Location {
+        &self.location
+    }
+
+    pub fn name(&self) -> lex::Identifier {
+        self.name
+    }
+
+    /// Looks up a binding by name, as when evaluating a variable
+    /// reference.
+    pub fn binding(&self, identifier: lex::Identifier) -> Option<&Value> {
+        self.bindings.get(&identifier)
+    }
+
+    pub fn reserved(&self, key: ReservedBinding) -> Option<&Value> {
+        let identifier = self.reserved.get(&key)?;
+        self.bindings.get(identifier)
+    }
+
+    pub fn command(&self) -> &Value {
+        match self.reserved(ReservedBinding::Command) {
+            Some(command) => command,
+            None => unreachable!("rules always have a command"),
+        }
+    }
+
+    pub fn depfile(&self) -> Option<&Value> {
+        self.reserved(ReservedBinding::Depfile)
+    }
+
+    /// The rule's `deps`, if it does not depend on the build.
+    pub fn deps(&self) -> Setting<Option<DepsType>> {
+        self.deps
+    }
+
+    pub fn msvc_deps_prefix(&self) -> Option<&Value> {
+        self.reserved(ReservedBinding::MsvcDepsPrefix)
+    }
+
+    pub fn description(&self) -> Option<&Value> {
+        self.reserved(ReservedBinding::Description)
+    }
+
+    pub fn dyndep(&self) -> Option<&Value> {
+        self.reserved(ReservedBinding::Dyndep)
+    }
+
+    /// Whether `generator` is set to anything nonempty.
+    pub fn generator(&self) -> Setting<bool> {
+        self.generator
+    }
+
+    /// Whether `restat` is set to anything nonempty.
+    pub fn restat(&self) -> Setting<bool> {
+        self.restat
+    }
+
+    pub fn rspfile(&self) -> Option<&Value> {
+        self.reserved(ReservedBinding::Rspfile)
+    }
+
+    pub fn rspfile_content(&self) -> Option<&Value> {
+        self.reserved(ReservedBinding::RspfileContent)
+    }
+
+    /// The name of the rule's pool, or `None` for the default pool.
+    pub fn pool(&self) -> Setting<Option<&blob::View>> {
+        match &self.pool {
+            Setting::Literal(pool) => Setting::Literal(pool.as_ref().map(|pool| pool.view())),
+            Setting::Deferred => Setting::Deferred,
+        }
+    }
+
+    /// The file scope the rule was declared in.
+    pub fn scope(&self) -> arena::Id<Scope> {
+        self.scope
     }
 }
 
@@ -91,10 +583,12 @@ pub struct Build {
     inputs: Vec<Target>,
     implicit_inputs: Vec<Target>,
     order_inputs: Vec<Target>,
+    validations: Vec<Target>,
     scope: arena::Id<Scope>,
 }
 
 impl Build {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         outputs: Vec<Target>,
         implicit_outputs: Vec<Target>,
@@ -102,6 +596,7 @@ impl Build {
         inputs: Vec<Target>,
         implicit_inputs: Vec<Target>,
         order_inputs: Vec<Target>,
+        validations: Vec<Target>,
         scope: arena::Id<Scope>,
     ) -> Build {
         Build {
@@ -111,29 +606,93 @@ impl Build {
             inputs,
             implicit_inputs,
             order_inputs,
+            validations,
             scope,
         }
     }
+
+    pub fn outputs(&self) -> &[Target] {
+        &self.outputs
+    }
+
+    pub fn implicit_outputs(&self) -> &[Target] {
+        &self.implicit_outputs
+    }
+
+    pub fn rule(&self) -> lex::Identifier {
+        self.rule
+    }
+
+    pub fn inputs(&self) -> &[Target] {
+        &self.inputs
+    }
+
+    pub fn implicit_inputs(&self) -> &[Target] {
+        &self.implicit_inputs
+    }
+
+    pub fn order_inputs(&self) -> &[Target] {
+        &self.order_inputs
+    }
+
+    /// Targets listed after `|@`. They are built whenever this build
+    /// is, but the build does not wait for them.
+    pub fn validations(&self) -> &[Target] {
+        &self.validations
+    }
+
+    /// The scope holding the build's own bindings. Its targets are
+    /// evaluated in this scope.
+    pub fn scope(&self) -> arena::Id<Scope> {
+        self.scope
+    }
 }
 
 pub struct Default {
     targets: Vec<Target>,
+    scope: arena::Id<Scope>,
 }
 
 impl Default {
-    pub fn new(targets: Vec<Target>) -> Default {
-        Default { targets }
+    pub fn new(targets: Vec<Target>, scope: arena::Id<Scope>) -> Default {
+        Default { targets, scope }
+    }
+
+    pub fn targets(&self) -> &[Target] {
+        &self.targets
+    }
+
+    /// The file scope the targets are evaluated in.
+    pub fn scope(&self) -> arena::Id<Scope> {
+        self.scope
     }
 }
 
 pub struct Pool {
     name: lex::Identifier,
     depth: usize,
+    location: lex::Location,
 }
 
 impl Pool {
-    pub fn new(name: lex::Identifier, depth: usize) -> Pool {
-        Pool { name, depth }
+    pub fn new(name: lex::Identifier, depth: usize, location: lex::Location) -> Pool {
+        Pool {
+            name,
+            depth,
+            location,
+        }
+    }
+
+    pub fn location(&self) -> &lex::Location {
+        &self.location
+    }
+
+    pub fn name(&self) -> lex::Identifier {
+        self.name
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
     }
 }
 
@@ -142,9 +701,29 @@ pub struct Value {
 }
 
 impl Value {
-    pub fn new(value: lex::Value) -> Value {
+    pub(crate) fn new(value: lex::Value) -> Value {
         Value { value }
     }
+
+    pub fn parts(&self) -> &[lex::ValuePart] {
+        &self.value.parts
+    }
+
+    pub fn evaluate(&self, scopes: &Scopes, scope: arena::Id<Scope>) -> Blob {
+        scopes.evaluate(scope, &self.value)
+    }
+
+    /// The text of a value without variable references.
+    pub fn literal(&self) -> Option<Blob> {
+        let mut builder = Builder::new();
+        for part in self.value.parts.iter() {
+            match part {
+                lex::ValuePart::Text(text) => builder.extend(text),
+                lex::ValuePart::Variable(_) => return None,
+            }
+        }
+        Some(builder.blob())
+    }
 }
 
 pub struct Target {
@@ -152,9 +731,17 @@ pub struct Target {
 }
 
 impl Target {
-    pub fn new(value: lex::Value) -> Target {
+    pub(crate) fn new(value: lex::Value) -> Target {
         Target { value }
     }
+
+    pub fn parts(&self) -> &[lex::ValuePart] {
+        &self.value.parts
+    }
+
+    pub fn evaluate(&self, scopes: &Scopes, scope: arena::Id<Scope>) -> Blob {
+        scopes.evaluate(scope, &self.value)
+    }
 }
 
 pub struct Scopes {
@@ -169,10 +756,27 @@ impl Scopes {
         Scopes { arena, top }
     }
 
-    pub fn new_scope(&mut self, bindings: Vec<Binding>) -> Result<arena::Id<Scope>, AstError> {
-        let scope = Scope::new(bindings, Some(self.top))?;
-        let id = self.arena.insert(scope);
-        Ok(id)
+    /// Creates a child of `parent`. As in ninja, the new bindings are
+    /// evaluated in the parent, and so cannot refer to each other.
+    pub fn new_scope(
+        &mut self,
+        parent: arena::Id<Scope>,
+        bindings: Vec<Binding>,
+    ) -> arena::Id<Scope> {
+        let mut scope = Scope::empty(Some(parent));
+        for binding in bindings {
+            let value = binding.value.evaluate(self, parent);
+            scope.insert(binding.id, value);
+        }
+        self.arena.insert(scope)
+    }
+
+    /// Evaluates `binding` in `id` and then adds it to that scope. Like
+    /// ninja, this replaces any earlier value of the variable, so that
+    /// `cflags = $cflags -g` appends to it.
+    pub fn bind(&mut self, id: arena::Id<Scope>, binding: Binding) {
+        let value = binding.value.evaluate(self, id);
+        self.get_scope_mut(id).insert(binding.id, value)
     }
 
     pub fn top(&self) -> arena::Id<Scope> {
@@ -199,16 +803,53 @@ impl Scopes {
             }
         }
     }
+
+    pub(crate) fn evaluate(&self, id: arena::Id<Scope>, value: &lex::Value) -> Blob {
+        let mut builder = Builder::new();
+        for part in value.parts.iter() {
+            match part {
+                lex::ValuePart::Text(text) => builder.extend(text),
+                lex::ValuePart::Variable(variable) => {
+                    let text = self.get(id, *variable).unwrap_or(b"");
+                    builder.extend(text);
+                }
+            }
+        }
+        builder.blob()
+    }
+}
+
+impl std::default::Default for Scopes {
+    fn default() -> Scopes {
+        Scopes::new()
+    }
 }
 
 pub struct Binding {
     id: lex::Identifier,
-    value: Blob,
+    value: Value,
+    location: lex::SourceLocation,
 }
 
 impl Binding {
-    pub fn new(id: lex::Identifier, value: Blob) -> Binding {
-        Binding { id, value }
+    pub(crate) fn new(id: lex::Identifier, value: Value, location: lex::SourceLocation) -> Binding {
+        Binding {
+            id,
+            value,
+            location,
+        }
+    }
+
+    pub fn name(&self) -> lex::Identifier {
+        self.id
+    }
+
+    pub fn value(&self) -> &Value {
+        &self.value
+    }
+
+    pub(crate) fn location(&self) -> lex::SourceLocation {
+        self.location
     }
 }
 
@@ -223,46 +864,170 @@ impl Scope {
         Scope { bindings, parent }
     }
 
-    pub fn new(
-        new_bindings: Vec<Binding>,
-        parent: Option<arena::Id<Scope>>,
-    ) -> Result<Scope, AstError> {
-        let mut scope = Scope::empty(parent);
+    /// Sets a variable, replacing any earlier value.
+    pub fn insert(&mut self, identifier: lex::Identifier, value: Blob) {
+        self.bindings.insert(identifier, value);
+    }
 
-        for binding in new_bindings {
-            scope.push(binding)?;
-        }
+    pub fn get(&self, identifier: lex::Identifier) -> Option<&blob::View> {
+        self.bindings.get(&identifier).map(|v| v.as_ref())
+    }
 
-        Ok(scope)
+    pub fn size(&self) -> usize {
+        self.bindings.len()
     }
 
-    pub fn push(&mut self, binding: Binding) -> Result<(), AstError> {
-        if self.bindings.insert(binding.id, binding.value).is_some() {
-            Err(AstError::DuplicateBinding)
-        } else {
-            Ok(())
+    pub fn bindings(&self) -> impl Iterator<Item = (lex::Identifier, &blob::View)> {
+        self.bindings
+            .iter()
+            .map(|(name, value)| (*name, value.view()))
+    }
+
+    pub fn parent(&self) -> Option<arena::Id<Scope>> {
+        self.parent
+    }
+}
+
+/// The variables visible to a build's commands. Lookups consult the
+/// build's own bindings, then its rule's bindings, and then the file
+/// scopes enclosing the build. The special variables `$in`,
+/// `$in_newline`, and `$out` are computed from the build's explicit
+/// inputs and outputs.
+pub struct Env<'a> {
+    scopes: &'a Scopes,
+    build: &'a Build,
+    rule: &'a Rule,
+    in_: lex::Identifier,
+    in_newline: lex::Identifier,
+    out: lex::Identifier,
+}
+
+impl<'a> Env<'a> {
+    pub fn new(
+        arena: &mut intern::Table,
+        scopes: &'a Scopes,
+        build: &'a Build,
+        rule: &'a Rule,
+    ) -> Env<'a> {
+        let in_ = lex::Identifier::new(arena, b"in");
+        let in_newline = lex::Identifier::new(arena, b"in_newline");
+        let out = lex::Identifier::new(arena, b"out");
+        Env {
+            scopes,
+            build,
+            rule,
+            in_,
+            in_newline,
+            out,
         }
     }
 
-    pub fn get(&self, identifier: lex::Identifier) -> Option<&blob::View> {
-        self.bindings.get(&identifier).map(|v| v.as_ref())
+    pub fn get(&self, identifier: lex::Identifier) -> Result<Blob, AstError> {
+        self.lookup(identifier, &mut vec![])
     }
 
-    pub fn size(&self) -> usize {
-        self.bindings.len()
+    /// The value of the rule setting `identifier` for this build, if the
+    /// rule gave it without variable references and the build does not
+    /// bind it itself.
+    pub fn literal<T>(&self, identifier: lex::Identifier, setting: Setting<T>) -> Option<T> {
+        let scope = self.scopes.get_scope(self.build.scope);
+        match setting {
+            Setting::Literal(value) if scope.get(identifier).is_none() => Some(value),
+            _ => None,
+        }
     }
 
-    pub fn evaluate(&self, value: &Value) -> Blob {
+    pub fn evaluate(&self, value: &Value) -> Result<Blob, AstError> {
+        self.evaluate_parts(&value.value, &mut vec![])
+    }
+
+    fn evaluate_parts(
+        &self,
+        value: &lex::Value,
+        stack: &mut Vec<lex::Identifier>,
+    ) -> Result<Blob, AstError> {
         let mut builder = Builder::new();
-        for part in value.value.parts.iter() {
+        for part in value.parts.iter() {
             match part {
                 lex::ValuePart::Text(text) => builder.extend(text),
                 lex::ValuePart::Variable(variable) => {
-                    let text = self.get(*variable).unwrap_or(b"");
-                    builder.extend(text);
+                    let text = self.lookup(*variable, stack)?;
+                    builder.extend(&text);
                 }
             }
         }
+        Ok(builder.blob())
+    }
+
+    fn lookup(
+        &self,
+        identifier: lex::Identifier,
+        stack: &mut Vec<lex::Identifier>,
+    ) -> Result<Blob, AstError> {
+        if identifier == self.in_ {
+            return Ok(self.paths(&self.build.inputs, b' '));
+        }
+        if identifier == self.in_newline {
+            return Ok(self.paths(&self.build.inputs, b'\n'));
+        }
+        if identifier == self.out {
+            return Ok(self.paths(&self.build.outputs, b' '));
+        }
+
+        let scope = self.scopes.get_scope(self.build.scope);
+        if let Some(value) = scope.get(identifier) {
+            return Ok(Blob::new(value));
+        }
+
+        if let Some(value) = self.rule.binding(identifier) {
+            if stack.contains(&identifier) {
+                return Err(AstError::VariableCycle);
+            }
+            stack.push(identifier);
+            let value = self.evaluate_parts(&value.value, stack)?;
+            stack.pop();
+            return Ok(value);
+        }
+
+        match scope.parent {
+            Some(parent) => Ok(Blob::new(
+                self.scopes.get(parent, identifier).unwrap_or(b""),
+            )),
+            None => Ok(Blob::empty()),
+        }
+    }
+
+    fn paths(&self, targets: &[Target], separator: u8) -> Blob {
+        let mut builder = Builder::new();
+        for (index, target) in targets.iter().enumerate() {
+            if index > 0 {
+                builder.push(separator);
+            }
+            // As in ninja, commands see canonical paths, but with their
+            // original separators.
+            let path = target.evaluate(self.scopes, self.build.scope);
+            let (path, slash_bits) = canon::canonicalize(&path);
+            let path = canon::decanonicalize(&path, slash_bits);
+            shell_escape(&mut builder, &path);
+        }
         builder.blob()
     }
 }
+
+fn shell_escape(builder: &mut Builder, path: &blob::View) {
+    let is_safe = |b: &u8| matches!(b, b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'+' | b'-' | b'.' | b'/');
+    if path.iter().all(is_safe) {
+        builder.extend(path);
+        return;
+    }
+
+    builder.push(b'\'');
+    for b in path.iter() {
+        if *b == b'\'' {
+            builder.extend(b"'\\''");
+        } else {
+            builder.push(*b);
+        }
+    }
+    builder.push(b'\'');
+}
@@ -3,7 +3,7 @@ use std::ops::Deref;
 
 pub type View = [u8];
 
-#[derive(Eq, PartialEq, Hash)]
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct Blob {
     bytes: Box<View>,
 }
@@ -25,7 +25,15 @@ impl Blob {
     }
 
     pub fn view(&self) -> &View {
-        &*self.bytes
+        &self.bytes
+    }
+}
+
+impl From<Vec<u8>> for Blob {
+    fn from(vec: Vec<u8>) -> Blob {
+        Blob {
+            bytes: vec.into_boxed_slice(),
+        }
     }
 }
 
@@ -43,6 +51,28 @@ impl Deref for Blob {
     }
 }
 
+#[cfg(unix)]
+pub fn to_path(view: &View) -> std::path::PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::OsStr::from_bytes(view).into()
+}
+
+#[cfg(not(unix))]
+pub fn to_path(view: &View) -> std::path::PathBuf {
+    String::from_utf8_lossy(view).into_owned().into()
+}
+
+#[cfg(unix)]
+pub fn from_path(path: &std::path::Path) -> Blob {
+    use std::os::unix::ffi::OsStrExt;
+    Blob::new(path.as_os_str().as_bytes())
+}
+
+#[cfg(not(unix))]
+pub fn from_path(path: &std::path::Path) -> Blob {
+    Blob::new(path.to_string_lossy().as_bytes())
+}
+
 pub struct Builder {
     bytes: Vec<u8>,
 }
@@ -66,3 +96,9 @@ impl Builder {
         self.bytes.extend_from_slice(bytes)
     }
 }
+
+impl Default for Builder {
+    fn default() -> Builder {
+        Builder::new()
+    }
+}
@@ -0,0 +1,309 @@
+//! Path canonicalization, as in ninja's `CanonicalizePath`.
+//!
+//! Canonical paths have no `.` components, no empty components, and no
+//! `..` components except at the start. On Windows, backslashes are
+//! separators too. They are replaced with slashes, and the positions
+//! they held are recorded in a bitmask so the original spelling can be
+//! restored when the path is passed to a command.
+
+use crate::blob;
+use crate::blob::Blob;
+
+/// Canonicalizes `path`, returning it along with a mask in which bit
+/// `n` is set if the `n`th separator was a backslash.
+pub fn canonicalize(path: &blob::View) -> (Blob, u64) {
+    canonicalize_with(path, cfg!(windows))
+}
+
+/// Undoes the replacement of backslashes by slashes recorded in
+/// `slash_bits`.
+pub fn decanonicalize(path: &blob::View, slash_bits: u64) -> Blob {
+    let mut path = path.to_vec();
+    let mut separator = 0;
+    for b in path.iter_mut() {
+        if *b == b'/' {
+            if separator < 64 && slash_bits & (1 << separator) != 0 {
+                *b = b'\\';
+            }
+            separator += 1;
+        }
+    }
+    Blob::from(path)
+}
+
+/// Canonicalizes `path`, treating backslashes as separators if
+/// `backslashes` is set, as they are on Windows.
+pub(crate) fn canonicalize_with(path: &blob::View, backslashes: bool) -> (Blob, u64) {
+    let separator = |b: u8| b == b'/' || (backslashes && b == b'\\');
+    let end = path.len();
+    if end == 0 {
+        return (Blob::empty(), 0);
+    }
+
+    let mut out = Vec::with_capacity(end);
+    let mut src = 0;
+    if separator(path[0]) {
+        // Keep the root, and on Windows the `//` of a network path.
+        src = if backslashes && end > 1 && separator(path[1]) {
+            2
+        } else {
+            1
+        };
+        out.extend_from_slice(&path[..src]);
+    }
+    let root = out.len();
+
+    // Where each component other than a leading `..` starts in `out`,
+    // for backing up over it.
+    let mut components = vec![];
+    while src < end {
+        if path[src] == b'.' {
+            if src + 1 == end || separator(path[src + 1]) {
+                src += 2;
+                continue;
+            }
+            if path[src + 1] == b'.' && (src + 2 == end || separator(path[src + 2])) {
+                match components.pop() {
+                    Some(start) => out.truncate(start),
+                    None => out.extend_from_slice(&path[src..end.min(src + 3)]),
+                }
+                src += 3;
+                continue;
+            }
+        }
+
+        if separator(path[src]) {
+            src += 1;
+            continue;
+        }
+
+        components.push(out.len());
+        while src < end && !separator(path[src]) {
+            out.push(path[src]);
+            src += 1;
+        }
+        if src < end {
+            out.push(path[src]);
+            src += 1;
+        }
+    }
+
+    if out.len() > root && separator(out[out.len() - 1]) {
+        out.pop();
+    }
+    if out.is_empty() {
+        out.push(b'.');
+    }
+
+    let mut slash_bits = 0;
+    let mut mask = 1u64;
+    for b in out.iter_mut() {
+        if separator(*b) {
+            if *b == b'\\' {
+                slash_bits |= mask;
+                *b = b'/';
+            }
+            mask <<= 1;
+        }
+    }
+
+    (Blob::from(out), slash_bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn canonical(path: &str, backslashes: bool) -> (String, u64) {
+        let (path, slash_bits) = canonicalize_with(path.as_bytes(), backslashes);
+        let path = String::from_utf8(path.to_vec()).expect("invalid utf8");
+        (path, slash_bits)
+    }
+
+    #[test]
+    fn path_samples() {
+        let samples = [
+            ("", ""),
+            ("foo.h", "foo.h"),
+            ("./foo.h", "foo.h"),
+            ("./foo/./bar.h", "foo/bar.h"),
+            ("./x/foo/../bar.h", "x/bar.h"),
+            ("./x/foo/../../bar.h", "bar.h"),
+            ("foo//bar", "foo/bar"),
+            ("foo//.//..///bar", "bar"),
+            ("./x/../foo/../../bar.h", "../bar.h"),
+            ("foo/./.", "foo"),
+            ("foo/bar/..", "foo"),
+            ("foo/.hidden_bar", "foo/.hidden_bar"),
+            ("/foo", "/foo"),
+            ("//foo", "/foo"),
+            ("..", ".."),
+            ("../", ".."),
+            ("../foo", "../foo"),
+            ("../foo/", "../foo"),
+            ("../..", "../.."),
+            ("../../", "../.."),
+            ("./../", ".."),
+            ("/..", "/.."),
+            ("/../", "/.."),
+            ("/../..", "/../.."),
+            ("/../../", "/../.."),
+            ("/", "/"),
+            ("/foo/..", "/"),
+            (".", "."),
+            ("./.", "."),
+            ("foo/..", "."),
+            ("foo/.._bar", "foo/.._bar"),
+        ];
+        for (path, expected) in samples.iter() {
+            assert_eq!(
+                canonical(path, false).0,
+                *expected,
+                "canonicalizing {}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn up_dir() {
+        assert_eq!(canonical("../../foo/bar.h", false).0, "../../foo/bar.h");
+        assert_eq!(canonical("test/../../foo/bar.h", false).0, "../foo/bar.h");
+    }
+
+    #[test]
+    fn absolute_path() {
+        assert_eq!(
+            canonical("/usr/include/stdio.h", false).0,
+            "/usr/include/stdio.h"
+        );
+    }
+
+    #[test]
+    fn windows_path_samples() {
+        let samples = [
+            ("foo\\bar", "foo/bar"),
+            (".\\foo.h", "foo.h"),
+            (".\\foo\\.\\bar.h", "foo/bar.h"),
+            (".\\x\\foo\\..\\..\\bar.h", "bar.h"),
+            ("foo\\\\bar", "foo/bar"),
+            ("foo\\\\.\\\\..\\\\\\bar", "bar"),
+            (".\\x\\..\\foo\\..\\..\\bar.h", "../bar.h"),
+            ("foo\\.\\.", "foo"),
+            ("foo\\bar\\..", "foo"),
+            ("\\\\foo", "//foo"),
+            ("//foo", "//foo"),
+            ("\\", "/"),
+        ];
+        for (path, expected) in samples.iter() {
+            assert_eq!(
+                canonical(path, true).0,
+                *expected,
+                "canonicalizing {}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn slash_tracking() {
+        let samples = [
+            ("foo.h", "foo.h", 0),
+            ("a\\foo.h", "a/foo.h", 1),
+            ("a/bcd/efh\\foo.h", "a/bcd/efh/foo.h", 4),
+            ("a\\bcd/efh\\foo.h", "a/bcd/efh/foo.h", 5),
+            ("a\\bcd\\efh\\foo.h", "a/bcd/efh/foo.h", 7),
+            ("a/bcd/efh/foo.h", "a/bcd/efh/foo.h", 0),
+            ("a\\./efh\\foo.h", "a/efh/foo.h", 3),
+            ("a\\../efh\\foo.h", "efh/foo.h", 1),
+            ("a\\b\\c\\d\\e\\f\\g\\foo.h", "a/b/c/d/e/f/g/foo.h", 127),
+            ("a\\b\\c\\..\\..\\..\\g\\foo.h", "g/foo.h", 1),
+            ("a/b\\c\\..\\..\\..\\g\\foo.h", "g/foo.h", 1),
+            ("a\\b/c\\../../..\\g\\foo.h", "g/foo.h", 1),
+            ("a\\b/c\\./../..\\g\\foo.h", "a/g/foo.h", 3),
+            ("a\\b/c\\./../..\\g/foo.h", "a/g/foo.h", 1),
+            ("a\\\\\\foo.h", "a/foo.h", 1),
+            ("a/\\\\foo.h", "a/foo.h", 0),
+            ("a\\//foo.h", "a/foo.h", 1),
+        ];
+        for (path, expected, bits) in samples.iter() {
+            let (canonical, slash_bits) = canonical(path, true);
+            assert_eq!(canonical, *expected, "canonicalizing {}", path);
+            assert_eq!(slash_bits, *bits, "slash bits of {}", path);
+        }
+    }
+
+    #[test]
+    fn decanonicalize_restores_backslashes() {
+        let (path, slash_bits) = canonicalize_with(b"a\\b/c\\d", true);
+        assert_eq!(decanonicalize(&path, slash_bits).view(), b"a\\b/c\\d");
+        assert_eq!(decanonicalize(b"a/b", 0).view(), b"a/b");
+    }
+
+    #[test]
+    fn many_separators() {
+        let path = "a/".repeat(70) + "b";
+        let (canonical, slash_bits) = canonical(&path, true);
+        assert_eq!(canonical, path);
+        assert_eq!(slash_bits, 0);
+        let path = "a\\".repeat(70) + "b";
+        let (_, slash_bits) = canonicalize_with(path.as_bytes(), true);
+        assert_eq!(slash_bits, u64::MAX);
+    }
+
+    /// Calls `check` with every path of up to `len` bytes drawn from
+    /// `alphabet`.
+    fn every_path(alphabet: &[u8], len: usize, check: &mut dyn FnMut(&[u8])) {
+        let mut path = vec![];
+        fn visit(path: &mut Vec<u8>, alphabet: &[u8], len: usize, check: &mut dyn FnMut(&[u8])) {
+            check(path);
+            if path.len() == len {
+                return;
+            }
+            for b in alphabet.iter() {
+                path.push(*b);
+                visit(path, alphabet, len, check);
+                path.pop();
+            }
+        }
+        visit(&mut path, alphabet, len, check);
+    }
+
+    #[test]
+    fn properties() {
+        for backslashes in [false, true].iter() {
+            let alphabet: &[u8] = if *backslashes { b"a./\\" } else { b"a./" };
+            every_path(alphabet, 7, &mut |path| {
+                let (canonical, slash_bits) = canonicalize_with(path, *backslashes);
+                let (again, bits_again) = canonicalize_with(&canonical, *backslashes);
+                assert_eq!(canonical, again, "not idempotent for {:?}", path);
+                assert_eq!(bits_again, 0);
+                if path.is_empty() {
+                    return;
+                }
+
+                let start = canonical.iter().position(|b| *b != b'/');
+                let body = &canonical[start.unwrap_or(canonical.len())..];
+                if body != b"." && !body.is_empty() {
+                    assert!(
+                        body.split(|b| *b == b'/')
+                            .all(|c| c != b"." && !c.is_empty()),
+                        "{:?} canonicalized to {:?}",
+                        path,
+                        canonical
+                    );
+                }
+                if *backslashes {
+                    assert!(!canonical.contains(&b'\\'));
+                }
+
+                let separators = canonical.iter().filter(|b| **b == b'/').count();
+                assert_eq!(slash_bits >> separators, 0);
+                let original = decanonicalize(&canonical, slash_bits);
+                let (restored, restored_bits) = canonicalize_with(&original, *backslashes);
+                assert_eq!(restored, canonical);
+                assert_eq!(restored_bits, slash_bits);
+            });
+        }
+    }
+}
@@ -0,0 +1,667 @@
+//! A lossless concrete syntax tree.
+//!
+//! Unlike the AST, the tree keeps every byte of its input, including
+//! comments, indentation and `$\n` continuations, so a file can be
+//! edited and written back with only the intended change. The tree is
+//! purely syntactic: nothing is evaluated or checked, and input that
+//! does not parse is kept in `Error` tokens. It is built from the
+//! tokens of the lexer's lossless mode, so it follows the same grammar
+//! as the parser.
+
+use crate::blob;
+use crate::blob::{Blob, Builder};
+use crate::intern;
+use crate::lex;
+use crate::lex::{DeclKind, Lexer, Piece, PieceKind};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NodeKind {
+    File,
+    Rule,
+    Build,
+    Pool,
+    Default,
+    Include,
+    Subninja,
+    /// A `name = value` line, at the top level or in a declaration.
+    Binding,
+    Path,
+    Value,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TokenKind {
+    Keyword,
+    Identifier,
+    Equal,
+    Colon,
+    Pipe,
+    PipePipe,
+    PipeAt,
+
+    /// Literal text in a path or value.
+    Text,
+    /// `$$`, `$ ` or `$:`.
+    Escape,
+    /// `$name` or `${name}`.
+    Variable,
+
+    Whitespace,
+    /// `$` and a line ending, with the indentation that follows.
+    Continuation,
+    Indent,
+    Newline,
+    Comment,
+
+    /// Input that could not be parsed.
+    Error,
+}
+
+#[derive(Clone, Debug)]
+pub struct Token {
+    kind: TokenKind,
+    text: Blob,
+}
+
+impl Token {
+    pub fn new(kind: TokenKind, text: &blob::View) -> Token {
+        Token {
+            kind,
+            text: Blob::new(text),
+        }
+    }
+
+    pub fn kind(&self) -> TokenKind {
+        self.kind
+    }
+
+    pub fn text(&self) -> &blob::View {
+        &self.text
+    }
+
+    pub fn set_text(&mut self, text: &blob::View) {
+        self.text = Blob::new(text);
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Element {
+    Node(Node),
+    Token(Token),
+}
+
+impl Element {
+    fn write(&self, builder: &mut Builder) {
+        match self {
+            Element::Node(node) => node.write(builder),
+            Element::Token(token) => builder.extend(&token.text),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Node {
+    kind: NodeKind,
+    children: Vec<Element>,
+}
+
+impl Node {
+    pub fn new(kind: NodeKind, children: Vec<Element>) -> Node {
+        Node { kind, children }
+    }
+
+    pub fn kind(&self) -> NodeKind {
+        self.kind
+    }
+
+    pub fn children(&self) -> &[Element] {
+        &self.children
+    }
+
+    pub fn children_mut(&mut self) -> &mut Vec<Element> {
+        &mut self.children
+    }
+
+    /// The child nodes, skipping tokens.
+    pub fn nodes(&self) -> impl Iterator<Item = &Node> {
+        self.children.iter().filter_map(|child| match child {
+            Element::Node(node) => Some(node),
+            Element::Token(_) => None,
+        })
+    }
+
+    /// Every token in the subtree, in input order.
+    pub fn tokens(&self) -> Vec<&Token> {
+        let mut tokens = vec![];
+        self.collect_tokens(&mut tokens);
+        tokens
+    }
+
+    fn collect_tokens<'a>(&'a self, tokens: &mut Vec<&'a Token>) {
+        for child in self.children.iter() {
+            match child {
+                Element::Node(node) => node.collect_tokens(tokens),
+                Element::Token(token) => tokens.push(token),
+            }
+        }
+    }
+
+    /// The text of the subtree. For an unedited tree, this is exactly
+    /// the input it was parsed from.
+    pub fn text(&self) -> Blob {
+        let mut builder = Builder::new();
+        self.write(&mut builder);
+        builder.blob()
+    }
+
+    fn write(&self, builder: &mut Builder) {
+        for child in self.children.iter() {
+            child.write(builder)
+        }
+    }
+
+    fn accepts_bindings(&self) -> bool {
+        matches!(self.kind, NodeKind::Rule | NodeKind::Build | NodeKind::Pool)
+    }
+}
+
+/// Parses `input` into a tree whose root is a `File` node.
+pub fn parse(input: &blob::View) -> Node {
+    let mut reader = Reader {
+        lexer: Lexer::lossless(b"", input),
+        input,
+        arena: intern::Table::new(),
+    };
+    let mut file = vec![];
+    // A declaration whose bindings may follow, and the comment lines
+    // seen since its last binding. Comments only stay with the
+    // declaration if another binding follows them.
+    let mut open: Option<Node> = None;
+    let mut comments = vec![];
+
+    while let Some(line) = reader.line() {
+        match line {
+            Line::Comment(mut children) => comments.append(&mut children),
+            Line::Indented(binding) => match &mut open {
+                Some(node) => {
+                    node.children.append(&mut comments);
+                    node.children.push(binding);
+                }
+                None => {
+                    file.append(&mut comments);
+                    file.push(binding);
+                }
+            },
+            Line::Blank(mut children) => {
+                if let Some(node) = open.take() {
+                    file.push(Element::Node(node));
+                }
+                file.append(&mut comments);
+                file.append(&mut children);
+            }
+            Line::Declaration(declaration) => {
+                if let Some(node) = open.take() {
+                    file.push(Element::Node(node));
+                }
+                file.append(&mut comments);
+                match declaration {
+                    Element::Node(node) if node.accepts_bindings() => open = Some(node),
+                    declaration => file.push(declaration),
+                }
+            }
+        }
+    }
+
+    if let Some(node) = open.take() {
+        file.push(Element::Node(node));
+    }
+    file.append(&mut comments);
+    Node::new(NodeKind::File, file)
+}
+
+enum Line {
+    Comment(Vec<Element>),
+    Blank(Vec<Element>),
+    Indented(Element),
+    Declaration(Element),
+}
+
+/// Builds the tree from the pieces of input recorded by a lossless
+/// lexer, a line at a time. Anything the lexer rejects is kept as an
+/// error up to the end of its line.
+struct Reader<'input> {
+    lexer: Lexer<'input>,
+    input: &'input blob::View,
+    arena: intern::Table,
+}
+
+impl<'input> Reader<'input> {
+    fn line(&mut self) -> Option<Line> {
+        let mut children = vec![];
+        let token = match self.lexer.lex() {
+            Ok(Some(token)) => token,
+            Ok(None) => return None,
+            Err(_) => {
+                self.recover(&mut children);
+                return Some(Line::Declaration(binding(children)));
+            }
+        };
+        self.take(&mut children);
+
+        match token.kind() {
+            lex::TokenKind::Newline => Some(Line::Blank(children)),
+            lex::TokenKind::Comment => {
+                self.end_line(&mut children);
+                Some(Line::Comment(children))
+            }
+            lex::TokenKind::Indent => match self.lexer.lex() {
+                Ok(Some(token)) if token.kind() == lex::TokenKind::Comment => {
+                    self.take(&mut children);
+                    self.end_line(&mut children);
+                    Some(Line::Comment(children))
+                }
+                Ok(Some(token)) if token.kind() == lex::TokenKind::Newline => {
+                    self.take(&mut children);
+                    Some(Line::Blank(children))
+                }
+                Ok(None) => Some(Line::Blank(children)),
+                Ok(Some(token)) if token.kind() == lex::TokenKind::Identifier => {
+                    self.take(&mut children);
+                    self.binding(&mut children);
+                    Some(Line::Indented(binding(children)))
+                }
+                _ => {
+                    self.recover(&mut children);
+                    Some(Line::Indented(binding(children)))
+                }
+            },
+            lex::TokenKind::Identifier => {
+                let kind = match self.lexer.decl(token).map(|decl| decl.kind()) {
+                    Some(DeclKind::Rule) => NodeKind::Rule,
+                    Some(DeclKind::Build) => NodeKind::Build,
+                    Some(DeclKind::Pool) => NodeKind::Pool,
+                    Some(DeclKind::Default) => NodeKind::Default,
+                    Some(DeclKind::Include) => NodeKind::Include,
+                    Some(DeclKind::Subninja) => NodeKind::Subninja,
+                    _ => {
+                        self.binding(&mut children);
+                        return Some(Line::Declaration(binding(children)));
+                    }
+                };
+                if let Some(Element::Token(keyword)) = children.first_mut() {
+                    keyword.kind = TokenKind::Keyword;
+                }
+                match kind {
+                    NodeKind::Rule | NodeKind::Pool => {
+                        if self.expect(lex::TokenKind::Identifier, &mut children) {
+                            self.end_line(&mut children);
+                        }
+                    }
+                    NodeKind::Build => self.paths(&mut children, true),
+                    _ => self.paths(&mut children, false),
+                }
+                Some(Line::Declaration(Element::Node(Node::new(kind, children))))
+            }
+            _ => {
+                // No line starts with this token.
+                for child in children.iter_mut() {
+                    if let Element::Token(token) = child {
+                        token.kind = TokenKind::Error;
+                    }
+                }
+                self.recover(&mut children);
+                Some(Line::Declaration(binding(children)))
+            }
+        }
+    }
+
+    /// Adds the tokens lexed since the last call to `children`.
+    fn take(&mut self, children: &mut Vec<Element>) {
+        for piece in self.lexer.take_pieces() {
+            children.push(self.token(&piece));
+        }
+    }
+
+    fn token(&self, piece: &Piece) -> Element {
+        let kind = match piece.kind() {
+            PieceKind::Token(lex::TokenKind::Identifier) => TokenKind::Identifier,
+            PieceKind::Token(lex::TokenKind::Equal) => TokenKind::Equal,
+            PieceKind::Token(lex::TokenKind::Colon) => TokenKind::Colon,
+            PieceKind::Token(lex::TokenKind::Pipe) => TokenKind::Pipe,
+            PieceKind::Token(lex::TokenKind::PipePipe) => TokenKind::PipePipe,
+            PieceKind::Token(lex::TokenKind::PipeAt) => TokenKind::PipeAt,
+            PieceKind::Token(lex::TokenKind::Newline) => TokenKind::Newline,
+            PieceKind::Token(lex::TokenKind::Indent) => TokenKind::Indent,
+            PieceKind::Token(lex::TokenKind::Comment) => TokenKind::Comment,
+            PieceKind::Text => TokenKind::Text,
+            PieceKind::Escape => TokenKind::Escape,
+            PieceKind::Variable => TokenKind::Variable,
+            PieceKind::Whitespace => TokenKind::Whitespace,
+            PieceKind::Continuation => TokenKind::Continuation,
+            PieceKind::Error => TokenKind::Error,
+        };
+        Element::Token(Token::new(kind, &self.input[piece.range()]))
+    }
+
+    /// Adds the pieces of a path or value as a node of `kind`, followed
+    /// by any whitespace after it.
+    fn word(&mut self, kind: NodeKind, children: &mut Vec<Element>) {
+        let pieces = self.lexer.take_pieces();
+        let end = pieces
+            .iter()
+            .rposition(|piece| {
+                !matches!(
+                    piece.kind(),
+                    PieceKind::Whitespace | PieceKind::Continuation
+                )
+            })
+            .map_or(0, |last| last + 1);
+        if end > 0 {
+            let word = pieces[..end].iter().map(|piece| self.token(piece));
+            children.push(Element::Node(Node::new(kind, word.collect())));
+        }
+        for piece in pieces[end..].iter() {
+            children.push(self.token(piece));
+        }
+    }
+
+    /// Lexes a token, which ends the line unless it is of kind
+    /// `expected`. Anything else before the line ending is an error.
+    fn expect(&mut self, expected: lex::TokenKind, children: &mut Vec<Element>) -> bool {
+        match self.lexer.lex() {
+            Ok(Some(token)) if token.kind() == expected => {
+                self.take(children);
+                true
+            }
+            Ok(Some(token)) if token.kind() == lex::TokenKind::Newline => {
+                self.take(children);
+                false
+            }
+            Ok(None) => false,
+            _ => {
+                self.recover(children);
+                false
+            }
+        }
+    }
+
+    fn end_line(&mut self, children: &mut Vec<Element>) {
+        self.expect(lex::TokenKind::Newline, children);
+    }
+
+    /// Keeps the rest of a line that does not parse as an error, along
+    /// with any tokens lexed but not yet added to `children`.
+    fn recover(&mut self, children: &mut Vec<Element>) {
+        self.lexer.skip_line();
+        let pieces = self.lexer.take_pieces();
+        if let (Some(first), Some(last)) = (pieces.first(), pieces.last()) {
+            let text = &self.input[first.range().start..last.range().end];
+            children.push(Element::Token(Token::new(TokenKind::Error, text)));
+        }
+        // All that is left is the line ending, if there is one.
+        let _ = self.lexer.lex();
+        self.take(children);
+    }
+
+    /// Reads the rest of a binding whose name has been lexed.
+    fn binding(&mut self, children: &mut Vec<Element>) {
+        if self.expect(lex::TokenKind::Equal, children) {
+            let value = self.lexer.lex_value(&mut self.arena);
+            self.word(NodeKind::Value, children);
+            match value {
+                Ok(_) => self.end_line(children),
+                Err(_) => self.recover(children),
+            }
+        }
+    }
+
+    /// Reads the paths and separators of a build or similar line. The
+    /// word after the first colon of a build is the rule name.
+    fn paths(&mut self, children: &mut Vec<Element>, build: bool) {
+        let mut rule_next = false;
+        loop {
+            if rule_next {
+                rule_next = false;
+                if !self.expect(lex::TokenKind::Identifier, children) {
+                    return;
+                }
+            }
+            let target = self.lexer.lex_target(&mut self.arena);
+            self.word(NodeKind::Path, children);
+            match target {
+                Ok(Some(_)) => continue,
+                Ok(None) => (),
+                Err(_) => return self.recover(children),
+            }
+            match self.lexer.lex() {
+                Ok(Some(token)) => match token.kind() {
+                    lex::TokenKind::Newline => return self.take(children),
+                    lex::TokenKind::Colon => rule_next = build,
+                    lex::TokenKind::Pipe | lex::TokenKind::PipePipe | lex::TokenKind::PipeAt => (),
+                    _ => return self.recover(children),
+                },
+                Ok(None) => return,
+                Err(_) => return self.recover(children),
+            }
+            self.take(children);
+        }
+    }
+}
+
+fn binding(children: Vec<Element>) -> Element {
+    Element::Node(Node::new(NodeKind::Binding, children))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NINJA: &[u8] = b"# Top comment\n\
+        ninja_required_version = 1.3\n\
+        cflags = -O2 $\n    -Wall  \n\
+        \n\
+        rule cc\n\
+        \x20 command = cc $cflags -c ${in} -o $out\n\
+        \x20 # about the depfile\n\
+        \x20 depfile = $out.d\n\
+        \n\
+        build a.o b$ c.o | a.d: cc a.c $\n    b$:c.c || gen |@ check\n\
+        \x20 cflags = $$HOME\n\
+        pool link\r\n\
+        \x20 depth = 2\n\
+        # trailing\n\
+        default a.o\n\
+        include rules.ninja\n\
+        subninja sub/build.ninja";
+
+    fn kinds(node: &Node) -> Vec<NodeKind> {
+        node.nodes().map(|node| node.kind()).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let file = parse(NINJA);
+        assert_eq!(file.text().view(), NINJA);
+    }
+
+    #[test]
+    fn round_trip_malformed() {
+        let inputs: &[&[u8]] = &[
+            b"",
+            b"\n\n",
+            b"   \n",
+            b"~~~\n",
+            b"rule\n",
+            b"  orphan = 1\n",
+            b"build a : $",
+            b"x = ${unterminated\n",
+            b"x = a\r",
+            b"build $\n",
+            b"# no newline",
+        ];
+        for input in inputs.iter() {
+            assert_eq!(parse(input).text().view(), *input);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let inputs: &[&[u8]] = &[
+            b"~~~\n",
+            b"= 1\n",
+            b"x y = 1\n",
+            b"x # comment\n",
+            b"rule r r\n",
+            b"  : orphan\n",
+            b"x = ${unterminated\n",
+            b"build a: $~\n",
+        ];
+        for input in inputs.iter() {
+            let file = parse(input);
+            let errors = file
+                .tokens()
+                .iter()
+                .filter(|token| token.kind() == TokenKind::Error)
+                .count();
+            assert!(errors > 0, "no error in {:?}", input);
+        }
+        let file = parse(b"x = 1\nrule r\n  command = c\n");
+        assert!(file
+            .tokens()
+            .iter()
+            .all(|token| token.kind() != TokenKind::Error));
+    }
+
+    #[test]
+    fn structure() {
+        let file = parse(NINJA);
+        assert_eq!(
+            kinds(&file),
+            vec![
+                NodeKind::Binding,
+                NodeKind::Binding,
+                NodeKind::Rule,
+                NodeKind::Build,
+                NodeKind::Pool,
+                NodeKind::Default,
+                NodeKind::Include,
+                NodeKind::Subninja,
+            ]
+        );
+
+        let rule = file.nodes().nth(2).expect("no rule");
+        assert_eq!(kinds(rule), vec![NodeKind::Binding, NodeKind::Binding]);
+        assert!(rule
+            .tokens()
+            .iter()
+            .any(|token| token.kind() == TokenKind::Comment
+                && token.text() == b"# about the depfile"));
+
+        let pool = file.nodes().nth(4).expect("no pool");
+        assert!(pool
+            .tokens()
+            .iter()
+            .all(|token| token.kind() != TokenKind::Comment));
+    }
+
+    #[test]
+    fn build_tokens() {
+        let file = parse(NINJA);
+        let build = file.nodes().nth(3).expect("no build");
+        let tokens = build
+            .children()
+            .iter()
+            .filter_map(|child| match child {
+                Element::Token(token) if token.kind() != TokenKind::Whitespace => {
+                    Some((token.kind(), token.text()))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            vec![
+                (TokenKind::Keyword, &b"build"[..]),
+                (TokenKind::Pipe, b"|"),
+                (TokenKind::Colon, b":"),
+                (TokenKind::Identifier, b"cc"),
+                (TokenKind::Continuation, b"$\n    "),
+                (TokenKind::PipePipe, b"||"),
+                (TokenKind::PipeAt, b"|@"),
+                (TokenKind::Newline, b"\n"),
+            ]
+        );
+
+        let paths = build
+            .nodes()
+            .filter(|node| node.kind() == NodeKind::Path)
+            .map(|node| node.text())
+            .collect::<Vec<_>>();
+        let paths = paths.iter().map(|path| path.view()).collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec![
+                &b"a.o"[..],
+                b"b$ c.o",
+                b"a.d",
+                b"a.c",
+                b"b$:c.c",
+                b"gen",
+                b"check"
+            ]
+        );
+    }
+
+    #[test]
+    fn value_tokens() {
+        let file = parse(b"x = a $y ${z}$$ $\n  b \n");
+        let binding = file.nodes().next().expect("no binding");
+        let value = binding.nodes().next().expect("no value");
+        let tokens = value
+            .tokens()
+            .iter()
+            .map(|token| (token.kind(), token.text()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            vec![
+                (TokenKind::Text, &b"a "[..]),
+                (TokenKind::Variable, b"$y"),
+                (TokenKind::Text, b" "),
+                (TokenKind::Variable, b"${z}"),
+                (TokenKind::Escape, b"$$"),
+                (TokenKind::Text, b" "),
+                (TokenKind::Continuation, b"$\n  "),
+                (TokenKind::Text, b"b "),
+            ]
+        );
+    }
+
+    #[test]
+    fn edit() {
+        let mut file = parse(NINJA);
+        let pool = file
+            .children_mut()
+            .iter_mut()
+            .find_map(|child| match child {
+                Element::Node(node) if node.kind() == NodeKind::Pool => Some(node),
+                _ => None,
+            })
+            .expect("no pool");
+        for child in pool.children_mut().iter_mut() {
+            if let Element::Node(binding) = child {
+                for child in binding.children_mut().iter_mut() {
+                    if let Element::Node(value) = child {
+                        *value = Node::new(
+                            NodeKind::Value,
+                            vec![Element::Token(Token::new(TokenKind::Text, b"4"))],
+                        );
+                    }
+                }
+            }
+        }
+
+        let expected = String::from_utf8_lossy(NINJA).replace("depth = 2", "depth = 4");
+        assert_eq!(file.text().view(), expected.as_bytes());
+    }
+}
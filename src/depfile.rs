@@ -0,0 +1,505 @@
+//! Parsing the Makefile-style dependency files written by `gcc -MD` and
+//! compatible compilers, as named by a rule's `depfile` binding.
+//!
+//! A depfile holds rules of the form `outputs: inputs`. Paths are
+//! separated by whitespace, and a backslash before a newline continues
+//! the rule on the next line. Spaces, `#` and `:` in paths are escaped
+//! with backslashes, which are otherwise kept, so Windows paths such as
+//! `c:\include\stdio.h` read as written. `$$` is a single `$`.
+
+use crate::arena;
+use crate::blob;
+use crate::blob::Blob;
+use crate::dirty::FileSystem;
+use crate::graph::{Edge, Graph};
+use crate::lex::Location;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum DepfileError {
+    ReadError { path: Blob, error: io::Error },
+    MissingColon { location: Location },
+    InputWithInputs { location: Location },
+    UndeclaredOutput { path: Blob, output: Blob },
+}
+
+impl fmt::Display for DepfileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DepfileError::ReadError { path, error } => {
+                write!(f, "loading '{}': {}", String::from_utf8_lossy(path), error)
+            }
+            DepfileError::MissingColon { location } => {
+                location.report(f, &"expected ':' in depfile")
+            }
+            DepfileError::InputWithInputs { location } => {
+                location.report(f, &"inputs may not also have inputs")
+            }
+            DepfileError::UndeclaredOutput { path, output } => write!(
+                f,
+                "{}: depfile mentions '{}' as an output, but no such output was declared",
+                String::from_utf8_lossy(path),
+                String::from_utf8_lossy(output)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DepfileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DepfileError::ReadError { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// The outputs and inputs named by a depfile, each listed once.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Depfile {
+    outputs: Vec<Blob>,
+    inputs: Vec<Blob>,
+}
+
+impl Depfile {
+    pub fn outputs(&self) -> &[Blob] {
+        &self.outputs
+    }
+
+    pub fn inputs(&self) -> &[Blob] {
+        &self.inputs
+    }
+}
+
+/// Parses `input`, the contents of the depfile at `file`.
+///
+/// Like ninja, this accepts the rules that `gcc -MP` adds for each input,
+/// which have the input as their output and no inputs of their own, but
+/// rejects an input that has inputs.
+pub fn parse(file: &blob::View, input: &blob::View) -> Result<Depfile, DepfileError> {
+    let mut depfile = Depfile::default();
+    let mut parsing_outputs = true;
+    let mut have_colon = false;
+    let mut first_output = None;
+    // Set once an input appears as an output, after which the rule may
+    // not have inputs.
+    let mut poisoned = false;
+    // The paths already in `depfile`, so that each is only checked once.
+    let mut inputs = std::collections::HashSet::new();
+    let mut outputs = std::collections::HashSet::new();
+
+    let mut position = 0;
+    while position < input.len() {
+        let start = position;
+        let (mut path, newline) = scan(input, &mut position);
+        let is_input = !parsing_outputs;
+        if path.last() == Some(&b':') {
+            path.pop();
+            parsing_outputs = false;
+            have_colon = true;
+        }
+
+        if !path.is_empty() {
+            let path = Blob::from(path);
+            if is_input {
+                if poisoned {
+                    let location = Location::new(file, input, start..position);
+                    return Err(DepfileError::InputWithInputs { location });
+                }
+                if inputs.insert(path.clone()) {
+                    depfile.inputs.push(path);
+                }
+            } else if inputs.contains(&path) {
+                poisoned = true;
+            } else if outputs.insert(path.clone()) {
+                first_output.get_or_insert(start);
+                depfile.outputs.push(path);
+            }
+        }
+
+        if newline {
+            parsing_outputs = true;
+            poisoned = false;
+        }
+    }
+
+    if let (false, Some(start)) = (have_colon, first_output) {
+        let location = Location::new(file, input, start..start + 1);
+        return Err(DepfileError::MissingColon { location });
+    }
+    Ok(depfile)
+}
+
+/// Reads the next path, unescaping it, and returns it along with whether
+/// it ended a rule. The path is empty if `position` was at whitespace.
+fn scan(input: &blob::View, position: &mut usize) -> (Vec<u8>, bool) {
+    let mut path = vec![];
+    let backslashes = |path: &mut Vec<u8>, count: usize| {
+        path.extend(std::iter::repeat_n(b'\\', count));
+    };
+
+    while let Some(&b) = input.get(*position) {
+        let next = input.get(*position + 1).cloned();
+        match b {
+            b'\\' => {
+                let run = input[*position..]
+                    .iter()
+                    .take_while(|b| **b == b'\\')
+                    .count();
+                let after = *position + run;
+                match input.get(after).cloned() {
+                    // An odd number of backslashes escapes a space, and the
+                    // rest are halved.
+                    Some(b' ') if run % 2 == 1 => {
+                        backslashes(&mut path, run / 2);
+                        path.push(b' ');
+                        *position = after + 1;
+                    }
+                    // An even number of backslashes does not.
+                    Some(b' ') => {
+                        backslashes(&mut path, run);
+                        *position = after + 1;
+                        return (path, false);
+                    }
+                    Some(b'#') => {
+                        backslashes(&mut path, run - 1);
+                        path.push(b'#');
+                        *position = after + 1;
+                    }
+                    Some(b':') => match input.get(after + 1).cloned() {
+                        // A colon that ends an output cannot be escaped,
+                        // so the backslashes belong to the path.
+                        None | Some(b'\0') | Some(b' ') | Some(b'\t') | Some(b'\r')
+                        | Some(b'\n') => {
+                            backslashes(&mut path, run);
+                            path.push(b':');
+                            let newline = input.get(after + 1) == Some(&b'\n');
+                            *position = input.len().min(after + 2);
+                            return (path, newline);
+                        }
+                        _ => {
+                            backslashes(&mut path, run - 1);
+                            path.push(b':');
+                            *position = after + 1;
+                        }
+                    },
+                    Some(b'\n') if run == 1 => {
+                        *position = after + 1;
+                        return (path, false);
+                    }
+                    Some(b'\r') if run == 1 && input.get(after + 1) == Some(&b'\n') => {
+                        *position = after + 2;
+                        return (path, false);
+                    }
+                    None | Some(b'\0') | Some(b'\r') | Some(b'\n') => {
+                        if run == 1 {
+                            *position = after;
+                            return (path, false);
+                        }
+                        backslashes(&mut path, run);
+                        *position = after;
+                    }
+                    Some(other) => {
+                        backslashes(&mut path, run);
+                        path.push(other);
+                        *position = after + 1;
+                    }
+                }
+            }
+            b'$' if next == Some(b'$') => {
+                path.push(b'$');
+                *position += 2;
+            }
+            b'\n' => {
+                *position += 1;
+                return (path, true);
+            }
+            b'\r' if next == Some(b'\n') => {
+                *position += 2;
+                return (path, true);
+            }
+            _ if is_path_char(b) => {
+                path.push(b);
+                *position += 1;
+            }
+            _ => {
+                *position += 1;
+                return (path, false);
+            }
+        }
+    }
+    (path, false)
+}
+
+fn is_path_char(b: u8) -> bool {
+    match b {
+        b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | 0x80..=0xff => true,
+        _ => b"+,/_:.~()}{%=@[]!-".contains(&b),
+    }
+}
+
+/// Reads the depfile of `edge`, and adds the inputs it lists to the
+/// graph as implicit inputs. Returns whether the depfile exists, which it
+/// does not until the edge has run.
+pub fn load(
+    graph: &mut Graph,
+    edge: arena::Id<Edge>,
+    file_system: &dyn FileSystem,
+) -> Result<bool, DepfileError> {
+    let path = match graph.edge(edge).depfile() {
+        Some(path) => Blob::new(path),
+        None => return Ok(true),
+    };
+    let contents = match file_system.read(&path) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(error) => return Err(DepfileError::ReadError { path, error }),
+    };
+    let depfile = parse(&path, &contents)?;
+    for output in depfile.outputs() {
+        if !declares(graph, edge, output) {
+            let output = output.clone();
+            return Err(DepfileError::UndeclaredOutput { path, output });
+        }
+    }
+    graph.add_implicit_inputs(edge, depfile.inputs());
+    Ok(true)
+}
+
+/// Whether `path` is an output of `edge`.
+fn declares(graph: &Graph, edge: arena::Id<Edge>, path: &blob::View) -> bool {
+    match graph.lookup(path) {
+        Some(node) => graph.edge(edge).all_outputs().any(|output| output == node),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dirty::{Dirty, MemoryFileSystem};
+    use crate::testing::graph;
+
+    fn depfile(input: &str) -> Depfile {
+        parse(b"test.d", input.as_bytes()).expect("failed to parse")
+    }
+
+    fn paths(paths: &[Blob]) -> Vec<String> {
+        paths
+            .iter()
+            .map(|path| String::from_utf8_lossy(path).into_owned())
+            .collect()
+    }
+
+    fn check(input: &str, outputs: &[&str], inputs: &[&str]) {
+        let depfile = depfile(input);
+        assert_eq!(paths(depfile.outputs()), outputs, "outputs of {:?}", input);
+        assert_eq!(paths(depfile.inputs()), inputs, "inputs of {:?}", input);
+    }
+
+    #[test]
+    fn basic() {
+        check(
+            "build/ninja.o: ninja.cc ninja.h eval_env.h manifest_parser.h\n",
+            &["build/ninja.o"],
+            &["ninja.cc", "ninja.h", "eval_env.h", "manifest_parser.h"],
+        );
+        check(" \\\n  out: in\n", &["out"], &["in"]);
+        check("", &[], &[]);
+        check("\n\n", &[], &[]);
+        check("foo.o:", &["foo.o"], &[]);
+    }
+
+    #[test]
+    fn continuations() {
+        check(
+            "foo.o: \\\n  bar.h baz.h\n",
+            &["foo.o"],
+            &["bar.h", "baz.h"],
+        );
+        check(
+            "foo.o: \\\r\n  bar.h baz.h\r\n",
+            &["foo.o"],
+            &["bar.h", "baz.h"],
+        );
+        check(
+            "Project\\Dir\\Build\\Release8\\Foo\\Foo.res : \\\n  Dir\\Library\\Foo.rc \\\n  \
+             Dir\\Library\\Version\\Bar.h \\\n  Dir\\Library\\Foo.ico \\\n  \
+             Project\\Thing\\Bar.tlb \\\n",
+            &["Project\\Dir\\Build\\Release8\\Foo\\Foo.res"],
+            &[
+                "Dir\\Library\\Foo.rc",
+                "Dir\\Library\\Version\\Bar.h",
+                "Dir\\Library\\Foo.ico",
+                "Project\\Thing\\Bar.tlb",
+            ],
+        );
+    }
+
+    #[test]
+    fn escapes() {
+        check(
+            "a\\ bc\\ def:   a\\ b c d",
+            &["a bc def"],
+            &["a b", "c", "d"],
+        );
+        check(
+            "a\\ b\\#c.h: \\\\\\\\\\  \\\\\\\\ \\\\share\\info\\\\#1",
+            &["a b#c.h"],
+            &["\\\\ ", "\\\\\\\\", "\\\\share\\info\\#1"],
+        );
+        check(
+            "\\!\\@\\#$$\\%\\^\\&\\[\\]\\\\:",
+            &["\\!\\@#$\\%\\^\\&\\[\\]\\\\"],
+            &[],
+        );
+        check(
+            "C:/Program\\ Files\\ (x86)/Microsoft\\ crtdefs.h: \\\n \
+             en@quot.header~ t+t-x!=1 \\\n \
+             openldap/slapd.d/cn=config/cn=schema/cn={0}core.ldif\\\n \
+             Fu\u{e4}ball\\\n a[1]b@2%c",
+            &["C:/Program Files (x86)/Microsoft crtdefs.h"],
+            &[
+                "en@quot.header~",
+                "t+t-x!=1",
+                "openldap/slapd.d/cn=config/cn=schema/cn={0}core.ldif",
+                "Fu\u{e4}ball",
+                "a[1]b@2%c",
+            ],
+        );
+    }
+
+    #[test]
+    fn windows_paths() {
+        check(
+            "c\\:\\gcc\\x86_64-w64-mingw32\\include\\stddef.o: \\\n \
+             c:\\gcc\\x86_64-w64-mingw32\\include\\stddef.h \n",
+            &["c:\\gcc\\x86_64-w64-mingw32\\include\\stddef.o"],
+            &["c:\\gcc\\x86_64-w64-mingw32\\include\\stddef.h"],
+        );
+        check("C:/out.o: C:/in.c\n", &["C:/out.o"], &["C:/in.c"]);
+        check(
+            "foo1\\: x\nfoo1\\:\nfoo1\\:\r\nfoo1\\:\t\nfoo1\\:",
+            &["foo1\\"],
+            &["x"],
+        );
+        check("foo1\\:", &["foo1\\"], &[]);
+    }
+
+    #[test]
+    fn multiple_outputs() {
+        check("foo foo: x y z", &["foo"], &["x", "y", "z"]);
+        check("foo bar: x y z", &["foo", "bar"], &["x", "y", "z"]);
+        check("foo: x\nfoo: \nfoo:\n", &["foo"], &["x"]);
+        check(
+            "foo: x\nfoo: y\nfoo \\\nfoo: z\n",
+            &["foo"],
+            &["x", "y", "z"],
+        );
+        check(
+            "foo: x\\\n     y\nfoo \\\nfoo: z\n",
+            &["foo"],
+            &["x", "y", "z"],
+        );
+        check(" foo: x\n foo: y\n foo: z\n", &["foo"], &["x", "y", "z"]);
+        check("foo: x y\nbar: y z\n", &["foo", "bar"], &["x", "y", "z"]);
+    }
+
+    #[test]
+    fn phony_rules() {
+        check("foo: x y z\nx:\ny:\nz:\n", &["foo"], &["x", "y", "z"]);
+        check(
+            "foo: x\nx:\nfoo: y\ny:\nfoo: z\nz:\n",
+            &["foo"],
+            &["x", "y", "z"],
+        );
+        match parse(b"test.d", b"foo: x y z\nx: alsoin\ny:\nz:\n") {
+            Err(error @ DepfileError::InputWithInputs { .. }) => assert_eq!(
+                error.to_string(),
+                "test.d:2: inputs may not also have inputs\nx: alsoin\n   ^ near here"
+            ),
+            _ => panic!("accepted an input with inputs"),
+        }
+        match parse(b"test.d", b"foo: x y\nx: y\n") {
+            Err(DepfileError::InputWithInputs { .. }) => (),
+            _ => panic!("accepted an input with an existing input"),
+        }
+    }
+
+    #[test]
+    fn missing_colon() {
+        match parse(b"test.d", b"foo.o foo.c\n") {
+            Err(error @ DepfileError::MissingColon { .. }) => assert_eq!(
+                error.to_string(),
+                "test.d:1: expected ':' in depfile\nfoo.o foo.c\n^ near here"
+            ),
+            _ => panic!("accepted a depfile without a colon"),
+        }
+    }
+
+    const CC: &[u8] = b"rule cc\n    command = cc -MD -MF $out.d $in -o $out\n    \
+                        depfile = $out.d\n\
+                        build a.o: cc a.c\n";
+
+    #[test]
+    fn load_into_graph() {
+        let mut graph = graph(CC);
+        let a_o = graph.lookup(b"a.o").expect("no a.o");
+        let edge = graph.node(a_o).in_edge().expect("no producer");
+        assert_eq!(graph.edge(edge).depfile(), Some(&b"a.o.d"[..]));
+
+        let mut file_system = MemoryFileSystem::new();
+        file_system.write(b"a.o.d", 2, b"a.o: a.c ./a.h \\\n  /usr/include/stdio.h\n");
+        file_system.touch(b"a.c", 1);
+        file_system.touch(b"a.h", 1);
+        file_system.touch(b"/usr/include/stdio.h", 1);
+        file_system.touch(b"a.o", 2);
+        assert!(load(&mut graph, edge, &file_system).expect("failed to load"));
+
+        let inputs = graph
+            .edge(edge)
+            .implicit_inputs()
+            .iter()
+            .map(|node| Blob::new(graph.node(*node).path()))
+            .collect::<Vec<_>>();
+        assert_eq!(paths(&inputs), &["a.h", "/usr/include/stdio.h"]);
+        let a_h = graph.lookup(b"a.h").expect("no a.h");
+        assert_eq!(graph.node(a_h).out_edges(), &[edge]);
+
+        let dirty = Dirty::compute(&mut graph, &file_system, None, None, &[a_o]).unwrap();
+        assert!(!dirty.is_dirty(edge));
+        file_system.touch(b"a.h", 3);
+        let dirty = Dirty::compute(&mut graph, &file_system, None, None, &[a_o]).unwrap();
+        assert!(dirty.is_dirty(edge));
+    }
+
+    #[test]
+    fn missing_depfile() {
+        let mut graph = graph(CC);
+        let a_o = graph.lookup(b"a.o").expect("no a.o");
+        let edge = graph.node(a_o).in_edge().expect("no producer");
+        let mut file_system = MemoryFileSystem::new();
+        file_system.touch(b"a.c", 1);
+        file_system.touch(b"a.o", 2);
+        assert!(!load(&mut graph, edge, &file_system).expect("failed to load"));
+        let dirty = Dirty::compute(&mut graph, &file_system, None, None, &[a_o]).unwrap();
+        assert!(dirty.is_node_dirty(a_o));
+    }
+
+    #[test]
+    fn undeclared_output() {
+        let mut graph = graph(CC);
+        let a_o = graph.lookup(b"a.o").expect("no a.o");
+        let edge = graph.node(a_o).in_edge().expect("no producer");
+        let mut file_system = MemoryFileSystem::new();
+        file_system.write(b"a.o.d", 2, b"b.o: a.c\n");
+        match load(&mut graph, edge, &file_system) {
+            Err(error @ DepfileError::UndeclaredOutput { .. }) => assert_eq!(
+                error.to_string(),
+                "a.o.d: depfile mentions 'b.o' as an output, but no such output was declared"
+            ),
+            _ => panic!("accepted an undeclared output"),
+        }
+    }
+}
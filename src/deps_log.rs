@@ -0,0 +1,598 @@
+//! Reading and writing `.ninja_deps`, the binary log in which ninja
+//! keeps the dependencies that commands with `deps` discovered.
+//!
+//! The file starts with `# ninjadeps\n` and a 4-byte version, followed by
+//! records that each begin with a 4-byte size. A size with its high bit
+//! clear introduces a path record: the path, padded with zeros to a
+//! multiple of 4 bytes, then the complement of the path's id, which is
+//! the number of paths before it. A size with the high bit set
+//! introduces a deps record: the id of an output, its modification time
+//! as two 4-byte halves, low first, and the ids of its inputs. All
+//! integers are little-endian.
+
+use crate::blob;
+use crate::blob::Blob;
+use crate::dirty;
+use crate::dirty::Timestamp;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+/// The only version that stores 64-bit modification times.
+pub const VERSION: u32 = 4;
+
+const SIGNATURE: &[u8] = b"# ninjadeps\n";
+
+/// The largest record ninja will read.
+const MAX_RECORD_SIZE: usize = (1 << 19) - 1;
+const DEPS_FLAG: u32 = 1 << 31;
+
+/// Below this many deps records the log is never recompacted.
+const MIN_COMPACTION_ENTRIES: usize = 1000;
+/// How many deps records per output the log may hold before it is
+/// recompacted.
+const COMPACTION_RATIO: usize = 3;
+
+#[derive(Debug)]
+pub enum DepsLogError {
+    Io(io::Error),
+}
+
+impl fmt::Display for DepsLogError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DepsLogError::Io(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for DepsLogError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DepsLogError::Io(error) => Some(error),
+        }
+    }
+}
+
+struct Record {
+    mtime: Timestamp,
+    inputs: Vec<u32>,
+}
+
+/// The dependencies recorded for one output.
+pub struct Deps<'a> {
+    log: &'a DepsLog,
+    record: &'a Record,
+}
+
+impl<'a> Deps<'a> {
+    /// The output's modification time when its dependencies were
+    /// recorded.
+    pub fn mtime(&self) -> Timestamp {
+        self.record.mtime
+    }
+
+    pub fn inputs(&self) -> impl Iterator<Item = &'a blob::View> + 'a {
+        let log = self.log;
+        self.record
+            .inputs
+            .iter()
+            .map(move |id| log.paths[*id as usize].view())
+    }
+}
+
+/// The contents of a deps log, and optionally the file it is being
+/// appended to.
+#[derive(Default)]
+pub struct DepsLog {
+    /// Every path the log mentions, indexed by id.
+    paths: Vec<Blob>,
+    ids: HashMap<Blob, u32>,
+    /// The latest deps record of each path, indexed by id.
+    records: Vec<Option<Record>>,
+    /// The number of deps records read or written, including ones later
+    /// replaced.
+    total: usize,
+    /// How many bytes of the input held complete, valid records.
+    valid_len: usize,
+    version: Option<u32>,
+    file: Option<std::fs::File>,
+}
+
+impl DepsLog {
+    pub fn new() -> DepsLog {
+        DepsLog::default()
+    }
+
+    /// Parses the contents of a log. Like ninja, this stops at the first
+    /// record that is incomplete or invalid, which is usually one cut
+    /// short when a build was interrupted, and keeps everything before it.
+    /// A log of another version, or without a signature, is read as
+    /// empty, and needs recompaction to start it over. Since none of it is
+    /// kept, none of it counts as invalid.
+    pub fn parse(input: &blob::View) -> DepsLog {
+        // A missing or short signature reads as version 0, which no ninja
+        // has written.
+        let version = input
+            .strip_prefix(SIGNATURE)
+            .and_then(read_u32)
+            .unwrap_or(0);
+        let mut log = DepsLog::new();
+        log.version = Some(version);
+        if version != VERSION {
+            log.valid_len = input.len();
+            return log;
+        }
+        let mut offset = SIGNATURE.len() + 4;
+        log.valid_len = offset;
+        while let Some(header) = read_u32(&input[offset..]) {
+            let size = (header & !DEPS_FLAG) as usize;
+            let start = offset + 4;
+            if size > MAX_RECORD_SIZE || input.len() - start < size {
+                break;
+            }
+            let record = &input[start..start + size];
+            let valid = if header & DEPS_FLAG != 0 {
+                log.read_deps(record)
+            } else {
+                log.read_path(record)
+            };
+            if !valid {
+                break;
+            }
+            offset = start + size;
+            log.valid_len = offset;
+        }
+        log
+    }
+
+    fn read_path(&mut self, record: &blob::View) -> bool {
+        if record.len() < 4 || !record.len().is_multiple_of(4) {
+            return false;
+        }
+        let (path, checksum) = record.split_at(record.len() - 4);
+        let checksum = read_u32(checksum).unwrap_or(0);
+        let padding = path.iter().rev().take(3).take_while(|b| **b == 0).count();
+        let path = &path[..path.len() - padding];
+        let id = self.paths.len() as u32;
+        if !checksum != id || self.ids.contains_key(path) {
+            return false;
+        }
+        self.add_path(path);
+        true
+    }
+
+    fn read_deps(&mut self, record: &blob::View) -> bool {
+        if record.len() < 12 || !record.len().is_multiple_of(4) {
+            return false;
+        }
+        let mut ids = record.chunks(4).map(|chunk| read_u32(chunk).unwrap_or(0));
+        let output = ids.next().unwrap_or(0);
+        let low = u64::from(ids.next().unwrap_or(0));
+        let high = u64::from(ids.next().unwrap_or(0));
+        let inputs = ids.collect::<Vec<_>>();
+        let known = |id: &u32| (*id as usize) < self.paths.len();
+        if !known(&output) || !inputs.iter().all(known) {
+            return false;
+        }
+        let mtime = high << 32 | low;
+        self.set_record(output, Record { mtime, inputs });
+        true
+    }
+
+    /// Reads the log at `path`, cutting off any incomplete or invalid
+    /// records at its end so that new ones can be appended. A missing
+    /// log is empty.
+    pub fn load(path: &Path) -> Result<DepsLog, DepsLogError> {
+        let contents = match std::fs::read(path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(DepsLog::new()),
+            Err(error) => return Err(DepsLogError::Io(error)),
+        };
+        let log = DepsLog::parse(&contents);
+        if log.valid_len < contents.len() {
+            let truncate = || -> io::Result<()> {
+                let file = std::fs::OpenOptions::new().write(true).open(path)?;
+                file.set_len(log.valid_len as u64)
+            };
+            if let Err(error) = truncate() {
+                return Err(DepsLogError::Io(error));
+            }
+        }
+        Ok(log)
+    }
+
+    pub fn lookup(&self, output: &blob::View) -> Option<Deps<'_>> {
+        let id = *self.ids.get(output)?;
+        let record = self.records[id as usize].as_ref()?;
+        Some(Deps { log: self, record })
+    }
+
+    /// The outputs that have dependencies recorded.
+    pub fn outputs(&self) -> impl Iterator<Item = &blob::View> {
+        self.paths
+            .iter()
+            .zip(self.records.iter())
+            .filter(|(_, record)| record.is_some())
+            .map(|(path, _)| path.view())
+    }
+
+    /// Whether the log was written by another version, or has grown with
+    /// enough superseded records to be worth rewriting.
+    pub fn needs_recompaction(&self) -> bool {
+        if let Some(version) = self.version {
+            if version != VERSION {
+                return true;
+            }
+        }
+        let unique = self
+            .records
+            .iter()
+            .filter(|record| record.is_some())
+            .count();
+        self.total > MIN_COMPACTION_ENTRIES && self.total > unique * COMPACTION_RATIO
+    }
+
+    /// Starts appending new records to `path`, writing a header if the
+    /// file is new.
+    pub fn open(&mut self, path: &Path) -> io::Result<()> {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        if file.metadata()?.len() == 0 {
+            file.write_all(&header())?;
+        }
+        self.file = Some(file);
+        Ok(())
+    }
+
+    /// Records the inputs that building `output` discovered, appending
+    /// them to the log file if one is open. Nothing is written if they
+    /// are the same as last time.
+    pub fn record(
+        &mut self,
+        output: &blob::View,
+        mtime: Timestamp,
+        inputs: &[&blob::View],
+    ) -> io::Result<()> {
+        let mut buffer = vec![];
+        self.encode(output, mtime, inputs, &mut buffer)?;
+        if let Some(file) = &mut self.file {
+            file.write_all(&buffer)?;
+        }
+        Ok(())
+    }
+
+    /// Adds a record, appending whatever should be written to the file
+    /// for it to `out`.
+    fn encode(
+        &mut self,
+        output: &blob::View,
+        mtime: Timestamp,
+        inputs: &[&blob::View],
+        out: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        let size = 4 * (3 + inputs.len());
+        if size > MAX_RECORD_SIZE {
+            let message = "too many dependencies for the deps log";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        }
+
+        let output = self.intern(output, out)?;
+        let mut ids = Vec::with_capacity(inputs.len());
+        for input in inputs {
+            ids.push(self.intern(input, out)?);
+        }
+        if let Some(Some(record)) = self.records.get(output as usize) {
+            if record.mtime == mtime && record.inputs == ids {
+                return Ok(());
+            }
+        }
+
+        out.extend_from_slice(&(size as u32 | DEPS_FLAG).to_le_bytes());
+        out.extend_from_slice(&output.to_le_bytes());
+        out.extend_from_slice(&(mtime as u32).to_le_bytes());
+        out.extend_from_slice(&((mtime >> 32) as u32).to_le_bytes());
+        for id in ids.iter() {
+            out.extend_from_slice(&id.to_le_bytes());
+        }
+        let inputs = ids;
+        self.set_record(output, Record { mtime, inputs });
+        Ok(())
+    }
+
+    /// The id of `path`, adding a path record for it to `out` if it is
+    /// new.
+    fn intern(&mut self, path: &blob::View, out: &mut Vec<u8>) -> io::Result<u32> {
+        if let Some(id) = self.ids.get(path) {
+            return Ok(*id);
+        }
+        let padding = (4 - path.len() % 4) % 4;
+        let size = path.len() + padding + 4;
+        if size > MAX_RECORD_SIZE {
+            let message = "path too long for the deps log";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        }
+
+        let id = self.add_path(path);
+        out.extend_from_slice(&(size as u32).to_le_bytes());
+        out.extend_from_slice(path);
+        out.extend_from_slice(&[0; 3][..padding]);
+        out.extend_from_slice(&(!id).to_le_bytes());
+        Ok(id)
+    }
+
+    fn add_path(&mut self, path: &blob::View) -> u32 {
+        let id = self.paths.len() as u32;
+        self.paths.push(Blob::new(path));
+        self.ids.insert(Blob::new(path), id);
+        self.records.push(None);
+        id
+    }
+
+    fn set_record(&mut self, output: u32, record: Record) {
+        self.records[output as usize] = Some(record);
+        self.total += 1;
+    }
+
+    /// Rewrites the log at `path` with only the latest records for the
+    /// outputs that `keep` accepts, and continues appending to the new
+    /// file. Paths that are no longer mentioned are dropped and the rest
+    /// are renumbered.
+    pub fn recompact(&mut self, path: &Path, keep: &dyn Fn(&blob::View) -> bool) -> io::Result<()> {
+        self.file = None;
+        let mut compacted = DepsLog::new();
+        let mut buffer = header();
+        for (output, record) in self.paths.iter().zip(self.records.iter()) {
+            let record = match record {
+                Some(record) if keep(output) => record,
+                _ => continue,
+            };
+            let inputs = record
+                .inputs
+                .iter()
+                .map(|id| self.paths[*id as usize].view())
+                .collect::<Vec<_>>();
+            compacted.encode(output, record.mtime, &inputs, &mut buffer)?;
+        }
+
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".recompact");
+        let temp = Path::new(&temp);
+        std::fs::write(temp, &buffer)?;
+        std::fs::rename(temp, path)?;
+
+        compacted.valid_len = buffer.len();
+        compacted.version = Some(VERSION);
+        *self = compacted;
+        self.open(path)
+    }
+}
+
+impl dirty::Discovered for DepsLog {
+    fn deps(&self, output: &blob::View) -> Option<(Timestamp, Vec<&blob::View>)> {
+        let deps = self.lookup(output)?;
+        Some((deps.mtime(), deps.inputs().collect()))
+    }
+}
+
+fn header() -> Vec<u8> {
+    let mut header = SIGNATURE.to_vec();
+    header.extend_from_slice(&VERSION.to_le_bytes());
+    header
+}
+
+fn read_u32(bytes: &blob::View) -> Option<u32> {
+    let mut word = [0; 4];
+    word.copy_from_slice(bytes.get(..4)?);
+    Some(u32::from_le_bytes(word))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn inputs(log: &DepsLog, output: &blob::View) -> Vec<Blob> {
+        let deps = log.lookup(output).expect("no deps");
+        deps.inputs().map(Blob::new).collect()
+    }
+
+    #[test]
+    fn encoding() {
+        let mut log = DepsLog::new();
+        let mut buffer = vec![];
+        log.encode(b"out.o", 0x1_0000_0002, &[b"a.h", b"out.o"], &mut buffer)
+            .unwrap();
+        let expected: &[u8] = b"\x0c\x00\x00\x00out.o\x00\x00\x00\xff\xff\xff\xff\
+                                \x08\x00\x00\x00a.h\x00\xfe\xff\xff\xff\
+                                \x14\x00\x00\x80\x00\x00\x00\x00\
+                                \x02\x00\x00\x00\x01\x00\x00\x00\
+                                \x01\x00\x00\x00\x00\x00\x00\x00";
+        assert_eq!(buffer, expected);
+
+        let mut file = header();
+        file.extend_from_slice(&buffer);
+        assert!(file.starts_with(b"# ninjadeps\n\x04\x00\x00\x00"));
+        let log = DepsLog::parse(&file);
+        assert_eq!(
+            log.lookup(b"out.o").expect("no deps").mtime(),
+            0x1_0000_0002
+        );
+        assert_eq!(
+            inputs(&log, b"out.o"),
+            vec![Blob::new(b"a.h"), Blob::new(b"out.o")]
+        );
+        assert_eq!(log.valid_len, file.len());
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = testing::temp_dir("deps-log-round-trip");
+        let path = dir.join(".ninja_deps");
+        let mut log = DepsLog::new();
+        log.open(&path).unwrap();
+        log.record(b"a.o", 1, &[b"a.c", b"common.h"]).unwrap();
+        log.record(b"b.o", 2, &[b"b.c", b"common.h"]).unwrap();
+        let size = std::fs::metadata(&path).unwrap().len();
+        log.record(b"b.o", 2, &[b"b.c", b"common.h"]).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+        log.record(b"a.o", 3, &[b"a.c"]).unwrap();
+        drop(log);
+
+        let mut log = DepsLog::load(&path).expect("failed to load");
+        assert_eq!(log.lookup(b"a.o").expect("no deps").mtime(), 3);
+        assert_eq!(inputs(&log, b"a.o"), vec![Blob::new(b"a.c")]);
+        assert_eq!(inputs(&log, b"b.o").len(), 2);
+        assert!(log.lookup(b"a.c").is_none());
+        assert_eq!(log.outputs().count(), 2);
+        assert_eq!(log.total, 3);
+
+        log.open(&path).unwrap();
+        log.record(b"c.o", 4, &[b"c.c", b"common.h"]).unwrap();
+        drop(log);
+        let log = DepsLog::load(&path).expect("failed to load");
+        assert_eq!(
+            inputs(&log, b"c.o"),
+            vec![Blob::new(b"c.c"), Blob::new(b"common.h")]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncated() {
+        let mut log = DepsLog::new();
+        let mut file = header();
+        log.encode(b"a.o", 1, &[b"a.c", b"a.h"], &mut file).unwrap();
+        let complete = file.len();
+        log.encode(b"b.o", 2, &[b"b.c"], &mut file).unwrap();
+
+        // Every prefix keeps the records it holds completely, and only
+        // those.
+        for len in complete..file.len() {
+            let log = DepsLog::parse(&file[..len]);
+            assert_eq!(inputs(&log, b"a.o").len(), 2);
+            assert!(log.lookup(b"b.o").is_none());
+            assert!(log.valid_len <= len);
+        }
+        for len in SIGNATURE.len() + 4..complete {
+            let log = DepsLog::parse(&file[..len]);
+            assert!(log.lookup(b"a.o").is_none());
+        }
+
+        let dir = testing::temp_dir("deps-log-truncated");
+        let path = dir.join(".ninja_deps");
+        std::fs::write(&path, &file[..file.len() - 3]).unwrap();
+        let mut log = DepsLog::load(&path).expect("failed to load");
+        let valid = std::fs::metadata(&path).unwrap().len() as usize;
+        assert!(valid < file.len() - 3);
+        assert_eq!(valid, log.valid_len);
+
+        log.open(&path).unwrap();
+        log.record(b"b.o", 2, &[b"b.c"]).unwrap();
+        drop(log);
+        let log = DepsLog::load(&path).expect("failed to load");
+        assert_eq!(inputs(&log, b"b.o"), vec![Blob::new(b"b.c")]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_records() {
+        let mut file = header();
+        // A path whose checksum does not match its id.
+        file.extend_from_slice(b"\x08\x00\x00\x00a.c\x00\x00\x00\x00\x00");
+        let log = DepsLog::parse(&file);
+        assert_eq!(log.paths.len(), 0);
+        assert_eq!(log.valid_len, SIGNATURE.len() + 4);
+
+        let mut file = header();
+        DepsLog::new().encode(b"a.o", 1, &[], &mut file).unwrap();
+        let valid = file.len();
+        // Deps naming a path id that was never defined.
+        file.extend_from_slice(b"\x10\x00\x00\x80\x00\x00\x00\x00\x01\x00\x00\x00");
+        file.extend_from_slice(b"\x00\x00\x00\x00\x07\x00\x00\x00");
+        let log = DepsLog::parse(&file);
+        assert_eq!(log.lookup(b"a.o").expect("no deps").mtime(), 1);
+        assert_eq!(log.valid_len, valid);
+    }
+
+    #[test]
+    fn versions() {
+        let unsupported: &[&blob::View] = &[
+            b"# ninjadeps\n\x03\x00\x00\x00\x04\x00\x00\x00a.o\0",
+            b"# ninjadeps\n\x04",
+            b"# ninja log v5\n",
+            b"",
+        ];
+        for input in unsupported.iter() {
+            let log = DepsLog::parse(input);
+            assert_eq!(log.outputs().count(), 0);
+            assert_eq!(log.valid_len, input.len());
+            assert!(log.needs_recompaction());
+        }
+    }
+
+    #[test]
+    fn load_other_version() {
+        let dir = testing::temp_dir("deps-log-version");
+        let path = dir.join(".ninja_deps");
+        let contents = b"# ninjadeps\n\x03\x00\x00\x00\x04\x00\x00\x00a.o\0";
+        std::fs::write(&path, contents).unwrap();
+        let mut log = DepsLog::load(&path).expect("failed to load");
+        assert!(log.needs_recompaction());
+        assert_eq!(std::fs::read(&path).unwrap(), contents);
+
+        log.recompact(&path, &|_| true).unwrap();
+        log.record(b"a.o", 1, &[b"a.c"]).unwrap();
+        drop(log);
+        let log = DepsLog::load(&path).expect("failed to load");
+        assert!(!log.needs_recompaction());
+        assert_eq!(inputs(&log, b"a.o"), vec![Blob::new(b"a.c")]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recompact() {
+        let dir = testing::temp_dir("deps-log-recompact");
+        let path = dir.join(".ninja_deps");
+        let mut log = DepsLog::new();
+        log.open(&path).unwrap();
+        for mtime in 0..MIN_COMPACTION_ENTRIES as Timestamp {
+            log.record(b"a.o", mtime, &[b"a.c", b"a.h"]).unwrap();
+            log.record(b"gone.o", mtime, &[b"gone.c"]).unwrap();
+        }
+        drop(log);
+
+        let mut log = DepsLog::load(&path).expect("failed to load");
+        assert!(log.needs_recompaction());
+        let before = std::fs::metadata(&path).unwrap().len();
+        log.recompact(&path, &|output| output != b"gone.o").unwrap();
+        assert!(!log.needs_recompaction());
+        assert!(std::fs::metadata(&path).unwrap().len() < before);
+        assert!(!dir.join(".ninja_deps.recompact").exists());
+        log.record(b"b.o", 1, &[b"a.h"]).unwrap();
+        drop(log);
+
+        let log = DepsLog::load(&path).expect("failed to load");
+        assert!(log.lookup(b"gone.o").is_none());
+        assert_eq!(log.paths.len(), 4);
+        let last = MIN_COMPACTION_ENTRIES as Timestamp - 1;
+        assert_eq!(log.lookup(b"a.o").expect("no deps").mtime(), last);
+        assert_eq!(inputs(&log, b"b.o"), vec![Blob::new(b"a.h")]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn discovered() {
+        use crate::dirty::Discovered;
+        let mut log = DepsLog::new();
+        log.record(b"a.o", 5, &[b"a.c", b"a.h"]).unwrap();
+        let (mtime, inputs) = log.deps(b"a.o").expect("no deps");
+        assert_eq!(mtime, 5);
+        assert_eq!(inputs, vec![&b"a.c"[..], &b"a.h"[..]]);
+        assert!(log.deps(b"a.c").is_none());
+    }
+}
@@ -0,0 +1,765 @@
+//! Deciding which edges of a graph need to run.
+//!
+//! An edge is dirty if any of its outputs is missing, older than its
+//! newest explicit or implicit input, or was last built by a different
+//! command, or if any of those inputs is itself dirty. Order-only inputs
+//! are brought up to date but never make an edge dirty. Dependencies
+//! that a command discovered the last time it ran are added to the graph
+//! as implicit inputs as its edge is reached.
+
+use crate::arena;
+use crate::blob;
+use crate::blob::Blob;
+use crate::depfile;
+use crate::depfile::DepfileError;
+use crate::graph::{Edge, Graph, Node};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+
+/// A modification time, in nanoseconds since the Unix epoch.
+pub type Timestamp = u64;
+
+/// Access to the files named by a graph.
+pub trait FileSystem {
+    /// The modification time of `path`, or `None` if it does not exist.
+    fn stat(&self, path: &blob::View) -> io::Result<Option<Timestamp>>;
+
+    /// The contents of `path`.
+    fn read(&self, path: &blob::View) -> io::Result<Blob>;
+}
+
+/// The file system of the running process.
+pub struct RealFileSystem;
+
+impl FileSystem for RealFileSystem {
+    fn stat(&self, path: &blob::View) -> io::Result<Option<Timestamp>> {
+        let metadata = match std::fs::metadata(blob::to_path(path)) {
+            Ok(metadata) => metadata,
+            Err(error) if missing(&error) => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        let modified = metadata.modified()?;
+        let since_epoch = match modified.duration_since(std::time::UNIX_EPOCH) {
+            Ok(duration) => duration,
+            Err(_) => std::time::Duration::from_secs(0),
+        };
+        Ok(Some(since_epoch.as_nanos() as Timestamp))
+    }
+
+    fn read(&self, path: &blob::View) -> io::Result<Blob> {
+        std::fs::read(blob::to_path(path)).map(Blob::from)
+    }
+}
+
+/// Whether `error` means that a path does not exist, including when one
+/// of its parents is a file rather than a directory.
+fn missing(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory
+    )
+}
+
+/// A file system that exists only in memory, for tests and dry runs.
+#[derive(Default)]
+pub struct MemoryFileSystem {
+    files: HashMap<Blob, (Timestamp, Blob)>,
+}
+
+impl MemoryFileSystem {
+    pub fn new() -> MemoryFileSystem {
+        MemoryFileSystem::default()
+    }
+
+    /// Creates an empty file at `path`, or updates its modification time.
+    pub fn touch(&mut self, path: &blob::View, mtime: Timestamp) {
+        match self.files.get_mut(path) {
+            Some(file) => file.0 = mtime,
+            None => self.write(path, mtime, b""),
+        }
+    }
+
+    pub fn write(&mut self, path: &blob::View, mtime: Timestamp, contents: &blob::View) {
+        self.files
+            .insert(Blob::new(path), (mtime, Blob::new(contents)));
+    }
+
+    pub fn remove(&mut self, path: &blob::View) {
+        self.files.remove(path);
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn stat(&self, path: &blob::View) -> io::Result<Option<Timestamp>> {
+        Ok(self.files.get(path).map(|(mtime, _)| *mtime))
+    }
+
+    fn read(&self, path: &blob::View) -> io::Result<Blob> {
+        match self.files.get(path) {
+            Some((_, contents)) => Ok(contents.clone()),
+            None => Err(io::Error::from(io::ErrorKind::NotFound)),
+        }
+    }
+}
+
+/// What earlier builds recorded about their outputs.
+pub trait History {
+    /// When `output` was last built, or `None` if it never was.
+    fn mtime(&self, output: &blob::View) -> Option<Timestamp>;
+
+    /// Whether `output` was last built by a command other than `command`.
+    fn command_changed(&self, output: &blob::View, command: &blob::View) -> bool;
+}
+
+/// Dependencies that commands reported as they ran, such as the headers
+/// a compiler read, for edges with `deps` set.
+pub trait Discovered {
+    /// The inputs recorded for `output`, along with its modification
+    /// time when they were recorded, or `None` if nothing was recorded.
+    fn deps(&self, output: &blob::View) -> Option<(Timestamp, Vec<&blob::View>)>;
+}
+
+#[derive(Debug)]
+pub enum DirtyError {
+    Stat { path: Blob, error: io::Error },
+    Cycle { paths: Vec<Blob> },
+    Depfile(DepfileError),
+}
+
+impl fmt::Display for DirtyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DirtyError::Stat { path, error } => {
+                write!(f, "stat({}): {}", String::from_utf8_lossy(path), error)
+            }
+            DirtyError::Cycle { paths } => {
+                write!(f, "dependency cycle: ")?;
+                for (index, path) in paths.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, " -> ")?;
+                    }
+                    write!(f, "{}", String::from_utf8_lossy(path))?;
+                }
+                Ok(())
+            }
+            DirtyError::Depfile(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for DirtyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DirtyError::Stat { error, .. } => Some(error),
+            DirtyError::Cycle { .. } => None,
+            DirtyError::Depfile(error) => Some(error),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Visit {
+    New,
+    InProgress,
+    Done,
+}
+
+/// The state of the parts of a graph needed to build some targets.
+pub struct Dirty {
+    mtimes: Vec<Option<Option<Timestamp>>>,
+    dirty_nodes: Vec<bool>,
+    dirty_edges: Vec<bool>,
+    visits: Vec<Visit>,
+}
+
+impl Dirty {
+    /// Stats every node that `targets` depend on and works out which
+    /// edges must run. The dependencies that those edges discovered, in
+    /// `discovered` for edges with `deps` and in depfiles for the others,
+    /// are first added to `graph` as implicit inputs. Without a
+    /// `history`, changed commands go unnoticed and `restat` has no
+    /// effect. Without `discovered` dependencies, edges with `deps` only
+    /// depend on their declared inputs.
+    pub fn compute(
+        graph: &mut Graph,
+        file_system: &dyn FileSystem,
+        history: Option<&dyn History>,
+        discovered: Option<&dyn Discovered>,
+        targets: &[arena::Id<Node>],
+    ) -> Result<Dirty, DirtyError> {
+        let mut loader = Loader {
+            file_system,
+            discovered,
+            stale: vec![false; graph.edges().count()],
+            mtimes: vec![],
+        };
+        loader.load(graph, targets)?;
+
+        let nodes = graph.nodes().count();
+        let edges = graph.edges().count();
+        let mut checker = Checker {
+            graph,
+            file_system,
+            history,
+            stale: loader.stale,
+            dirty: Dirty {
+                mtimes: vec![None; nodes],
+                dirty_nodes: vec![false; nodes],
+                dirty_edges: vec![false; edges],
+                visits: vec![Visit::New; edges],
+            },
+            stack: vec![],
+        };
+        for (node, mtime) in loader.mtimes {
+            checker.dirty.mtimes[node.index()] = Some(mtime);
+        }
+        for target in targets {
+            checker.visit_node(*target)?;
+        }
+        Ok(checker.dirty)
+    }
+
+    /// Whether `edge` must run.
+    pub fn is_dirty(&self, edge: arena::Id<Edge>) -> bool {
+        self.dirty_edges[edge.index()]
+    }
+
+    /// Whether `node` is out of date, either because the edge producing
+    /// it is dirty or because it is a missing source file.
+    pub fn is_node_dirty(&self, node: arena::Id<Node>) -> bool {
+        self.dirty_nodes[node.index()]
+    }
+
+    /// The modification time of `node`, or `None` if it is missing or
+    /// was not needed for the targets.
+    pub fn mtime(&self, node: arena::Id<Node>) -> Option<Timestamp> {
+        self.mtimes[node.index()].unwrap_or(None)
+    }
+}
+
+fn stat(file_system: &dyn FileSystem, path: &blob::View) -> Result<Option<Timestamp>, DirtyError> {
+    match file_system.stat(path) {
+        Ok(mtime) => Ok(mtime),
+        Err(error) => {
+            let path = Blob::new(path);
+            Err(DirtyError::Stat { path, error })
+        }
+    }
+}
+
+/// Adds the dependencies that edges discovered to the graph, like
+/// ninja's `ImplicitDepLoader`, for only the edges that are needed.
+struct Loader<'a> {
+    file_system: &'a dyn FileSystem,
+    discovered: Option<&'a dyn Discovered>,
+    /// The edges whose discovered dependencies are missing or out of
+    /// date, which must run to find them again.
+    stale: Vec<bool>,
+    /// The outputs statted along the way.
+    mtimes: Vec<(arena::Id<Node>, Option<Timestamp>)>,
+}
+
+impl<'a> Loader<'a> {
+    fn load(&mut self, graph: &mut Graph, targets: &[arena::Id<Node>]) -> Result<(), DirtyError> {
+        let mut visited = vec![false; self.stale.len()];
+        let mut stack = targets.to_vec();
+        while let Some(node) = stack.pop() {
+            let edge = match graph.node(node).in_edge() {
+                Some(edge) => edge,
+                None => continue,
+            };
+            if std::mem::replace(&mut visited[edge.index()], true) {
+                continue;
+            }
+            self.stale[edge.index()] = !self.load_edge(graph, edge)?;
+            // The edge's inputs now include those just added.
+            stack.extend(graph.edge(edge).all_inputs());
+        }
+        Ok(())
+    }
+
+    /// Loads the discovered dependencies of `edge`, and returns whether
+    /// they were found and up to date.
+    fn load_edge(&mut self, graph: &mut Graph, edge: arena::Id<Edge>) -> Result<bool, DirtyError> {
+        if graph.edge(edge).deps().is_none() {
+            return match depfile::load(graph, edge, self.file_system) {
+                Ok(found) => Ok(found),
+                Err(error) => Err(DirtyError::Depfile(error)),
+            };
+        }
+
+        let discovered = match self.discovered {
+            Some(discovered) => discovered,
+            None => return Ok(true),
+        };
+        let output = match graph.edge(edge).outputs().first() {
+            Some(output) => *output,
+            None => return Ok(true),
+        };
+        let path = graph.node(output).path();
+        let (recorded, inputs) = match discovered.deps(path) {
+            Some(deps) => deps,
+            None => return Ok(false),
+        };
+        let mtime = stat(self.file_system, path)?;
+        self.mtimes.push((output, mtime));
+        if mtime.is_some_and(|mtime| mtime > recorded) {
+            // The output was rebuilt without recording what it read.
+            return Ok(false);
+        }
+        let inputs = inputs.into_iter().map(Blob::new).collect::<Vec<_>>();
+        graph.add_implicit_inputs(edge, &inputs);
+        Ok(true)
+    }
+}
+
+struct Checker<'a> {
+    graph: &'a Graph,
+    file_system: &'a dyn FileSystem,
+    history: Option<&'a dyn History>,
+    /// The edges whose discovered dependencies could not be loaded.
+    stale: Vec<bool>,
+    dirty: Dirty,
+    /// The nodes whose producing edges are being visited, for reporting
+    /// cycles.
+    stack: Vec<arena::Id<Node>>,
+}
+
+impl<'a> Checker<'a> {
+    fn stat(&mut self, node: arena::Id<Node>) -> Result<Option<Timestamp>, DirtyError> {
+        if let Some(mtime) = self.dirty.mtimes[node.index()] {
+            return Ok(mtime);
+        }
+        let mtime = stat(self.file_system, self.graph.node(node).path())?;
+        self.dirty.mtimes[node.index()] = Some(mtime);
+        Ok(mtime)
+    }
+
+    fn visit_node(&mut self, node: arena::Id<Node>) -> Result<(), DirtyError> {
+        match self.graph.node(node).in_edge() {
+            Some(edge) => {
+                self.stack.push(node);
+                self.visit_edge(edge)?;
+                self.stack.pop();
+            }
+            None => {
+                let missing = self.stat(node)?.is_none();
+                self.dirty.dirty_nodes[node.index()] = missing;
+            }
+        }
+        Ok(())
+    }
+
+    fn visit_edge(&mut self, id: arena::Id<Edge>) -> Result<(), DirtyError> {
+        match self.dirty.visits[id.index()] {
+            Visit::Done => return Ok(()),
+            Visit::InProgress => return Err(self.cycle(id)),
+            Visit::New => self.dirty.visits[id.index()] = Visit::InProgress,
+        }
+
+        let edge = self.graph.edge(id);
+        for output in edge.all_outputs() {
+            self.stat(output)?;
+        }
+
+        let mut dirty = false;
+        let mut newest_input = None;
+        let order_only = edge.all_inputs().count() - edge.order_inputs().len();
+        for (index, input) in edge.all_inputs().enumerate() {
+            self.visit_node(input)?;
+            if index >= order_only {
+                continue;
+            }
+            if self.dirty.is_node_dirty(input) {
+                dirty = true;
+            } else if let Some(mtime) = self.dirty.mtime(input) {
+                newest_input = newest_input.max(Some(mtime));
+            }
+        }
+        if self.stale[id.index()] {
+            // What the command depends on is only known once it has run.
+            dirty = true;
+        }
+
+        if !dirty {
+            dirty = self.outputs_dirty(edge, newest_input);
+        }
+
+        // Phony outputs stand in for their inputs, so edges that use them
+        // should see the inputs' times.
+        if edge.is_phony() && newest_input.is_some() {
+            for output in edge.all_outputs() {
+                let mtime = &mut self.dirty.mtimes[output.index()];
+                *mtime = Some(mtime.unwrap_or(None).max(newest_input));
+            }
+        }
+
+        for output in edge.all_outputs() {
+            self.dirty.dirty_nodes[output.index()] = dirty;
+        }
+        self.dirty.dirty_edges[id.index()] = dirty;
+        self.dirty.visits[id.index()] = Visit::Done;
+        Ok(())
+    }
+
+    fn outputs_dirty(&self, edge: &Edge, newest_input: Option<Timestamp>) -> bool {
+        let older = |mtime| newest_input.is_some_and(|newest| mtime < newest);
+        edge.all_outputs().any(|output| {
+            let path = self.graph.node(output).path();
+            let mtime = self.dirty.mtime(output);
+            if edge.is_phony() {
+                // Outputs only make a phony edge dirty when it has no
+                // inputs and they do not exist.
+                return edge.all_inputs().next().is_none() && mtime.is_none();
+            }
+            let mut mtime = match mtime {
+                Some(mtime) => mtime,
+                None => return true,
+            };
+
+            let history = match self.history {
+                Some(history) => history,
+                None => return older(mtime),
+            };
+            let built = history.mtime(path);
+            if edge.is_restat() {
+                // An output the command chose not to touch is as new as
+                // the last time the command ran.
+                if let Some(built) = built {
+                    mtime = built;
+                }
+            }
+            if older(mtime) {
+                return true;
+            }
+            match built {
+                Some(built) => {
+                    (!edge.is_generator() && history.command_changed(path, edge.command()))
+                        || older(built)
+                }
+                None => !edge.is_generator(),
+            }
+        })
+    }
+
+    fn cycle(&self, edge: arena::Id<Edge>) -> DirtyError {
+        let start = self
+            .stack
+            .iter()
+            .position(|node| self.graph.node(*node).in_edge() == Some(edge))
+            .unwrap_or(0);
+        // The stack ends with the node that closed the cycle.
+        let paths = self.stack[start..]
+            .iter()
+            .map(|node| Blob::new(self.graph.node(*node).path()))
+            .collect();
+        DirtyError::Cycle { paths }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use crate::testing::graph;
+
+    fn node(graph: &Graph, path: &blob::View) -> arena::Id<Node> {
+        graph.lookup(path).expect("no such node")
+    }
+
+    fn edge(graph: &Graph, output: &blob::View) -> arena::Id<Edge> {
+        let node = node(graph, output);
+        graph.node(node).in_edge().expect("no producer")
+    }
+
+    fn files(files: &[(&str, Timestamp)]) -> MemoryFileSystem {
+        let mut file_system = MemoryFileSystem::new();
+        for (path, mtime) in files.iter() {
+            file_system.touch(path.as_bytes(), *mtime);
+        }
+        file_system
+    }
+
+    #[derive(Default)]
+    struct Log {
+        entries: HashMap<Blob, (Blob, Timestamp)>,
+    }
+
+    impl Log {
+        fn record(&mut self, output: &str, command: &str, mtime: Timestamp) {
+            let entry = (Blob::new(command.as_bytes()), mtime);
+            self.entries.insert(Blob::new(output.as_bytes()), entry);
+        }
+    }
+
+    impl History for Log {
+        fn mtime(&self, output: &blob::View) -> Option<Timestamp> {
+            self.entries.get(output).map(|(_, mtime)| *mtime)
+        }
+
+        fn command_changed(&self, output: &blob::View, command: &blob::View) -> bool {
+            match self.entries.get(output) {
+                Some((logged, _)) => logged.view() != command,
+                None => true,
+            }
+        }
+    }
+
+    fn compute(
+        graph: &mut Graph,
+        file_system: &MemoryFileSystem,
+        history: Option<&dyn History>,
+        target: &blob::View,
+    ) -> Dirty {
+        let target = node(graph, target);
+        Dirty::compute(graph, file_system, history, None, &[target]).expect("failed to compute")
+    }
+
+    const CC: &[u8] = b"rule cc\n    command = cc $in -o $out\n\
+                        build a.o: cc a.c | a.h || gen.h\n\
+                        rule gen\n    command = gen $out\n\
+                        build gen.h: gen\n\
+                        rule link\n    command = ld $in -o $out\n\
+                        build app: link a.o\n";
+
+    #[test]
+    fn up_to_date() {
+        let mut graph = graph(CC);
+        let file_system = files(&[("a.c", 1), ("a.h", 1), ("gen.h", 1), ("a.o", 2), ("app", 3)]);
+        let dirty = compute(&mut graph, &file_system, None, b"app");
+        assert!(!dirty.is_dirty(edge(&graph, b"app")));
+        assert!(!dirty.is_dirty(edge(&graph, b"a.o")));
+        assert_eq!(dirty.mtime(node(&graph, b"a.o")), Some(2));
+    }
+
+    #[test]
+    fn missing_output() {
+        let mut graph = graph(CC);
+        let file_system = files(&[("a.c", 1), ("a.h", 1), ("gen.h", 1), ("app", 3)]);
+        let dirty = compute(&mut graph, &file_system, None, b"app");
+        assert!(dirty.is_dirty(edge(&graph, b"a.o")));
+        assert!(dirty.is_node_dirty(node(&graph, b"a.o")));
+        assert!(dirty.is_dirty(edge(&graph, b"app")));
+    }
+
+    #[test]
+    fn newer_input() {
+        let mut graph = graph(CC);
+        let file_system = files(&[("a.c", 1), ("a.h", 3), ("gen.h", 1), ("a.o", 2), ("app", 3)]);
+        let dirty = compute(&mut graph, &file_system, None, b"app");
+        assert!(dirty.is_dirty(edge(&graph, b"a.o")));
+        assert!(dirty.is_dirty(edge(&graph, b"app")));
+    }
+
+    #[test]
+    fn missing_source() {
+        let mut graph = graph(CC);
+        let file_system = files(&[("a.h", 1), ("gen.h", 1), ("a.o", 2), ("app", 3)]);
+        let dirty = compute(&mut graph, &file_system, None, b"app");
+        assert!(dirty.is_node_dirty(node(&graph, b"a.c")));
+        assert!(dirty.is_dirty(edge(&graph, b"a.o")));
+    }
+
+    #[test]
+    fn order_only_inputs() {
+        let mut graph = graph(CC);
+        let file_system = files(&[("a.c", 1), ("a.h", 1), ("a.o", 2), ("app", 3)]);
+        let dirty = compute(&mut graph, &file_system, None, b"app");
+        assert!(dirty.is_dirty(edge(&graph, b"gen.h")));
+        assert!(!dirty.is_dirty(edge(&graph, b"a.o")));
+
+        let file_system = files(&[("a.c", 1), ("a.h", 1), ("gen.h", 5), ("a.o", 2), ("app", 3)]);
+        let dirty = compute(&mut graph, &file_system, None, b"app");
+        assert!(!dirty.is_dirty(edge(&graph, b"a.o")));
+    }
+
+    #[test]
+    fn command_changed() {
+        let mut graph = graph(CC);
+        let file_system = files(&[("a.c", 1), ("a.h", 1), ("gen.h", 1), ("a.o", 2), ("app", 3)]);
+        let mut log = Log::default();
+        log.record("gen.h", "gen gen.h", 1);
+        log.record("a.o", "cc a.c -o a.o", 2);
+        log.record("app", "ld a.o -o app", 3);
+        let clean = compute(&mut graph, &file_system, Some(&log), b"app");
+        assert!(!clean.is_dirty(edge(&graph, b"app")));
+
+        log.record("a.o", "cc -O2 a.c -o a.o", 2);
+        let changed = compute(&mut graph, &file_system, Some(&log), b"app");
+        assert!(changed.is_dirty(edge(&graph, b"a.o")));
+        assert!(changed.is_dirty(edge(&graph, b"app")));
+        assert!(!changed.is_dirty(edge(&graph, b"gen.h")));
+
+        log.entries.remove(b"app".as_ref());
+        let unlogged = compute(&mut graph, &file_system, Some(&log), b"app");
+        assert!(unlogged.is_dirty(edge(&graph, b"app")));
+    }
+
+    #[test]
+    fn generator() {
+        let mut graph = graph(
+            b"rule configure\n    command = configure\n    generator = 1\n\
+              build build.ninja: configure configure.py\n",
+        );
+        let file_system = files(&[("configure.py", 1), ("build.ninja", 2)]);
+        let mut log = Log::default();
+        log.record("build.ninja", "an older configure", 2);
+        let dirty = compute(&mut graph, &file_system, Some(&log), b"build.ninja");
+        assert!(!dirty.is_dirty(edge(&graph, b"build.ninja")));
+
+        let empty = Log::default();
+        let dirty = compute(&mut graph, &file_system, Some(&empty), b"build.ninja");
+        assert!(!dirty.is_dirty(edge(&graph, b"build.ninja")));
+    }
+
+    #[test]
+    fn restat() {
+        let ninja = b"rule touch\n    command = touch-if-changed $out\n    restat = 1\n\
+                      build header.h: touch header.in\n\
+                      rule cc\n    command = cc $in\n\
+                      build a.o: cc header.h\n";
+        let mut graph = graph(ninja);
+        let file_system = files(&[("header.in", 2), ("header.h", 1), ("a.o", 3)]);
+        let mut log = Log::default();
+        log.record("header.h", "touch-if-changed header.h", 3);
+        log.record("a.o", "cc header.h", 3);
+        let dirty = compute(&mut graph, &file_system, Some(&log), b"a.o");
+        assert!(!dirty.is_dirty(edge(&graph, b"header.h")));
+        assert!(!dirty.is_dirty(edge(&graph, b"a.o")));
+
+        log.record("header.h", "touch-if-changed header.h", 1);
+        let dirty = compute(&mut graph, &file_system, Some(&log), b"a.o");
+        assert!(dirty.is_dirty(edge(&graph, b"header.h")));
+        assert!(dirty.is_dirty(edge(&graph, b"a.o")));
+    }
+
+    #[test]
+    fn phony() {
+        let ninja = b"rule cc\n    command = cc $in -o $out\n\
+                      build a.o: cc a.c\n\
+                      build all: phony a.o\n\
+                      build always: phony\n\
+                      build b.o: cc b.c | always\n";
+        let mut graph = graph(ninja);
+        let file_system = files(&[("a.c", 1), ("a.o", 2), ("b.c", 1), ("b.o", 2)]);
+        let dirty = compute(&mut graph, &file_system, None, b"all");
+        assert!(!dirty.is_dirty(edge(&graph, b"all")));
+        assert_eq!(dirty.mtime(node(&graph, b"all")), Some(2));
+
+        let b = compute(&mut graph, &file_system, None, b"b.o");
+        assert!(b.is_dirty(edge(&graph, b"always")));
+        assert!(b.is_dirty(edge(&graph, b"b.o")));
+    }
+
+    #[derive(Default)]
+    struct Deps {
+        entries: HashMap<Blob, (Timestamp, Vec<Blob>)>,
+    }
+
+    impl Discovered for Deps {
+        fn deps(&self, output: &blob::View) -> Option<(Timestamp, Vec<&blob::View>)> {
+            let (mtime, inputs) = self.entries.get(output)?;
+            Some((*mtime, inputs.iter().map(|input| input.view()).collect()))
+        }
+    }
+
+    #[test]
+    fn discovered() {
+        let ninja = b"rule cc\n    command = cc $in -o $out\n    deps = gcc\n\
+                      build a.o: cc a.c\n\
+                      rule gen\n    command = gen $out\n\
+                      build gen.h: gen\n";
+        let graph = graph(ninja);
+        let target = node(&graph, b"a.o");
+        let mut file_system = files(&[("a.c", 1), ("a.h", 1), ("gen.h", 1), ("a.o", 2)]);
+        let mut deps = Deps::default();
+        let check = |file_system: &MemoryFileSystem, deps: &Deps| {
+            let mut graph = self::graph(ninja);
+            let dirty = Dirty::compute(&mut graph, file_system, None, Some(deps), &[target]);
+            dirty.expect("failed to compute")
+        };
+
+        assert!(check(&file_system, &deps).is_dirty(edge(&graph, b"a.o")));
+
+        let inputs = vec![Blob::new(b"a.h"), Blob::new(b"gen.h")];
+        deps.entries.insert(Blob::new(b"a.o"), (2, inputs));
+        let dirty = check(&file_system, &deps);
+        assert!(!dirty.is_dirty(edge(&graph, b"a.o")));
+        assert!(!dirty.is_dirty(edge(&graph, b"gen.h")));
+
+        file_system.touch(b"a.h", 3);
+        assert!(check(&file_system, &deps).is_dirty(edge(&graph, b"a.o")));
+        file_system.touch(b"a.h", 1);
+
+        file_system.remove(b"gen.h");
+        let dirty = check(&file_system, &deps);
+        assert!(dirty.is_dirty(edge(&graph, b"gen.h")));
+        assert!(dirty.is_dirty(edge(&graph, b"a.o")));
+        file_system.touch(b"gen.h", 1);
+
+        file_system.remove(b"a.h");
+        assert!(check(&file_system, &deps).is_dirty(edge(&graph, b"a.o")));
+        file_system.touch(b"a.h", 1);
+
+        file_system.touch(b"a.o", 4);
+        assert!(check(&file_system, &deps).is_dirty(edge(&graph, b"a.o")));
+    }
+
+    #[test]
+    fn depfiles_of_needed_edges() {
+        let ninja = b"rule cc\n    command = cc $in -o $out\n    depfile = $out.d\n\
+                      build a.o: cc a.c\n\
+                      build b.o: cc b.c\n";
+        let mut graph = graph(ninja);
+        let mut file_system = files(&[("a.c", 1), ("a.h", 1), ("a.o", 2), ("b.c", 1)]);
+        file_system.write(b"a.o.d", 2, b"a.o: a.c a.h\n");
+        file_system.write(b"b.o.d", 2, b"b.o b.c\n");
+
+        assert_eq!(graph.lookup(b"a.h"), None);
+        let dirty = compute(&mut graph, &file_system, None, b"a.o");
+        assert!(!dirty.is_dirty(edge(&graph, b"a.o")));
+        assert!(graph.lookup(b"a.h").is_some());
+        file_system.touch(b"a.h", 3);
+        let dirty = compute(&mut graph, &file_system, None, b"a.o");
+        assert!(dirty.is_dirty(edge(&graph, b"a.o")));
+
+        let target = node(&graph, b"b.o");
+        match Dirty::compute(&mut graph, &file_system, None, None, &[target]) {
+            Err(error @ DirtyError::Depfile(_)) => {
+                assert!(error.to_string().contains("expected ':' in depfile"))
+            }
+            _ => panic!("accepted a bad depfile"),
+        }
+    }
+
+    #[test]
+    fn cycle() {
+        let ninja = b"rule cat\n    command = cat $in > $out\n\
+                      build a: cat b\n\
+                      build b: cat c\n\
+                      build c: cat b\n";
+        let mut graph = graph(ninja);
+        let target = node(&graph, b"a");
+        let file_system = MemoryFileSystem::new();
+        match Dirty::compute(&mut graph, &file_system, None, None, &[target]) {
+            Err(error @ DirtyError::Cycle { .. }) => {
+                assert_eq!(error.to_string(), "dependency cycle: b -> c -> b")
+            }
+            _ => panic!("expected a cycle"),
+        }
+    }
+
+    #[test]
+    fn real_file_system() {
+        let dir = testing::temp_dir("dirty");
+        let file = dir.join("file");
+        std::fs::write(&file, b"").unwrap();
+
+        let stat = |path: &std::path::Path| RealFileSystem.stat(&blob::from_path(path));
+        assert!(stat(&file).unwrap().is_some());
+        assert_eq!(stat(&dir.join("missing")).unwrap(), None);
+        assert_eq!(stat(&file.join("under-a-file")).unwrap(), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
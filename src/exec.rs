@@ -0,0 +1,1421 @@
+//! Running the commands of dirty edges.
+//!
+//! A [`Builder`] walks the part of a graph needed for some targets and
+//! starts each dirty edge once every edge producing one of its inputs,
+//! order-only inputs included, has finished. Commands run through a
+//! [`CommandRunner`], so that scheduling can be tested without spawning
+//! processes.
+//!
+//! Edges in a pool wait for one of its slots, in the order they became
+//! ready. Commands in the `console` pool, which has a single slot, run
+//! with direct access to the terminal, and the status of other edges is
+//! held back until they finish.
+
+use crate::arena;
+use crate::blob;
+use crate::blob::Blob;
+use crate::canon;
+use crate::depfile;
+use crate::deps_log::DepsLog;
+use crate::dirty::{Dirty, FileSystem};
+use crate::graph::{DepsType, Edge, Graph, Node};
+use crate::jobserver;
+use crate::log;
+use crate::log::Log;
+use crate::msvc;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::io;
+use std::io::Read;
+use std::process;
+use std::sync::mpsc;
+use std::time::Instant;
+
+/// A command that has stopped running.
+#[derive(Debug)]
+pub struct Finished {
+    pub edge: arena::Id<Edge>,
+    pub success: bool,
+    /// What the command wrote to stdout and stderr.
+    pub output: Blob,
+}
+
+/// Runs commands, possibly several at once.
+pub trait CommandRunner {
+    /// Starts running the command of `edge`. A `console` command shares
+    /// the terminal of the build rather than having its output collected.
+    fn start(
+        &mut self,
+        edge: arena::Id<Edge>,
+        command: &blob::View,
+        console: bool,
+    ) -> io::Result<()>;
+
+    /// Waits for one of the started commands to finish.
+    fn wait(&mut self) -> io::Result<Finished>;
+}
+
+/// Runs commands with `/bin/sh -c`. Unless they are console commands,
+/// their stdin is closed and their stdout and stderr are collected
+/// together.
+pub struct ProcessRunner {
+    sender: mpsc::Sender<Finished>,
+    receiver: mpsc::Receiver<Finished>,
+    running: usize,
+    env: Vec<(String, String)>,
+}
+
+impl ProcessRunner {
+    pub fn new() -> ProcessRunner {
+        let (sender, receiver) = mpsc::channel();
+        ProcessRunner {
+            sender,
+            receiver,
+            running: 0,
+            env: vec![],
+        }
+    }
+
+    /// Sets an environment variable for the commands started afterwards,
+    /// such as `MAKEFLAGS` to share a [`jobserver::Server`].
+    pub fn set_env(&mut self, name: &str, value: &str) {
+        self.env.push((name.to_string(), value.to_string()));
+    }
+}
+
+impl Default for ProcessRunner {
+    fn default() -> ProcessRunner {
+        ProcessRunner::new()
+    }
+}
+
+impl CommandRunner for ProcessRunner {
+    fn start(
+        &mut self,
+        edge: arena::Id<Edge>,
+        command: &blob::View,
+        console: bool,
+    ) -> io::Result<()> {
+        let mut shell = process::Command::new("/bin/sh");
+        shell.arg("-c").arg(blob::to_path(command));
+        shell.envs(self.env.iter().map(|(name, value)| (name, value)));
+        let mut reader = None;
+        if !console {
+            let (output, writer) = io::pipe()?;
+            shell
+                .stdin(process::Stdio::null())
+                .stdout(writer.try_clone()?)
+                .stderr(writer);
+            reader = Some(output);
+        }
+        let child = shell.spawn();
+        // Close this process's copies of the pipe's write end, so that
+        // reading sees the end of the output once the command exits.
+        drop(shell);
+        let mut child = child?;
+        // Each command gets a thread to drain its output, so that none of
+        // them blocks on a full pipe while another is being waited for.
+        let sender = self.sender.clone();
+        std::thread::spawn(move || {
+            let mut output = vec![];
+            let mut success = true;
+            if let Some(Err(error)) = reader.map(|mut reader| reader.read_to_end(&mut output)) {
+                output.extend_from_slice(format!("reading output: {}\n", error).as_bytes());
+                success = false;
+            }
+            match child.wait() {
+                Ok(status) => success &= status.success(),
+                Err(error) => {
+                    output.extend_from_slice(format!("waiting: {}\n", error).as_bytes());
+                    success = false;
+                }
+            }
+            let output = Blob::from(output);
+            let _ = sender.send(Finished {
+                edge,
+                success,
+                output,
+            });
+        });
+        self.running += 1;
+        Ok(())
+    }
+
+    fn wait(&mut self) -> io::Result<Finished> {
+        if self.running == 0 {
+            return Err(io::Error::other("no commands are running"));
+        }
+        let finished = match self.receiver.recv() {
+            Ok(finished) => finished,
+            Err(_) => return Err(io::Error::other("command thread exited")),
+        };
+        self.running -= 1;
+        Ok(finished)
+    }
+}
+
+/// Told about the progress of a build.
+pub trait Status {
+    /// Called once, with the number of commands the build will run if
+    /// none of them fail.
+    fn planned(&mut self, commands: usize);
+
+    fn started(&mut self, edge: arena::Id<Edge>);
+
+    /// Called with the output of the command, after anything that
+    /// reported dependencies has been removed from it.
+    fn finished(&mut self, edge: arena::Id<Edge>, success: bool, output: &blob::View);
+}
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// How many commands may run at once, or 0 for no limit.
+    pub jobs: usize,
+    /// Whether to keep starting commands that do not depend on a failed
+    /// one, rather than stopping at the first failure.
+    pub keep_going: bool,
+    /// A load average at or above which no more commands start, unless
+    /// none are running.
+    pub max_load: Option<f64>,
+}
+
+impl Default for Options {
+    /// Like ninja, runs two more commands than there are processors.
+    fn default() -> Options {
+        let processors = match std::thread::available_parallelism() {
+            Ok(processors) => processors.get(),
+            Err(_) => 1,
+        };
+        Options {
+            jobs: processors + 2,
+            keep_going: false,
+            max_load: None,
+        }
+    }
+}
+
+/// How busy the machine running a build is.
+pub trait Load {
+    /// The load average over the last minute, or `None` if it cannot be
+    /// found.
+    fn average(&self) -> Option<f64>;
+}
+
+/// The load average that the kernel reports in `/proc/loadavg`, which
+/// only Linux has.
+pub struct SystemLoad;
+
+impl Load for SystemLoad {
+    fn average(&self) -> Option<f64> {
+        let loadavg = std::fs::read_to_string("/proc/loadavg").ok()?;
+        parse_loadavg(&loadavg)
+    }
+}
+
+fn parse_loadavg(loadavg: &str) -> Option<f64> {
+    loadavg.split_whitespace().next()?.parse().ok()
+}
+
+#[derive(Debug)]
+pub enum ExecError {
+    Start { path: Blob, error: io::Error },
+    Wait(io::Error),
+    Record { path: Blob, error: io::Error },
+    Jobserver(io::Error),
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecError::Start { path, error } => write!(
+                f,
+                "starting command for '{}': {}",
+                String::from_utf8_lossy(path),
+                error
+            ),
+            ExecError::Wait(error) => write!(f, "waiting for commands: {}", error),
+            ExecError::Jobserver(error) => write!(f, "jobserver: {}", error),
+            ExecError::Record { path, error } => {
+                write!(
+                    f,
+                    "recording '{}': {}",
+                    String::from_utf8_lossy(path),
+                    error
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ExecError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ExecError::Start { error, .. } => Some(error),
+            ExecError::Wait(error) => Some(error),
+            ExecError::Record { error, .. } => Some(error),
+            ExecError::Jobserver(error) => Some(error),
+        }
+    }
+}
+
+/// What happened to the commands a build planned to run.
+#[derive(Debug, Default)]
+pub struct Outcome {
+    succeeded: usize,
+    failed: Vec<arena::Id<Edge>>,
+    unstarted: usize,
+}
+
+impl Outcome {
+    /// The number of commands that succeeded.
+    pub fn succeeded(&self) -> usize {
+        self.succeeded
+    }
+
+    /// The edges whose commands failed, in the order they finished.
+    pub fn failed(&self) -> &[arena::Id<Edge>] {
+        &self.failed
+    }
+
+    /// The number of commands that never ran, because the build stopped
+    /// or because something they depend on failed.
+    pub fn unstarted(&self) -> usize {
+        self.unstarted
+    }
+
+    /// Whether every planned command ran and succeeded.
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty() && self.unstarted == 0
+    }
+}
+
+/// The edges needed for some targets, and which of them can start.
+struct Plan {
+    /// Whether each edge is needed.
+    wanted: Vec<bool>,
+    /// Whether each needed edge has a command to run.
+    runs: Vec<bool>,
+    /// For each needed edge, how many of the needed edges producing its
+    /// inputs have not finished.
+    waiting: Vec<usize>,
+    /// The edges that can start. Those in a pool already hold one of its
+    /// slots.
+    ready: VecDeque<arena::Id<Edge>>,
+    /// For each pool, how many of its edges are ready or running.
+    scheduled: Vec<usize>,
+    /// For each pool, the edges that are waiting only for a slot, in the
+    /// order they became ready.
+    delayed: Vec<VecDeque<arena::Id<Edge>>>,
+    /// How many needed edges have commands to run.
+    commands: usize,
+}
+
+impl Plan {
+    /// Plans every edge that `targets` depend on. Edges that are clean
+    /// are still planned, so that the edges after them wait for any
+    /// dirty edges before them.
+    fn new(graph: &Graph, dirty: &Dirty, targets: &[arena::Id<Node>]) -> Plan {
+        let edges = graph.edges().count();
+        let pools = graph.pools().count();
+        let mut plan = Plan {
+            wanted: vec![false; edges],
+            runs: vec![false; edges],
+            waiting: vec![0; edges],
+            ready: VecDeque::new(),
+            scheduled: vec![0; pools],
+            delayed: vec![VecDeque::new(); pools],
+            commands: 0,
+        };
+        let mut wanted = vec![];
+        let mut stack = targets
+            .iter()
+            .filter_map(|target| graph.node(*target).in_edge())
+            .collect::<Vec<_>>();
+        while let Some(id) = stack.pop() {
+            if plan.wanted[id.index()] {
+                continue;
+            }
+            plan.wanted[id.index()] = true;
+            wanted.push(id);
+            for input in graph.edge(id).all_inputs() {
+                stack.extend(graph.node(input).in_edge());
+            }
+        }
+
+        wanted.sort_by_key(|id| id.index());
+        let mut ready = vec![];
+        for id in wanted {
+            let edge = graph.edge(id);
+            if !edge.is_phony() && dirty.is_dirty(id) {
+                plan.runs[id.index()] = true;
+                plan.commands += 1;
+            }
+            let producers = edge
+                .all_inputs()
+                .filter_map(|input| graph.node(input).in_edge())
+                .collect::<HashSet<_>>();
+            plan.waiting[id.index()] = producers.len();
+            if producers.is_empty() {
+                ready.push(id);
+            }
+        }
+        for id in ready {
+            plan.schedule(graph, id);
+        }
+        plan
+    }
+
+    /// Makes `id` ready, or delays it if its pool is full.
+    fn schedule(&mut self, graph: &Graph, id: arena::Id<Edge>) {
+        if let (true, Some(pool)) = (self.runs[id.index()], graph.edge(id).pool()) {
+            let depth = graph.pool(pool).depth();
+            if depth != 0 && self.scheduled[pool.index()] >= depth {
+                self.delayed[pool.index()].push_back(id);
+                return;
+            }
+            self.scheduled[pool.index()] += 1;
+        }
+        self.ready.push_back(id);
+    }
+
+    /// Marks `id` as finished, making ready the edges that were waiting
+    /// only for it.
+    fn finish(&mut self, graph: &Graph, id: arena::Id<Edge>) {
+        let consumers = graph
+            .edge(id)
+            .all_outputs()
+            .flat_map(|output| graph.node(output).out_edges().iter().cloned())
+            .filter(|consumer| self.wanted[consumer.index()])
+            .collect::<HashSet<_>>();
+        let mut consumers = consumers.into_iter().collect::<Vec<_>>();
+        consumers.sort_by_key(|consumer| consumer.index());
+        for consumer in consumers {
+            let waiting = &mut self.waiting[consumer.index()];
+            *waiting -= 1;
+            if *waiting == 0 {
+                self.schedule(graph, consumer);
+            }
+        }
+    }
+
+    /// Gives up the pool slot of `id`, whose command has stopped, to the
+    /// edge that has waited longest for it.
+    fn release(&mut self, graph: &Graph, id: arena::Id<Edge>) {
+        if let Some(pool) = graph.edge(id).pool() {
+            self.scheduled[pool.index()] -= 1;
+            if let Some(next) = self.delayed[pool.index()].pop_front() {
+                self.scheduled[pool.index()] += 1;
+                self.ready.push_back(next);
+            }
+        }
+    }
+}
+
+/// A status update, held back while a console command is running.
+enum Event {
+    Started(arena::Id<Edge>),
+    Finished(arena::Id<Edge>, bool, Blob),
+}
+
+/// Runs the commands needed to bring targets up to date.
+pub struct Builder<'a> {
+    graph: &'a Graph,
+    file_system: &'a dyn FileSystem,
+    runner: &'a mut dyn CommandRunner,
+    status: &'a mut dyn Status,
+    options: Options,
+    log: Option<&'a mut Log>,
+    deps_log: Option<&'a mut DepsLog>,
+    jobserver: Option<&'a mut jobserver::Client>,
+    load: &'a dyn Load,
+    /// The absolute path of the directory commands run in, which MSVC
+    /// includes are made relative to.
+    build_dir: Blob,
+}
+
+impl<'a> Builder<'a> {
+    pub fn new(
+        graph: &'a Graph,
+        file_system: &'a dyn FileSystem,
+        runner: &'a mut dyn CommandRunner,
+        status: &'a mut dyn Status,
+        options: Options,
+    ) -> Builder<'a> {
+        Builder {
+            graph,
+            file_system,
+            runner,
+            status,
+            options,
+            log: None,
+            deps_log: None,
+            jobserver: None,
+            load: &SystemLoad,
+            build_dir: match std::env::current_dir() {
+                Ok(dir) => blob::from_path(&dir),
+                Err(_) => Blob::empty(),
+            },
+        }
+    }
+
+    /// Records the outputs of successful commands in `log`.
+    pub fn set_log(&mut self, log: &'a mut Log) {
+        self.log = Some(log);
+    }
+
+    /// Records the dependencies that commands of edges with `deps`
+    /// report in `deps_log`.
+    pub fn set_deps_log(&mut self, deps_log: &'a mut DepsLog) {
+        self.deps_log = Some(deps_log);
+    }
+
+    /// Takes a token from `jobserver` for each command beyond the first
+    /// that runs at once, so that the build shares its job slots with
+    /// the processes using the same jobserver. [`Options::jobs`] still
+    /// limits the commands that run.
+    pub fn set_jobserver(&mut self, jobserver: &'a mut jobserver::Client) {
+        self.jobserver = Some(jobserver);
+    }
+
+    /// Reads the load average that [`Options::max_load`] limits from
+    /// `load` rather than from the system.
+    pub fn set_load(&mut self, load: &'a dyn Load) {
+        self.load = load;
+    }
+
+    /// Runs the dirty edges that `targets` depend on. `dirty` must have
+    /// been computed for the same targets, which should include their
+    /// validations as given by [`Graph::with_validations`]. Failed
+    /// commands are reported in the outcome rather than as errors.
+    pub fn build(
+        &mut self,
+        dirty: &Dirty,
+        targets: &[arena::Id<Node>],
+    ) -> Result<Outcome, ExecError> {
+        let graph = self.graph;
+        let mut plan = Plan::new(graph, dirty, targets);
+        self.status.planned(plan.commands);
+
+        let began = Instant::now();
+        let mut starts = vec![0; plan.wanted.len()];
+        let mut outcome = Outcome::default();
+        let mut running = 0;
+        let mut stopping = false;
+        // The updates for other edges while a console command runs.
+        let mut held = None;
+        loop {
+            while !stopping && (self.options.jobs == 0 || running < self.options.jobs) {
+                let id = match plan.ready.pop_front() {
+                    Some(id) => id,
+                    None => break,
+                };
+                if !plan.runs[id.index()] {
+                    plan.finish(graph, id);
+                    continue;
+                }
+                if !self.can_start(running)? {
+                    plan.ready.push_front(id);
+                    break;
+                }
+                let edge = graph.edge(id);
+                let console = edge.pool() == Some(graph.console());
+                self.report(&mut held, Event::Started(id));
+                if console {
+                    held = Some(vec![]);
+                }
+                starts[id.index()] = millis(began);
+                if let Err(error) = self.runner.start(id, edge.command(), console) {
+                    let path = first_output(graph, edge);
+                    return Err(ExecError::Start { path, error });
+                }
+                running += 1;
+            }
+            if running == 0 {
+                break;
+            }
+
+            let finished = match self.runner.wait() {
+                Ok(finished) => finished,
+                Err(error) => return Err(ExecError::Wait(error)),
+            };
+            running -= 1;
+            self.release(running)?;
+            let id = finished.edge;
+            plan.release(graph, id);
+            let (success, output) = if finished.success {
+                self.record(id, finished.output, starts[id.index()], millis(began))?
+            } else {
+                (false, finished.output)
+            };
+            if graph.edge(id).pool() == Some(graph.console()) {
+                let held = held.take().unwrap_or_default();
+                self.status.finished(id, success, &output);
+                for event in held {
+                    self.report(&mut None, event);
+                }
+            } else {
+                self.report(&mut held, Event::Finished(id, success, output));
+            }
+            if success {
+                outcome.succeeded += 1;
+                plan.finish(graph, id);
+            } else {
+                outcome.failed.push(id);
+                stopping = !self.options.keep_going;
+            }
+        }
+        outcome.unstarted = plan.commands - outcome.succeeded - outcome.failed.len();
+        Ok(outcome)
+    }
+
+    /// Whether another command may start while `running` are, taking a
+    /// jobserver token for it if there is a jobserver. The first command
+    /// may always start, and needs no token.
+    fn can_start(&mut self, running: usize) -> Result<bool, ExecError> {
+        if running == 0 {
+            return Ok(true);
+        }
+        if let (Some(max_load), Some(load)) = (self.options.max_load, self.load.average()) {
+            if load >= max_load {
+                return Ok(false);
+            }
+        }
+        match &mut self.jobserver {
+            Some(jobserver) => match jobserver.try_acquire() {
+                Ok(acquired) => Ok(acquired),
+                Err(error) => Err(ExecError::Jobserver(error)),
+            },
+            _ => Ok(true),
+        }
+    }
+
+    /// Gives back a jobserver token if more are held than the `running`
+    /// commands need.
+    fn release(&mut self, running: usize) -> Result<(), ExecError> {
+        if let Some(jobserver) = &mut self.jobserver {
+            if jobserver.held() > running.saturating_sub(1) {
+                if let Err(error) = jobserver.release() {
+                    return Err(ExecError::Jobserver(error));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Passes `event` on to the status, or holds it back if a console
+    /// command is running.
+    fn report(&mut self, held: &mut Option<Vec<Event>>, event: Event) {
+        if let Some(held) = held {
+            held.push(event);
+            return;
+        }
+        match event {
+            Event::Started(id) => self.status.started(id),
+            Event::Finished(id, success, output) => self.status.finished(id, success, &output),
+        }
+    }
+
+    /// Records a successful command in the logs, returning whether it
+    /// still counts as a success and the output to show for it.
+    fn record(
+        &mut self,
+        id: arena::Id<Edge>,
+        output: Blob,
+        start: u32,
+        end: u32,
+    ) -> Result<(bool, Blob), ExecError> {
+        let graph = self.graph;
+        let edge = graph.edge(id);
+        let (output, inputs) = match self.discovered(edge, output) {
+            Ok(discovered) => discovered,
+            Err(output) => return Ok((false, output)),
+        };
+
+        let mut mtimes = vec![];
+        for node in edge.all_outputs() {
+            let path = graph.node(node).path();
+            match self.file_system.stat(path) {
+                Ok(mtime) => mtimes.push((path, mtime.unwrap_or(0))),
+                Err(error) => {
+                    let path = Blob::new(path);
+                    return Err(ExecError::Record { path, error });
+                }
+            }
+        }
+
+        if let Some(log) = &mut self.log {
+            let command_hash = log::hash_command(edge.command());
+            for (path, mtime) in mtimes.iter() {
+                let entry = log::Entry {
+                    output: Blob::new(path),
+                    command_hash,
+                    start,
+                    end,
+                    mtime: *mtime,
+                };
+                if let Err(error) = log.record(entry) {
+                    let path = Blob::new(path);
+                    return Err(ExecError::Record { path, error });
+                }
+            }
+        }
+
+        if let (Some(inputs), Some(deps_log), Some((path, mtime))) =
+            (inputs, &mut self.deps_log, mtimes.first())
+        {
+            let inputs = inputs.iter().map(|input| input.view()).collect::<Vec<_>>();
+            if let Err(error) = deps_log.record(path, *mtime, &inputs) {
+                let path = Blob::new(path);
+                return Err(ExecError::Record { path, error });
+            }
+        }
+        Ok((true, output))
+    }
+
+    /// Extracts the dependencies reported by the command of an edge with
+    /// `deps`. Returns the output to show and the canonical paths of the
+    /// dependencies, or, if they cannot be read, the output with an
+    /// explanation appended.
+    fn discovered(&self, edge: &Edge, output: Blob) -> Result<(Blob, Option<Vec<Blob>>), Blob> {
+        match edge.deps() {
+            None => Ok((output, None)),
+            Some(DepsType::Msvc) => {
+                let parsed = msvc::parse(&output, edge.msvc_deps_prefix(), &self.build_dir);
+                let inputs = parsed.includes().map(Blob::new).collect();
+                Ok((Blob::new(parsed.output()), Some(inputs)))
+            }
+            Some(DepsType::Gcc) => {
+                let path = match edge.depfile() {
+                    Some(path) => path,
+                    None => return Ok((output, Some(vec![]))),
+                };
+                let contents = match self.file_system.read(path) {
+                    Ok(contents) => contents,
+                    Err(error) if error.kind() == io::ErrorKind::NotFound => Blob::empty(),
+                    Err(error) => {
+                        let path = Blob::new(path);
+                        let error = depfile::DepfileError::ReadError { path, error };
+                        return Err(explain(output, &error));
+                    }
+                };
+                match depfile::parse(path, &contents) {
+                    Ok(parsed) => {
+                        let inputs = parsed
+                            .inputs()
+                            .iter()
+                            .map(|input| canon::canonicalize(input).0)
+                            .collect();
+                        Ok((output, Some(inputs)))
+                    }
+                    Err(error) => Err(explain(output, &error)),
+                }
+            }
+        }
+    }
+}
+
+/// Appends an explanation of why a command is treated as failed to its
+/// output.
+fn explain(output: Blob, error: &dyn fmt::Display) -> Blob {
+    let mut builder = blob::Builder::new();
+    builder.extend(&output);
+    if !output.is_empty() && !output.ends_with(b"\n") {
+        builder.push(b'\n');
+    }
+    builder.extend(format!("{}\n", error).as_bytes());
+    builder.blob()
+}
+
+fn first_output(graph: &Graph, edge: &Edge) -> Blob {
+    match edge.all_outputs().next() {
+        Some(output) => Blob::new(graph.node(output).path()),
+        None => Blob::empty(),
+    }
+}
+
+fn millis(began: Instant) -> u32 {
+    began.elapsed().as_millis() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dirty::{Discovered, History, MemoryFileSystem};
+    use crate::testing::graph;
+    use std::collections::HashMap;
+
+    /// Finishes commands in the order they started.
+    #[derive(Default)]
+    struct FakeRunner {
+        commands: HashMap<arena::Id<Edge>, Blob>,
+        running: VecDeque<arena::Id<Edge>>,
+        most_running: usize,
+        failing: Vec<&'static str>,
+        outputs: HashMap<&'static str, &'static str>,
+        console: Vec<String>,
+    }
+
+    impl CommandRunner for FakeRunner {
+        fn start(
+            &mut self,
+            edge: arena::Id<Edge>,
+            command: &blob::View,
+            console: bool,
+        ) -> io::Result<()> {
+            if console {
+                self.console
+                    .push(String::from_utf8_lossy(command).into_owned());
+            }
+            self.commands.insert(edge, Blob::new(command));
+            self.running.push_back(edge);
+            self.most_running = self.most_running.max(self.running.len());
+            Ok(())
+        }
+
+        fn wait(&mut self) -> io::Result<Finished> {
+            let edge = self.running.pop_front().expect("nothing is running");
+            let command = String::from_utf8_lossy(&self.commands[&edge]).into_owned();
+            let success = !self.failing.contains(&command.as_str());
+            let output = self.outputs.get(command.as_str()).unwrap_or(&"");
+            let output = Blob::new(output.as_bytes());
+            Ok(Finished {
+                edge,
+                success,
+                output,
+            })
+        }
+    }
+
+    struct Events<'a> {
+        graph: &'a Graph,
+        planned: usize,
+        events: Vec<String>,
+    }
+
+    impl<'a> Status for Events<'a> {
+        fn planned(&mut self, commands: usize) {
+            self.planned = commands;
+        }
+
+        fn started(&mut self, edge: arena::Id<Edge>) {
+            let command = String::from_utf8_lossy(self.graph.edge(edge).command());
+            self.events.push(format!("start {}", command));
+        }
+
+        fn finished(&mut self, edge: arena::Id<Edge>, success: bool, output: &blob::View) {
+            let command = String::from_utf8_lossy(self.graph.edge(edge).command());
+            let result = if success { "done" } else { "failed" };
+            self.events.push(format!("{} {}", result, command));
+            if !output.is_empty() {
+                self.events
+                    .push(format!("output {}", String::from_utf8_lossy(output)));
+            }
+        }
+    }
+
+    struct Run {
+        outcome: Outcome,
+        planned: usize,
+        events: Vec<String>,
+        most_running: usize,
+    }
+
+    fn run_with(
+        graph: &mut Graph,
+        file_system: &MemoryFileSystem,
+        runner: &mut FakeRunner,
+        options: Options,
+        log: Option<&mut Log>,
+        deps_log: Option<&mut DepsLog>,
+    ) -> Run {
+        let targets = graph.with_validations(&graph.roots());
+        let history = log.as_deref().map(|log| log as &dyn History);
+        let discovered = deps_log.as_deref().map(|log| log as &dyn Discovered);
+        let dirty = Dirty::compute(graph, file_system, history, discovered, &targets)
+            .expect("failed to compute dirty edges");
+        let graph = &*graph;
+        let mut status = Events {
+            graph,
+            planned: 0,
+            events: vec![],
+        };
+        let mut builder = Builder::new(graph, file_system, runner, &mut status, options);
+        if let Some(log) = log {
+            builder.set_log(log);
+        }
+        if let Some(deps_log) = deps_log {
+            builder.set_deps_log(deps_log);
+        }
+        let outcome = builder.build(&dirty, &targets).expect("failed to build");
+        Run {
+            outcome,
+            planned: status.planned,
+            events: status.events,
+            most_running: runner.most_running,
+        }
+    }
+
+    fn run(ninja: &str, runner: &mut FakeRunner, jobs: usize, keep_going: bool) -> Run {
+        let mut graph = graph(ninja);
+        let options = Options {
+            jobs,
+            keep_going,
+            max_load: None,
+        };
+        run_with(
+            &mut graph,
+            &MemoryFileSystem::new(),
+            runner,
+            options,
+            None,
+            None,
+        )
+    }
+
+    const CHAIN: &str = "rule r\n    command = $cmd\n\
+                         build a.o : r a.c\n    cmd = cc a\n\
+                         build b.o : r b.c\n    cmd = cc b\n\
+                         build app : r a.o b.o\n    cmd = ld app\n";
+
+    #[test]
+    fn dependency_order() {
+        let run = run(CHAIN, &mut FakeRunner::default(), 1, false);
+        assert_eq!(
+            run.events,
+            &[
+                "start cc a",
+                "done cc a",
+                "start cc b",
+                "done cc b",
+                "start ld app",
+                "done ld app"
+            ]
+        );
+        assert_eq!(run.planned, 3);
+        assert_eq!(run.outcome.succeeded(), 3);
+        assert!(run.outcome.is_success());
+    }
+
+    #[test]
+    fn parallel() {
+        let run = run(CHAIN, &mut FakeRunner::default(), 2, false);
+        assert_eq!(
+            run.events,
+            &[
+                "start cc a",
+                "start cc b",
+                "done cc a",
+                "done cc b",
+                "start ld app",
+                "done ld app"
+            ]
+        );
+        assert_eq!(run.most_running, 2);
+    }
+
+    #[test]
+    fn job_limit() {
+        let mut ninja = String::from("rule r\n    command = $cmd\n");
+        for index in 0..10 {
+            ninja.push_str(&format!("build out{} : r\n    cmd = c{}\n", index, index));
+        }
+        let run3 = run(&ninja, &mut FakeRunner::default(), 3, false);
+        assert_eq!(run3.most_running, 3);
+        assert_eq!(run3.outcome.succeeded(), 10);
+
+        let unlimited = run(&ninja, &mut FakeRunner::default(), 0, false);
+        assert_eq!(unlimited.most_running, 10);
+    }
+
+    #[test]
+    fn order_only_inputs() {
+        let ninja = "rule r\n    command = $cmd\n\
+                     build out : r in || gen.h\n    cmd = cc\n\
+                     build gen.h : r\n    cmd = gen\n";
+        let run = run(ninja, &mut FakeRunner::default(), 4, false);
+        assert_eq!(
+            run.events,
+            &["start gen", "done gen", "start cc", "done cc"]
+        );
+    }
+
+    #[test]
+    fn only_dirty_edges() {
+        let mut graph = graph(CHAIN);
+        let mut file_system = MemoryFileSystem::new();
+        for (path, mtime) in [("a.c", 1), ("b.c", 3), ("a.o", 2), ("b.o", 2), ("app", 4)] {
+            file_system.touch(path.as_bytes(), mtime);
+        }
+        let mut runner = FakeRunner::default();
+        let run = run_with(
+            &mut graph,
+            &file_system,
+            &mut runner,
+            Options::default(),
+            None,
+            None,
+        );
+        assert_eq!(run.planned, 2);
+        assert_eq!(
+            run.events,
+            &["start cc b", "done cc b", "start ld app", "done ld app"]
+        );
+    }
+
+    #[test]
+    fn clean_edges_still_wait() {
+        // `out` is up to date, but the order-only input of `last` waits
+        // for `gen.h` through it.
+        let ninja = "rule r\n    command = $cmd\n\
+                     build gen.h : r\n    cmd = gen\n\
+                     build out : r in || gen.h\n    cmd = cc\n\
+                     build last : r || out\n    cmd = last\n";
+        let mut graph = graph(ninja);
+        let mut file_system = MemoryFileSystem::new();
+        file_system.touch(b"in", 1);
+        file_system.touch(b"out", 2);
+        let mut runner = FakeRunner::default();
+        let options = Options {
+            jobs: 4,
+            keep_going: false,
+            max_load: None,
+        };
+        let run = run_with(&mut graph, &file_system, &mut runner, options, None, None);
+        assert_eq!(
+            run.events,
+            &["start gen", "done gen", "start last", "done last"]
+        );
+    }
+
+    #[test]
+    fn phony_edges() {
+        let ninja = "rule r\n    command = $cmd\n\
+                     build a : r\n    cmd = make a\n\
+                     build all : phony a\n\
+                     build after : r || all\n    cmd = after\n";
+        let run = run(ninja, &mut FakeRunner::default(), 1, false);
+        assert_eq!(run.planned, 2);
+        assert_eq!(
+            run.events,
+            &["start make a", "done make a", "start after", "done after"]
+        );
+    }
+
+    #[test]
+    fn validations() {
+        let ninja = "rule r\n    command = $cmd\n\
+                     build out : r in |@ check\n    cmd = build\n\
+                     build check : r in\n    cmd = lint\n";
+        let run = run(ninja, &mut FakeRunner::default(), 1, false);
+        assert_eq!(run.planned, 2);
+        assert_eq!(run.outcome.succeeded(), 2);
+    }
+
+    #[test]
+    fn pool_depth() {
+        let mut ninja = String::from(
+            "pool link\n    depth = 2\n\
+             rule r\n    command = $cmd\n\
+             rule ld\n    command = $cmd\n    pool = link\n",
+        );
+        for index in 0..5 {
+            ninja.push_str(&format!("build out{} : ld\n    cmd = ld{}\n", index, index));
+        }
+        let run = run(&ninja, &mut FakeRunner::default(), 10, false);
+        assert_eq!(run.most_running, 2);
+        assert_eq!(run.outcome.succeeded(), 5);
+    }
+
+    #[test]
+    fn pool_order() {
+        let ninja = "pool one\n    depth = 1\n\
+                     rule r\n    command = $cmd\n\
+                     build a : r\n    cmd = a\n    pool = one\n\
+                     build b : r\n    cmd = b\n    pool = one\n\
+                     build c : r\n    cmd = c\n\
+                     build d : r\n    cmd = d\n    pool = one\n";
+        let run = run(ninja, &mut FakeRunner::default(), 4, false);
+        assert_eq!(
+            run.events,
+            &["start a", "start c", "done a", "start b", "done c", "done b", "start d", "done d"]
+        );
+    }
+
+    #[test]
+    fn unlimited_pool() {
+        let mut ninja = String::from("pool any\n    depth = 0\nrule r\n    command = $cmd\n");
+        for index in 0..4 {
+            ninja.push_str(&format!("build out{} : r\n    cmd = c{}\n", index, index));
+            ninja.push_str("    pool = any\n");
+        }
+        let run = run(&ninja, &mut FakeRunner::default(), 0, false);
+        assert_eq!(run.most_running, 4);
+    }
+
+    #[test]
+    fn failures_release_pool_slots() {
+        let ninja = "pool one\n    depth = 1\n\
+                     rule r\n    command = $cmd\n    pool = one\n\
+                     build a : r\n    cmd = a\n\
+                     build b : r\n    cmd = b\n";
+        let mut runner = FakeRunner {
+            failing: vec!["a"],
+            ..FakeRunner::default()
+        };
+        let run = run(ninja, &mut runner, 2, true);
+        assert_eq!(run.events, &["start a", "failed a", "start b", "done b"]);
+    }
+
+    #[test]
+    fn console_pool() {
+        let ninja = "rule r\n    command = $cmd\n\
+                     build a : r\n    cmd = a\n\
+                     build test : r\n    cmd = test\n    pool = console\n\
+                     build b : r\n    cmd = b\n\
+                     build c : r\n    cmd = c\n    pool = console\n";
+        let mut runner = FakeRunner::default();
+        let run = run(ninja, &mut runner, 4, false);
+        assert_eq!(runner.console, &["test", "c"]);
+        // Only one console command runs at a time, and nothing else is
+        // reported while it does.
+        assert_eq!(
+            run.events,
+            &[
+                "start a",
+                "start test",
+                "done test",
+                "start b",
+                "done a",
+                "start c",
+                "done c",
+                "done b"
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn jobserver_slots() {
+        let mut ninja = String::from("rule r\n    command = $cmd\n");
+        for index in 0..6 {
+            ninja.push_str(&format!("build out{} : r\n    cmd = c{}\n", index, index));
+        }
+        let mut graph = graph(&ninja);
+        let targets = graph.roots();
+        let file_system = MemoryFileSystem::new();
+        let dirty = Dirty::compute(&mut graph, &file_system, None, None, &targets).unwrap();
+
+        let path = std::env::temp_dir().join(format!("ninja-rs-exec-{}", std::process::id()));
+        let server = jobserver::Server::new(&path, 3).expect("failed to start jobserver");
+        let mut client = server.client().expect("failed to connect");
+        // Another process is using one of the three slots.
+        let mut other = server.client().expect("failed to connect");
+        assert!(other.try_acquire().unwrap());
+
+        let mut runner = FakeRunner::default();
+        let mut status = Events {
+            graph: &graph,
+            planned: 0,
+            events: vec![],
+        };
+        let options = Options {
+            jobs: 0,
+            keep_going: false,
+            max_load: None,
+        };
+        let mut builder = Builder::new(&graph, &file_system, &mut runner, &mut status, options);
+        builder.set_jobserver(&mut client);
+        let outcome = builder.build(&dirty, &targets).expect("failed to build");
+        assert_eq!(outcome.succeeded(), 6);
+        assert_eq!(runner.most_running, 2);
+        assert_eq!(client.held(), 0);
+
+        // Both tokens are free again.
+        drop(other);
+        assert!(client.try_acquire().unwrap());
+        assert!(client.try_acquire().unwrap());
+        assert!(!client.try_acquire().unwrap());
+    }
+
+    struct FakeLoad(Option<f64>);
+
+    impl Load for FakeLoad {
+        fn average(&self) -> Option<f64> {
+            self.0
+        }
+    }
+
+    fn run_loaded(load: Option<f64>, max_load: Option<f64>) -> usize {
+        let mut ninja = String::from("rule r\n    command = $cmd\n");
+        for index in 0..4 {
+            ninja.push_str(&format!("build out{} : r\n    cmd = c{}\n", index, index));
+        }
+        let mut graph = graph(&ninja);
+        let targets = graph.roots();
+        let file_system = MemoryFileSystem::new();
+        let dirty = Dirty::compute(&mut graph, &file_system, None, None, &targets).unwrap();
+        let mut runner = FakeRunner::default();
+        let mut status = Events {
+            graph: &graph,
+            planned: 0,
+            events: vec![],
+        };
+        let options = Options {
+            jobs: 4,
+            keep_going: false,
+            max_load,
+        };
+        let load = FakeLoad(load);
+        let mut builder = Builder::new(&graph, &file_system, &mut runner, &mut status, options);
+        builder.set_load(&load);
+        let outcome = builder.build(&dirty, &targets).expect("failed to build");
+        assert_eq!(outcome.succeeded(), 4);
+        runner.most_running
+    }
+
+    #[test]
+    fn max_load() {
+        // An overloaded machine still runs one command at a time.
+        assert_eq!(run_loaded(Some(6.5), Some(4.0)), 1);
+        assert_eq!(run_loaded(Some(4.0), Some(4.0)), 1);
+        assert_eq!(run_loaded(Some(3.9), Some(4.0)), 4);
+        assert_eq!(run_loaded(Some(6.5), None), 4);
+        assert_eq!(run_loaded(None, Some(4.0)), 4);
+    }
+
+    #[test]
+    fn loadavg() {
+        assert_eq!(parse_loadavg("0.52 0.58 0.59 1/467 12345\n"), Some(0.52));
+        assert_eq!(parse_loadavg("12.00 8.25 4.10 9/1024 99\n"), Some(12.0));
+        assert_eq!(parse_loadavg(""), None);
+        assert_eq!(parse_loadavg("busy"), None);
+        if cfg!(target_os = "linux") {
+            assert!(SystemLoad.average().is_some());
+        }
+    }
+
+    #[test]
+    fn stops_at_failure() {
+        let mut runner = FakeRunner {
+            failing: vec!["cc a"],
+            outputs: HashMap::from([("cc a", "a.c:1: error\n")]),
+            ..FakeRunner::default()
+        };
+        let run = run(CHAIN, &mut runner, 1, false);
+        assert_eq!(
+            run.events,
+            &["start cc a", "failed cc a", "output a.c:1: error\n"]
+        );
+        assert_eq!(run.outcome.failed().len(), 1);
+        assert_eq!(run.outcome.unstarted(), 2);
+        assert!(!run.outcome.is_success());
+    }
+
+    #[test]
+    fn finishes_running_commands_after_failure() {
+        let mut runner = FakeRunner {
+            failing: vec!["cc a"],
+            ..FakeRunner::default()
+        };
+        let run = run(CHAIN, &mut runner, 2, false);
+        assert_eq!(
+            run.events,
+            &["start cc a", "start cc b", "failed cc a", "done cc b"]
+        );
+        assert_eq!(run.outcome.succeeded(), 1);
+        assert_eq!(run.outcome.unstarted(), 1);
+    }
+
+    #[test]
+    fn keep_going() {
+        let ninja = "rule r\n    command = $cmd\n\
+                     build a.o : r a.c\n    cmd = cc a\n\
+                     build b.o : r b.c\n    cmd = cc b\n\
+                     build a : r a.o\n    cmd = ld a\n\
+                     build b : r b.o\n    cmd = ld b\n";
+        let mut runner = FakeRunner {
+            failing: vec!["cc a"],
+            ..FakeRunner::default()
+        };
+        let run = run(ninja, &mut runner, 1, true);
+        assert_eq!(
+            run.events,
+            &[
+                "start cc a",
+                "failed cc a",
+                "start cc b",
+                "done cc b",
+                "start ld b",
+                "done ld b"
+            ]
+        );
+        assert_eq!(run.outcome.succeeded(), 2);
+        assert_eq!(run.outcome.unstarted(), 1);
+    }
+
+    #[test]
+    fn records_log() {
+        let mut graph = graph(CHAIN);
+        let mut file_system = MemoryFileSystem::new();
+        file_system.touch(b"a.o", 5);
+        let mut log = Log::new();
+        let mut runner = FakeRunner::default();
+        let options = Options::default();
+        run_with(
+            &mut graph,
+            &file_system,
+            &mut runner,
+            options,
+            Some(&mut log),
+            None,
+        );
+
+        let entry = log.lookup(b"a.o").expect("a.o not logged");
+        assert_eq!(entry.command_hash, log::hash_command(b"cc a"));
+        assert_eq!(entry.mtime, 5);
+        assert_eq!(log.lookup(b"b.o").expect("b.o not logged").mtime, 0);
+        assert!(!log.command_changed(b"app", b"ld app"));
+    }
+
+    #[test]
+    fn records_gcc_deps() {
+        let ninja = "rule cc\n    command = cc $in\n    deps = gcc\n    depfile = $out.d\n\
+                     build foo.o : cc foo.c\n";
+        let mut graph = graph(ninja);
+        let mut file_system = MemoryFileSystem::new();
+        file_system.touch(b"foo.o", 9);
+        file_system.write(b"foo.o.d", 9, b"foo.o: foo.c ./include/foo.h \\\n  bar.h\n");
+        let mut deps_log = DepsLog::new();
+        let mut runner = FakeRunner::default();
+        let options = Options::default();
+        let run = run_with(
+            &mut graph,
+            &file_system,
+            &mut runner,
+            options,
+            None,
+            Some(&mut deps_log),
+        );
+        assert!(run.outcome.is_success());
+
+        let deps = deps_log.lookup(b"foo.o").expect("no deps");
+        assert_eq!(deps.mtime(), 9);
+        let inputs = deps.inputs().collect::<Vec<_>>();
+        assert_eq!(inputs, &[&b"foo.c"[..], b"include/foo.h", b"bar.h"]);
+    }
+
+    #[test]
+    fn builds_deps_log_inputs() {
+        let ninja = "rule gen\n    command = gen $in $out\n\
+                     build gen.h : gen gen.in\n\
+                     rule cc\n    command = cc $in\n    deps = gcc\n    depfile = $out.d\n\
+                     build a.o : cc a.c || gen.h\n";
+        let mut graph = graph(ninja);
+        let a_o = graph.lookup(b"a.o").expect("no a.o");
+        let mut file_system = MemoryFileSystem::new();
+        file_system.touch(b"gen.in", 3);
+        file_system.touch(b"gen.h", 2);
+        file_system.touch(b"a.c", 1);
+        file_system.touch(b"a.o", 2);
+        let mut deps_log = DepsLog::new();
+        deps_log.record(b"a.o", 2, &[b"a.c", b"gen.h"]).unwrap();
+
+        let dirty = Dirty::compute(&mut graph, &file_system, None, Some(&deps_log), &[a_o])
+            .expect("failed to compute dirty edges");
+        let mut runner = FakeRunner::default();
+        let mut status = Events {
+            graph: &graph,
+            planned: 0,
+            events: vec![],
+        };
+        let options = Options::default();
+        let mut builder = Builder::new(&graph, &file_system, &mut runner, &mut status, options);
+        let outcome = builder.build(&dirty, &[a_o]).expect("failed to build");
+        assert_eq!(outcome.succeeded(), 2);
+        assert_eq!(
+            status.events,
+            &[
+                "start gen gen.in gen.h",
+                "done gen gen.in gen.h",
+                "start cc a.c",
+                "done cc a.c"
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_depfile_fails() {
+        let ninja = "rule cc\n    command = cc $in\n    deps = gcc\n    depfile = $out.d\n\
+                     build foo.o : cc foo.c\n";
+        let mut graph = graph(ninja);
+        let mut file_system = MemoryFileSystem::new();
+        file_system.write(b"foo.o.d", 1, b"foo.o foo.c\n");
+        let mut runner = FakeRunner::default();
+        let options = Options::default();
+        let run = run_with(&mut graph, &file_system, &mut runner, options, None, None);
+        assert_eq!(run.outcome.failed().len(), 1);
+        assert_eq!(run.events[1], "failed cc foo.c");
+        assert!(run.events[2].contains("expected ':' in depfile"));
+    }
+
+    #[test]
+    fn records_msvc_deps() {
+        let ninja = "rule cl\n    command = cl $in\n    deps = msvc\n\
+                     build foo.obj : cl foo.cc\n";
+        let mut graph = graph(ninja);
+        let mut runner = FakeRunner {
+            outputs: HashMap::from([(
+                "cl foo.cc",
+                "foo.cc\r\nNote: including file: foo.h\r\nfoo.cc(3): warning C4101\r\n",
+            )]),
+            ..FakeRunner::default()
+        };
+        let mut deps_log = DepsLog::new();
+        let file_system = MemoryFileSystem::new();
+        let options = Options::default();
+        let run = run_with(
+            &mut graph,
+            &file_system,
+            &mut runner,
+            options,
+            None,
+            Some(&mut deps_log),
+        );
+        assert_eq!(run.events[2], "output foo.cc(3): warning C4101\n");
+
+        let deps = deps_log.lookup(b"foo.obj").expect("no deps");
+        assert_eq!(deps.inputs().collect::<Vec<_>>(), &[&b"foo.h"[..]]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn process_runner() {
+        let graph = graph("rule r\n    command = $cmd\nbuild a : r\nbuild b : r\n");
+        let a = graph.node(graph.lookup(b"a").unwrap()).in_edge().unwrap();
+        let b = graph.node(graph.lookup(b"b").unwrap()).in_edge().unwrap();
+
+        let mut runner = ProcessRunner::new();
+        runner
+            .start(a, b"echo out; echo err >&2", false)
+            .expect("failed to start");
+        runner.start(b, b"exit 3", false).expect("failed to start");
+        let mut finished = [runner.wait().unwrap(), runner.wait().unwrap()];
+        finished.sort_by_key(|finished| finished.edge.index());
+
+        assert_eq!(finished[0].edge, a);
+        assert!(finished[0].success);
+        assert_eq!(finished[0].output.view(), b"out\nerr\n");
+        assert_eq!(finished[1].edge, b);
+        assert!(!finished[1].success);
+        assert!(finished[1].output.is_empty());
+        assert!(runner.wait().is_err());
+
+        runner.set_env("MAKEFLAGS", "-j2");
+        runner
+            .start(a, b"test \"$MAKEFLAGS\" = -j2", true)
+            .expect("failed to start");
+        let finished = runner.wait().unwrap();
+        assert!(finished.success);
+        assert!(finished.output.is_empty());
+    }
+}
@@ -0,0 +1,465 @@
+//! Pretty-printing of ninja files in a canonical style.
+//!
+//! Files are formatted from their [`cst`], so declarations stay in the
+//! order they were written, comments are kept, and paths and values are
+//! printed as written rather than evaluated. Files named by `include`
+//! and `subninja` are not read. Bindings are indented by two spaces,
+//! runs of blank lines become one, and long `build` and `default` lines
+//! are wrapped with `$` continuations.
+
+use crate::blob;
+use crate::blob::{Blob, Builder};
+use crate::cst;
+use crate::cst::{Element, NodeKind, TokenKind};
+use std::fmt;
+
+pub(crate) const INDENT: &blob::View = b"  ";
+const CONTINUATION_INDENT: &blob::View = b"    ";
+
+#[derive(Debug)]
+pub enum FmtError {
+    /// The line with this number does not parse.
+    Syntax(usize),
+}
+
+impl fmt::Display for FmtError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FmtError::Syntax(line) => write!(f, "syntax error on line {}", line),
+        }
+    }
+}
+
+impl std::error::Error for FmtError {}
+
+pub struct Options {
+    /// The column at which long lines are wrapped.
+    pub width: usize,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options { width: 80 }
+    }
+}
+
+/// Escapes `$`, `:` and spaces, so that `text` reads back as a single
+/// path.
+pub fn escape_path(text: &blob::View) -> Blob {
+    escape(text, true, true)
+}
+
+/// Escapes `$` and any leading spaces, so that `text` reads back as the
+/// same variable value.
+pub fn escape_value(text: &blob::View) -> Blob {
+    escape(text, false, true)
+}
+
+pub(crate) fn escape(text: &blob::View, path: bool, leading: bool) -> Blob {
+    let mut builder = Builder::new();
+    let mut leading = leading;
+    for b in text.iter() {
+        match *b {
+            b'$' => builder.extend(b"$$"),
+            b' ' if path || leading => builder.extend(b"$ "),
+            b':' if path => builder.extend(b"$:"),
+            b => builder.push(b),
+        }
+        leading = leading && *b == b' ';
+    }
+    builder.blob()
+}
+
+/// Writes `words` separated by spaces, breaking the line with a `$`
+/// continuation before any word that would take it past `width`.
+pub(crate) fn wrap(out: &mut Builder, words: &[Blob], width: usize) {
+    let mut len = 0;
+    for (index, word) in words.iter().enumerate() {
+        if index > 0 {
+            let last = index + 1 == words.len();
+            let end = len + 1 + word.len();
+            let fits = if last { end <= width } else { end + 2 <= width };
+            if fits {
+                out.push(b' ');
+                len += 1;
+            } else {
+                out.extend(b" $\n");
+                out.extend(CONTINUATION_INDENT);
+                len = CONTINUATION_INDENT.len();
+            }
+        }
+        out.extend(word);
+        len += word.len();
+    }
+    out.push(b'\n');
+}
+
+pub(crate) fn is_bare_identifier(b: u8) -> bool {
+    matches!(b, b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'-')
+}
+
+/// The text of a path or value without its continuations, using
+/// `${name}` where joining its lines would make `$name` ambiguous.
+fn word(node: &cst::Node) -> Blob {
+    let tokens = node
+        .tokens()
+        .into_iter()
+        .filter(|token| {
+            matches!(
+                token.kind(),
+                TokenKind::Text | TokenKind::Escape | TokenKind::Variable
+            )
+        })
+        .collect::<Vec<_>>();
+    let mut builder = Builder::new();
+    for (index, token) in tokens.iter().enumerate() {
+        let text = token.text();
+        let next = tokens.get(index + 1).and_then(|next| next.text().first());
+        let ambiguous = matches!(next, Some(b) if is_bare_identifier(*b));
+        if token.kind() == TokenKind::Variable && !text.starts_with(b"${") && ambiguous {
+            builder.extend(b"${");
+            builder.extend(&text[1..]);
+            builder.extend(b"}");
+        } else {
+            builder.extend(text);
+        }
+    }
+    builder.blob()
+}
+
+/// Formats the ninja file `input`, which must parse, though the files
+/// it includes need not exist.
+pub fn format(input: &blob::View, options: &Options) -> Result<Blob, FmtError> {
+    let file = cst::parse(input);
+    let mut line = 1;
+    for token in file.tokens() {
+        if token.kind() == TokenKind::Error {
+            return Err(FmtError::Syntax(line));
+        }
+        line += token.text().iter().filter(|b| **b == b'\n').count();
+    }
+
+    let mut printer = Printer {
+        width: options.width,
+        out: Builder::new(),
+        started: false,
+        blank: false,
+    };
+    // Whether the line being read is a comment, so that its line ending
+    // is not taken for a blank line.
+    let mut comment = false;
+    for child in file.children() {
+        match child {
+            Element::Node(node) => printer.node(node),
+            Element::Token(token) => match token.kind() {
+                TokenKind::Comment => {
+                    printer.comment(b"", token.text());
+                    comment = true;
+                }
+                TokenKind::Newline if comment => comment = false,
+                TokenKind::Newline => printer.blank = printer.started,
+                _ => (),
+            },
+        }
+    }
+    Ok(printer.out.blob())
+}
+
+struct Printer {
+    width: usize,
+    out: Builder,
+    /// Whether anything has been written yet, and whether a blank line
+    /// is due before the next line.
+    started: bool,
+    blank: bool,
+}
+
+impl Printer {
+    fn line(&mut self, indent: &blob::View) {
+        if self.blank {
+            self.out.push(b'\n');
+            self.blank = false;
+        }
+        self.started = true;
+        self.out.extend(indent);
+    }
+
+    fn comment(&mut self, indent: &blob::View, text: &blob::View) {
+        self.line(indent);
+        let end = text
+            .iter()
+            .rposition(|b| !b.is_ascii_whitespace())
+            .map_or(0, |last| last + 1);
+        self.out.extend(&text[..end]);
+        self.out.push(b'\n');
+    }
+
+    fn node(&mut self, node: &cst::Node) {
+        if node.kind() != NodeKind::Binding {
+            return self.declaration(node);
+        }
+        // An indented binding outside any declaration stays indented, so
+        // that the parser still rejects it.
+        let indented = match node.children().first() {
+            Some(Element::Token(token)) => token.kind() == TokenKind::Indent,
+            _ => false,
+        };
+        self.binding(if indented { INDENT } else { b"" }, node);
+    }
+
+    fn binding(&mut self, indent: &blob::View, node: &cst::Node) {
+        self.line(indent);
+        let mut equal = false;
+        let mut value = None;
+        for child in node.children() {
+            match child {
+                Element::Node(node) => value = Some(word(node)),
+                Element::Token(token) => match token.kind() {
+                    TokenKind::Identifier => self.out.extend(token.text()),
+                    TokenKind::Equal => equal = true,
+                    _ => (),
+                },
+            }
+        }
+        if equal {
+            self.out.extend(b" =");
+        }
+        if let Some(value) = value {
+            self.out.push(b' ');
+            self.out.extend(&value);
+        }
+        self.out.push(b'\n');
+    }
+
+    /// Writes the first line of a declaration, wrapped, followed by its
+    /// bindings and the comments between them.
+    fn declaration(&mut self, node: &cst::Node) {
+        self.line(b"");
+        let mut words: Vec<Blob> = vec![];
+        let mut header = true;
+        for child in node.children() {
+            match child {
+                Element::Node(child) if header => words.push(word(child)),
+                Element::Node(child) => self.binding(INDENT, child),
+                Element::Token(token) if header => match token.kind() {
+                    TokenKind::Keyword
+                    | TokenKind::Identifier
+                    | TokenKind::Pipe
+                    | TokenKind::PipePipe
+                    | TokenKind::PipeAt => words.push(Blob::new(token.text())),
+                    TokenKind::Colon if words.len() > 1 => {
+                        let last = words.len() - 1;
+                        let mut colon = words[last].to_vec();
+                        colon.push(b':');
+                        words[last] = Blob::from(colon);
+                    }
+                    TokenKind::Colon => words.push(Blob::new(b":")),
+                    TokenKind::Newline => {
+                        header = false;
+                        wrap(&mut self.out, &words, self.width);
+                    }
+                    _ => (),
+                },
+                Element::Token(token) if token.kind() == TokenKind::Comment => {
+                    self.comment(INDENT, token.text())
+                }
+                Element::Token(_) => (),
+            }
+        }
+        if header {
+            wrap(&mut self.out, &words, self.width);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast;
+    use crate::intern;
+    use crate::lex;
+    use crate::parse::{MemoryLoader, Parser};
+
+    fn parse(ninja: &blob::View, arena: &mut intern::Table) -> ast::File {
+        let mut loader = MemoryLoader::new();
+        loader.insert(b"rules.ninja", b"rule ld\n    command = ld\n");
+        loader.insert(b"sub.ninja", b"rule cc\n    command = cc\n");
+        let mut parser = Parser::new(b"build.ninja", ninja);
+        parser.parse(arena, &mut loader).expect("failed to parse")
+    }
+
+    fn format_with(ninja: &blob::View, width: usize) -> Blob {
+        format(ninja, &Options { width }).expect("failed to format")
+    }
+
+    fn format_str(ninja: &blob::View) -> String {
+        let formatted = format_with(ninja, 80);
+        String::from_utf8(formatted.to_vec()).expect("formatted invalid utf8")
+    }
+
+    fn write_parts(arena: &intern::Table, parts: &[lex::ValuePart]) -> String {
+        parts
+            .iter()
+            .map(|part| match part {
+                lex::ValuePart::Text(text) => String::from_utf8_lossy(text).into_owned(),
+                lex::ValuePart::Variable(variable) => {
+                    format!("${{{}}}", String::from_utf8_lossy(variable.name(arena)))
+                }
+            })
+            .collect()
+    }
+
+    /// Evaluates everything in a file, for comparing two files.
+    fn describe(arena: &intern::Table, file: &ast::File) -> Vec<String> {
+        let scopes = file.scopes();
+        let show = |blob: &blob::View| String::from_utf8_lossy(blob).into_owned();
+        let scope = |id| {
+            let mut bindings = scopes
+                .get_scope(id)
+                .bindings()
+                .map(|(name, value)| format!("{}={}", show(name.name(arena)), show(value)))
+                .collect::<Vec<_>>();
+            bindings.sort();
+            bindings.join(",")
+        };
+        let targets = |targets: &[ast::Target], id| {
+            targets
+                .iter()
+                .map(|target| show(&target.evaluate(scopes, id)))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+
+        let mut description = vec![scope(scopes.top())];
+        for declaration in file.declarations().iter() {
+            description.push(match declaration {
+                ast::Declaration::Rule(rule) => {
+                    let bindings = ast::ReservedBinding::all()
+                        .filter_map(|key| {
+                            let value = rule.reserved(key)?;
+                            let value = write_parts(arena, value.parts());
+                            Some(format!("{}={}", show(key.name()), value))
+                        })
+                        .collect::<Vec<_>>();
+                    format!(
+                        "rule {} {}",
+                        show(rule.name().name(arena)),
+                        bindings.join(",")
+                    )
+                }
+                ast::Declaration::Pool(pool) => {
+                    format!("pool {} {}", show(pool.name().name(arena)), pool.depth())
+                }
+                ast::Declaration::Build(build) => {
+                    let id = build.scope();
+                    format!(
+                        "build {} | {} : {} {} | {} || {} |@ {} {}",
+                        targets(build.outputs(), id),
+                        targets(build.implicit_outputs(), id),
+                        show(build.rule().name(arena)),
+                        targets(build.inputs(), id),
+                        targets(build.implicit_inputs(), id),
+                        targets(build.order_inputs(), id),
+                        targets(build.validations(), id),
+                        scope(id),
+                    )
+                }
+                ast::Declaration::Default(default) => {
+                    format!("default {}", targets(default.targets(), default.scope()))
+                }
+            });
+        }
+        description
+    }
+
+    fn assert_round_trip(ninja: &blob::View, width: usize) {
+        let mut arena = intern::Table::new();
+        let file = parse(ninja, &mut arena);
+        let formatted = format_with(ninja, width);
+        let reparsed = parse(&formatted, &mut arena);
+        assert_eq!(describe(&arena, &file), describe(&arena, &reparsed));
+        assert_eq!(formatted, format_with(&formatted, width));
+    }
+
+    const NINJA: &[u8] = b"# Compiler flags.\n\
+        cflags = -O2   -Wall\nroot = .\n\n\n\
+        rule cc\n    description = CC $out\n    # Written by the compiler.\n    depfile = $out.d  \n\
+        \x20   command = cc ${cflags}x -c $in -o $out\n\
+        pool link\n    depth = 2\n\
+        build $root/a.o: cc a.c\nbuild b$ c.o | b.d : cc b$:c.c $$dollar || gen |@ check\n    pool = link\n    cflags = $$HOME  \n\
+        \n   # Everything.\n\n\
+        default a.o b$ c.o\ninclude rules.ninja\nsubninja sub.ninja\n\n";
+
+    #[test]
+    fn canonical() {
+        assert_eq!(
+            format_str(NINJA),
+            "# Compiler flags.\n\
+             cflags = -O2   -Wall\nroot = .\n\
+             \n\
+             rule cc\n  description = CC $out\n  # Written by the compiler.\n  depfile = $out.d  \n\
+             \x20 command = cc ${cflags}x -c $in -o $out\n\
+             pool link\n  depth = 2\n\
+             build $root/a.o: cc a.c\n\
+             build b$ c.o | b.d: cc b$:c.c $$dollar || gen |@ check\n  pool = link\n  cflags = $$HOME  \n\
+             \n\
+             # Everything.\n\
+             \n\
+             default a.o b$ c.o\ninclude rules.ninja\nsubninja sub.ninja\n"
+        );
+    }
+
+    #[test]
+    fn source_order() {
+        let ninja = b"zed = 1\nalpha = $zed x\nrule r\n  command = $alpha\n";
+        assert_eq!(format_str(ninja).as_bytes(), &ninja[..]);
+    }
+
+    #[test]
+    fn continuations() {
+        let ninja = b"x = a$\n    b\ny = $x$\n  z\nbuild o: r $\n    i\n";
+        assert_eq!(format_str(ninja), "x = ab\ny = ${x}z\nbuild o: r i\n");
+        assert_round_trip(ninja, 80);
+    }
+
+    #[test]
+    fn wraps_long_lines() {
+        let ninja = b"build output: phony input1 input2 input3 input4\n";
+        assert_eq!(
+            format_with(ninja, 30).view(),
+            &b"build output: phony input1 $\n    input2 input3 input4\n"[..]
+        );
+        assert_eq!(
+            format_with(ninja, 10).view(),
+            &b"build $\n    output: $\n    phony $\n    input1 $\n    input2 $\n    input3 $\n    input4\n"[..]
+        );
+        assert_round_trip(ninja, 10);
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(escape_path(b"a b:c$d").view(), b"a$ b$:c$$d");
+        assert_eq!(escape_value(b"  a b:c$d").view(), b"$ $ a b:c$$d");
+    }
+
+    #[test]
+    fn leading_space_value() {
+        let ninja = b"x = $ $ a\nrule r\n    command = $ echo\n";
+        assert_eq!(format_str(ninja), "x = $ $ a\nrule r\n  command = $ echo\n");
+        assert_round_trip(ninja, 80);
+    }
+
+    #[test]
+    fn round_trip() {
+        assert_round_trip(NINJA, 80);
+        assert_round_trip(NINJA, 20);
+    }
+
+    #[test]
+    fn syntax_error() {
+        match format(b"x = 1\n\nbuild a: r ${b\n", &Options::default()) {
+            Err(FmtError::Syntax(3)) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,847 @@
+use crate::arena;
+use crate::ast;
+use crate::blob;
+use crate::blob::Blob;
+use crate::canon;
+use crate::intern;
+use crate::lex;
+use std::fmt;
+
+pub use crate::ast::DepsType;
+
+#[derive(Debug)]
+pub enum GraphError {
+    AstError(ast::AstError),
+    UnknownRule { rule: Blob },
+    UnknownPool { pool: Blob },
+    UnknownTarget { target: Blob },
+    MultipleProducers { path: Blob },
+    MultipleDepsOutputs { path: Blob },
+}
+
+impl fmt::Display for GraphError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GraphError::AstError(error) => error.fmt(f),
+            GraphError::UnknownRule { rule } => {
+                write!(f, "unknown build rule '{}'", String::from_utf8_lossy(rule))
+            }
+            GraphError::UnknownPool { pool } => {
+                write!(f, "unknown pool name '{}'", String::from_utf8_lossy(pool))
+            }
+            GraphError::UnknownTarget { target } => {
+                write!(f, "unknown target '{}'", String::from_utf8_lossy(target))
+            }
+            GraphError::MultipleProducers { path } => {
+                write!(
+                    f,
+                    "multiple rules generate {}",
+                    String::from_utf8_lossy(path)
+                )
+            }
+            GraphError::MultipleDepsOutputs { path } => write!(
+                f,
+                "{}: multiple outputs aren't supported by the deps log",
+                String::from_utf8_lossy(path)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for GraphError {}
+
+/// A file in the build, identified by its canonical path.
+pub struct Node {
+    path: Blob,
+    slash_bits: u64,
+    in_edge: Option<arena::Id<Edge>>,
+    out_edges: Vec<arena::Id<Edge>>,
+    validation_out_edges: Vec<arena::Id<Edge>>,
+}
+
+impl Node {
+    fn new(path: Blob, slash_bits: u64) -> Node {
+        Node {
+            path,
+            slash_bits,
+            in_edge: None,
+            out_edges: vec![],
+            validation_out_edges: vec![],
+        }
+    }
+
+    pub fn path(&self) -> &blob::View {
+        &self.path
+    }
+
+    /// The path with the separators it was first spelled with, which
+    /// differ from `path` only on Windows.
+    pub fn path_decanonicalized(&self) -> Blob {
+        canon::decanonicalize(&self.path, self.slash_bits)
+    }
+
+    /// The edge that produces this node, if any.
+    pub fn in_edge(&self) -> Option<arena::Id<Edge>> {
+        self.in_edge
+    }
+
+    /// The edges that consume this node as an input of any kind.
+    pub fn out_edges(&self) -> &[arena::Id<Edge>] {
+        &self.out_edges
+    }
+
+    /// The edges that list this node as a validation.
+    pub fn validation_out_edges(&self) -> &[arena::Id<Edge>] {
+        &self.validation_out_edges
+    }
+}
+
+/// A single build statement, connecting its input nodes to its output
+/// nodes.
+pub struct Edge {
+    rule: lex::Identifier,
+    phony: bool,
+    pool: Option<arena::Id<Pool>>,
+    command: Blob,
+    restat: bool,
+    generator: bool,
+    deps: Option<DepsType>,
+    depfile: Option<Blob>,
+    msvc_deps_prefix: Option<Blob>,
+    outputs: Vec<arena::Id<Node>>,
+    implicit_outputs: Vec<arena::Id<Node>>,
+    inputs: Vec<arena::Id<Node>>,
+    implicit_inputs: Vec<arena::Id<Node>>,
+    order_inputs: Vec<arena::Id<Node>>,
+    validations: Vec<arena::Id<Node>>,
+}
+
+impl Edge {
+    pub fn rule(&self) -> lex::Identifier {
+        self.rule
+    }
+
+    /// Phony edges run no command. Their outputs are up to date
+    /// whenever their inputs are.
+    pub fn is_phony(&self) -> bool {
+        self.phony
+    }
+
+    pub fn pool(&self) -> Option<arena::Id<Pool>> {
+        self.pool
+    }
+
+    pub fn command(&self) -> &blob::View {
+        &self.command
+    }
+
+    /// Whether outputs that the command leaves untouched should be
+    /// treated as clean.
+    pub fn is_restat(&self) -> bool {
+        self.restat
+    }
+
+    /// Whether the edge regenerates the build file, so that changes to
+    /// its command line do not make it dirty.
+    pub fn is_generator(&self) -> bool {
+        self.generator
+    }
+
+    /// How the dependencies the command discovers are recorded, if they
+    /// are kept in the deps log.
+    pub fn deps(&self) -> Option<DepsType> {
+        self.deps
+    }
+
+    /// The Makefile-style file in which the command lists the inputs it
+    /// discovered.
+    pub fn depfile(&self) -> Option<&blob::View> {
+        self.depfile.as_ref().map(|depfile| depfile.view())
+    }
+
+    /// The prefix of the lines in which the MSVC compiler reports the
+    /// files it includes, when it is not the English default.
+    pub fn msvc_deps_prefix(&self) -> Option<&blob::View> {
+        self.msvc_deps_prefix.as_ref().map(|prefix| prefix.view())
+    }
+
+    pub fn outputs(&self) -> &[arena::Id<Node>] {
+        &self.outputs
+    }
+
+    pub fn implicit_outputs(&self) -> &[arena::Id<Node>] {
+        &self.implicit_outputs
+    }
+
+    /// Both explicit and implicit outputs.
+    pub fn all_outputs(&self) -> impl Iterator<Item = arena::Id<Node>> + '_ {
+        self.outputs
+            .iter()
+            .chain(self.implicit_outputs.iter())
+            .cloned()
+    }
+
+    pub fn inputs(&self) -> &[arena::Id<Node>] {
+        &self.inputs
+    }
+
+    pub fn implicit_inputs(&self) -> &[arena::Id<Node>] {
+        &self.implicit_inputs
+    }
+
+    pub fn order_inputs(&self) -> &[arena::Id<Node>] {
+        &self.order_inputs
+    }
+
+    /// Explicit, implicit, and order-only inputs, in that order.
+    pub fn all_inputs(&self) -> impl Iterator<Item = arena::Id<Node>> + '_ {
+        self.inputs
+            .iter()
+            .chain(self.implicit_inputs.iter())
+            .chain(self.order_inputs.iter())
+            .cloned()
+    }
+
+    /// Nodes built alongside this edge. They are not inputs, so the
+    /// edge neither waits for them nor becomes dirty when they change.
+    pub fn validations(&self) -> &[arena::Id<Node>] {
+        &self.validations
+    }
+}
+
+pub struct Pool {
+    name: lex::Identifier,
+    depth: usize,
+}
+
+impl Pool {
+    pub fn name(&self) -> lex::Identifier {
+        self.name
+    }
+
+    /// The maximum number of concurrent edges, or 0 for no limit.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+}
+
+/// Rule bindings that change how an edge is scheduled. The boolean ones
+/// are true when set to anything nonempty.
+#[derive(Default)]
+struct Settings {
+    restat: bool,
+    generator: bool,
+    deps: Option<DepsType>,
+    depfile: Option<Blob>,
+    msvc_deps_prefix: Option<Blob>,
+}
+
+pub struct Graph {
+    nodes: arena::Arena<Node>,
+    edges: arena::Arena<Edge>,
+    pools: arena::Arena<Pool>,
+    paths: std::collections::HashMap<Blob, arena::Id<Node>>,
+    defaults: Vec<arena::Id<Node>>,
+    phony: lex::Identifier,
+    console: arena::Id<Pool>,
+}
+
+impl Graph {
+    pub fn new(arena: &mut intern::Table, file: &ast::File) -> Result<Graph, GraphError> {
+        let mut pools = arena::Arena::new();
+        let console = lex::Identifier::new(arena, ast::CONSOLE);
+        let console = pools.insert(Pool {
+            name: console,
+            depth: 1,
+        });
+        let mut graph = Graph {
+            nodes: arena::Arena::new(),
+            edges: arena::Arena::new(),
+            pools,
+            paths: std::collections::HashMap::new(),
+            defaults: vec![],
+            phony: lex::Identifier::new(arena, ast::PHONY),
+            console,
+        };
+
+        let mut pools = std::collections::HashMap::new();
+        pools.insert(graph.pool(console).name, console);
+        for pool in file.pools() {
+            let name = pool.name();
+            let depth = pool.depth();
+            let id = graph.pools.insert(Pool { name, depth });
+            pools.insert(name, id);
+        }
+
+        let command = lex::Identifier::new(arena, b"command");
+        let pool = lex::Identifier::new(arena, b"pool");
+        let restat = lex::Identifier::new(arena, b"restat");
+        let generator = lex::Identifier::new(arena, b"generator");
+        let deps = lex::Identifier::new(arena, b"deps");
+        let depfile = lex::Identifier::new(arena, b"depfile");
+        let msvc_deps_prefix = lex::Identifier::new(arena, b"msvc_deps_prefix");
+        for build in file.builds() {
+            if build.rule() == graph.phony {
+                let settings = Settings::default();
+                graph.add_edge(file, build, None, Blob::empty(), settings)?;
+                continue;
+            }
+
+            let rule = match file.rule(build) {
+                Some(rule) => rule,
+                None => {
+                    let rule = Blob::new(build.rule().name(arena));
+                    return Err(GraphError::UnknownRule { rule });
+                }
+            };
+
+            // Settings the parser has already interpreted are only
+            // evaluated here if they refer to variables.
+            let env = ast::Env::new(arena, file.scopes(), build, rule);
+            let binding = |identifier| env.get(identifier).map_err(GraphError::AstError);
+            let pool = match env.literal(pool, rule.pool()) {
+                Some(name) => name.map(Blob::new),
+                None => Some(binding(pool)?).filter(|name| !name.is_empty()),
+            };
+            let pool = match pool {
+                Some(name) => {
+                    let identifier = lex::Identifier::new(arena, &name);
+                    match pools.get(&identifier) {
+                        Some(id) => Some(*id),
+                        None => return Err(GraphError::UnknownPool { pool: name }),
+                    }
+                }
+                None => None,
+            };
+            let command = binding(command)?;
+            let settings = Settings {
+                restat: match env.literal(restat, rule.restat()) {
+                    Some(restat) => restat,
+                    None => !binding(restat)?.is_empty(),
+                },
+                generator: match env.literal(generator, rule.generator()) {
+                    Some(generator) => generator,
+                    None => !binding(generator)?.is_empty(),
+                },
+                deps: match env.literal(deps, rule.deps()) {
+                    Some(deps) => deps,
+                    None => DepsType::parse(&binding(deps)?).map_err(GraphError::AstError)?,
+                },
+                depfile: Some(binding(depfile)?).filter(|depfile| !depfile.is_empty()),
+                msvc_deps_prefix: Some(binding(msvc_deps_prefix)?)
+                    .filter(|prefix| !prefix.is_empty()),
+            };
+
+            graph.add_edge(file, build, pool, command, settings)?;
+        }
+
+        for default in file.defaults() {
+            for target in default.targets() {
+                let path = target.evaluate(file.scopes(), default.scope());
+                match graph.lookup(&path) {
+                    Some(node) => graph.defaults.push(node),
+                    None => return Err(GraphError::UnknownTarget { target: path }),
+                }
+            }
+        }
+
+        Ok(graph)
+    }
+
+    fn add_edge(
+        &mut self,
+        file: &ast::File,
+        build: &ast::Build,
+        pool: Option<arena::Id<Pool>>,
+        command: Blob,
+        settings: Settings,
+    ) -> Result<(), GraphError> {
+        let phony = build.rule() == self.phony;
+        let mut nodes = |targets: &[ast::Target]| {
+            targets
+                .iter()
+                .map(|target| self.intern(target.evaluate(file.scopes(), build.scope())))
+                .collect::<Vec<_>>()
+        };
+        let edge = Edge {
+            rule: build.rule(),
+            phony,
+            pool,
+            command,
+            restat: settings.restat,
+            generator: settings.generator,
+            deps: settings.deps,
+            depfile: settings.depfile,
+            msvc_deps_prefix: settings.msvc_deps_prefix,
+            outputs: nodes(build.outputs()),
+            implicit_outputs: nodes(build.implicit_outputs()),
+            inputs: nodes(build.inputs()),
+            implicit_inputs: nodes(build.implicit_inputs()),
+            order_inputs: nodes(build.order_inputs()),
+            validations: nodes(build.validations()),
+        };
+
+        for output in edge.all_outputs() {
+            let node = self.nodes.get(output);
+            if node.in_edge.is_some() {
+                let path = node.path.clone();
+                return Err(GraphError::MultipleProducers { path });
+            }
+        }
+        if edge.deps.is_some() && edge.outputs.len() > 1 {
+            let path = self.nodes.get(edge.outputs[0]).path.clone();
+            return Err(GraphError::MultipleDepsOutputs { path });
+        }
+
+        let id = self.edges.insert(edge);
+        let edge = self.edges.get(id);
+        for output in edge.all_outputs() {
+            self.nodes.get_mut(output).in_edge = Some(id);
+        }
+        for input in edge.all_inputs() {
+            self.nodes.get_mut(input).out_edges.push(id);
+        }
+        for validation in edge.validations() {
+            self.nodes
+                .get_mut(*validation)
+                .validation_out_edges
+                .push(id);
+        }
+
+        Ok(())
+    }
+
+    fn intern(&mut self, path: Blob) -> arena::Id<Node> {
+        let (path, slash_bits) = canon::canonicalize(&path);
+        if let Some(id) = self.paths.get(&path) {
+            return *id;
+        }
+        let id = self.nodes.insert(Node::new(path.clone(), slash_bits));
+        self.paths.insert(path, id);
+        id
+    }
+
+    /// Adds inputs that `edge` was found to depend on, such as those
+    /// listed in its depfile, as implicit inputs. Paths that are already
+    /// explicit or implicit inputs are skipped, but order-only inputs
+    /// become implicit ones, since changes to them now matter.
+    pub fn add_implicit_inputs(&mut self, edge: arena::Id<Edge>, paths: &[Blob]) {
+        let existing = self.edges.get(edge);
+        let mut seen = existing
+            .inputs
+            .iter()
+            .chain(existing.implicit_inputs.iter())
+            .cloned()
+            .collect::<std::collections::HashSet<_>>();
+        let order_inputs = existing
+            .order_inputs
+            .iter()
+            .cloned()
+            .collect::<std::collections::HashSet<_>>();
+        let mut moved = std::collections::HashSet::new();
+        for path in paths {
+            let node = self.intern(path.clone());
+            if !seen.insert(node) {
+                continue;
+            }
+            self.edges.get_mut(edge).implicit_inputs.push(node);
+            if order_inputs.contains(&node) {
+                moved.insert(node);
+            } else {
+                self.nodes.get_mut(node).out_edges.push(edge);
+            }
+        }
+        if !moved.is_empty() {
+            let edge = self.edges.get_mut(edge);
+            edge.order_inputs.retain(|input| !moved.contains(input));
+        }
+    }
+
+    /// Finds the node for `path`, which need not be canonical.
+    pub fn lookup(&self, path: &blob::View) -> Option<arena::Id<Node>> {
+        let (path, _) = canon::canonicalize(path);
+        self.paths.get(&path).cloned()
+    }
+
+    pub fn node(&self, id: arena::Id<Node>) -> &Node {
+        self.nodes.get(id)
+    }
+
+    pub fn edge(&self, id: arena::Id<Edge>) -> &Edge {
+        self.edges.get(id)
+    }
+
+    pub fn pool(&self, id: arena::Id<Pool>) -> &Pool {
+        self.pools.get(id)
+    }
+
+    /// The built-in `console` pool.
+    pub fn console(&self) -> arena::Id<Pool> {
+        self.console
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = (arena::Id<Node>, &Node)> {
+        self.nodes.iter()
+    }
+
+    pub fn edges(&self) -> impl Iterator<Item = (arena::Id<Edge>, &Edge)> {
+        self.edges.iter()
+    }
+
+    /// Every pool, including `console`.
+    pub fn pools(&self) -> impl Iterator<Item = (arena::Id<Pool>, &Pool)> {
+        self.pools.iter()
+    }
+
+    /// The targets named by `default` declarations.
+    pub fn defaults(&self) -> &[arena::Id<Node>] {
+        &self.defaults
+    }
+
+    /// The nodes that are produced by an edge but not consumed by any,
+    /// which ninja builds when there are no defaults.
+    pub fn roots(&self) -> Vec<arena::Id<Node>> {
+        self.nodes
+            .iter()
+            .filter(|(_, node)| {
+                node.in_edge.is_some()
+                    && node.out_edges.is_empty()
+                    && node.validation_out_edges.is_empty()
+            })
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    /// The requested targets followed by the validations of every edge
+    /// needed to build them. Validations are scheduled as additional
+    /// targets, and their own dependencies may bring in further
+    /// validations.
+    pub fn with_validations(&self, targets: &[arena::Id<Node>]) -> Vec<arena::Id<Node>> {
+        let mut wanted = targets.to_vec();
+        let mut seen = targets
+            .iter()
+            .copied()
+            .collect::<std::collections::HashSet<_>>();
+        let mut visited = std::collections::HashSet::new();
+        let mut stack = targets.to_vec();
+        while let Some(node) = stack.pop() {
+            if !visited.insert(node) {
+                continue;
+            }
+            let edge = match self.nodes.get(node).in_edge {
+                Some(edge) => self.edges.get(edge),
+                None => continue,
+            };
+            for validation in edge.validations() {
+                if seen.insert(*validation) {
+                    wanted.push(*validation);
+                }
+                stack.push(*validation);
+            }
+            stack.extend(edge.all_inputs());
+        }
+        wanted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph(ninja: &blob::View) -> Result<(Graph, intern::Table), GraphError> {
+        let mut arena = intern::Table::new();
+        let file = crate::parse_bytes(&mut arena, ninja).expect("failed to parse");
+        let graph = Graph::new(&mut arena, &file)?;
+        Ok((graph, arena))
+    }
+
+    fn path(graph: &Graph, id: arena::Id<Node>) -> &blob::View {
+        graph.node(id).path()
+    }
+
+    #[test]
+    fn empty() {
+        let (graph, _) = graph(b"").expect("failed to build graph");
+        assert_eq!(graph.nodes().count(), 0);
+        assert_eq!(graph.edges().count(), 0);
+    }
+
+    #[test]
+    fn edges() {
+        let ninja = b"rule cc\n    command = cc $in -o $out\n\
+                      rule link\n    command = ld $in -o $out\n\
+                      build a.o | a.d : cc a.c | a.h || gen\n\
+                      build b.o : cc b.c | a.h\n\
+                      build app : link a.o b.o\n";
+        let (graph, arena) = graph(ninja).expect("failed to build graph");
+        assert_eq!(graph.edges().count(), 3);
+        assert_eq!(graph.nodes().count(), 8);
+
+        let a_o = graph.lookup(b"a.o").expect("no a.o");
+        let cc = graph.node(a_o).in_edge().expect("a.o has no producer");
+        let cc = graph.edge(cc);
+        assert_eq!(cc.rule().name(&arena), b"cc");
+        assert_eq!(cc.command(), b"cc a.c -o a.o");
+        assert_eq!(path(&graph, cc.implicit_outputs()[0]), b"a.d");
+        assert_eq!(path(&graph, cc.inputs()[0]), b"a.c");
+        assert_eq!(path(&graph, cc.implicit_inputs()[0]), b"a.h");
+        assert_eq!(path(&graph, cc.order_inputs()[0]), b"gen");
+
+        let a_h = graph.lookup(b"a.h").expect("no a.h");
+        assert!(graph.node(a_h).in_edge().is_none());
+        assert_eq!(graph.node(a_h).out_edges().len(), 2);
+
+        let app = graph.lookup(b"app").expect("no app");
+        let a_d = graph.lookup(b"a.d").expect("no a.d");
+        assert_eq!(graph.roots(), vec![a_d, app]);
+        let link = graph.edge(graph.node(app).in_edge().expect("app has no producer"));
+        assert_eq!(link.command(), b"ld a.o b.o -o app");
+        assert_eq!(graph.node(a_o).out_edges().len(), 1);
+    }
+
+    #[test]
+    fn pool() {
+        let ninja = b"pool link_pool\n    depth = 2\n\
+                      rule link\n    command = ld\n    pool = link_pool\n\
+                      build app : link a.o\n\
+                      build lib : link b.o\n    pool =\n";
+        let (graph, arena) = graph(ninja).expect("failed to build graph");
+        let app = graph.lookup(b"app").expect("no app");
+        let edge = graph.edge(graph.node(app).in_edge().expect("no producer"));
+        let pool = graph.pool(edge.pool().expect("no pool"));
+        assert_eq!(pool.name().name(&arena), b"link_pool");
+        assert_eq!(pool.depth(), 2);
+
+        let lib = graph.lookup(b"lib").expect("no lib");
+        let edge = graph.edge(graph.node(lib).in_edge().expect("no producer"));
+        assert!(edge.pool().is_none());
+    }
+
+    #[test]
+    fn flags() {
+        let ninja = b"rule gen\n    command = gen\n    generator = 1\n\
+                      rule touch\n    command = touch\n    restat = $changed\n\
+                      build build.ninja : gen\n\
+                      build a : touch\n    changed = yes\n\
+                      build b : touch\n";
+        let (graph, _) = graph(ninja).expect("failed to build graph");
+        let edge = |path: &blob::View| {
+            let node = graph.lookup(path).expect("no such node");
+            graph.edge(graph.node(node).in_edge().expect("no producer"))
+        };
+        assert!(edge(b"build.ninja").is_generator());
+        assert!(!edge(b"build.ninja").is_restat());
+        assert!(edge(b"a").is_restat());
+        assert!(!edge(b"b").is_restat());
+        assert!(!edge(b"b").is_generator());
+    }
+
+    #[test]
+    fn deps() {
+        let ninja = b"rule cc\n    command = cc\n    deps = $style\n    \
+                      depfile = $out.d\n    msvc_deps_prefix = $prefix\n\
+                      build a.o : cc a.c\n    style = gcc\n\
+                      build b.obj : cc b.c\n    style = msvc\n    \
+                      prefix = Remarque : inclusion du fichier :\n\
+                      build c.o : cc c.c\n";
+        let (built, _) = graph(ninja).expect("failed to build graph");
+        let edge = |path: &blob::View| {
+            let node = built.lookup(path).expect("no such node");
+            built.edge(built.node(node).in_edge().expect("no producer"))
+        };
+        assert_eq!(edge(b"a.o").deps(), Some(DepsType::Gcc));
+        assert_eq!(edge(b"a.o").depfile(), Some(&b"a.o.d"[..]));
+        assert_eq!(edge(b"a.o").msvc_deps_prefix(), None);
+        assert_eq!(edge(b"b.obj").deps(), Some(DepsType::Msvc));
+        assert_eq!(
+            edge(b"b.obj").msvc_deps_prefix(),
+            Some(&b"Remarque : inclusion du fichier :"[..])
+        );
+        assert_eq!(edge(b"c.o").deps(), None);
+
+        let unknown = b"rule cc\n    command = cc\n    deps = $style\n\
+                        build a.o : cc a.c\n    style = clang\n";
+        match graph(unknown) {
+            Err(GraphError::AstError(ast::AstError::UnknownDeps { deps })) => {
+                assert_eq!(deps.view(), b"clang")
+            }
+            _ => panic!("accepted an unknown deps type"),
+        }
+        match graph(b"rule cc\n    command = cc\n    deps = gcc\nbuild a.o b.o : cc a.c\n") {
+            Err(GraphError::MultipleDepsOutputs { path }) => assert_eq!(path.view(), b"a.o"),
+            _ => panic!("accepted several outputs with deps"),
+        }
+        let implicit = b"rule cc\n    command = cc\n    deps = gcc\nbuild a.o | a.d : cc a.c\n";
+        assert!(graph(implicit).is_ok());
+    }
+
+    #[test]
+    fn defaults() {
+        let ninja = b"rule cc\n    command = cc\nbuild a : cc\nbuild b : cc\ndefault b\n";
+        let (graph, _) = graph(ninja).expect("failed to build graph");
+        assert_eq!(graph.defaults(), &[graph.lookup(b"b").expect("no b")][..]);
+    }
+
+    #[test]
+    fn unknown_rule() {
+        match graph(b"build out : cc in\n") {
+            Err(GraphError::UnknownRule { rule }) => assert_eq!(rule.view(), b"cc"),
+            _ => panic!("built an edge with an unknown rule"),
+        }
+    }
+
+    #[test]
+    fn unknown_pool() {
+        let ninja = b"rule cc\n    command = cc\n    pool = $pool_name\n\
+                      build out : cc in\n    pool_name = missing\n";
+        match graph(ninja) {
+            Err(GraphError::UnknownPool { pool }) => assert_eq!(pool.view(), b"missing"),
+            _ => panic!("built an edge with an unknown pool"),
+        }
+    }
+
+    #[test]
+    fn unknown_default() {
+        match graph(b"default missing\n") {
+            Err(GraphError::UnknownTarget { target }) => assert_eq!(target.view(), b"missing"),
+            _ => panic!("accepted an unknown default"),
+        }
+    }
+
+    #[test]
+    fn multiple_producers() {
+        let ninja = b"rule cc\n    command = cc\nbuild out : cc a\nbuild x | out : cc b\n";
+        match graph(ninja) {
+            Err(GraphError::MultipleProducers { path }) => assert_eq!(path.view(), b"out"),
+            _ => panic!("accepted an output produced twice"),
+        }
+    }
+
+    #[test]
+    fn subninja_rules() {
+        let mut arena = intern::Table::new();
+        let mut loader = crate::parse::MemoryLoader::new();
+        loader.insert(b"sub.ninja", b"rule cc\n    command = sub\nbuild b : cc\n");
+        let mut parser = crate::parse::Parser::new(
+            b"build.ninja",
+            b"rule cc\n    command = top\nsubninja sub.ninja\nbuild a : cc\n",
+        );
+        let file = parser
+            .parse(&mut arena, &mut loader)
+            .expect("failed to parse");
+        let graph = Graph::new(&mut arena, &file).expect("failed to build graph");
+        let command = |path: &blob::View| {
+            let node = graph.lookup(path).expect("no node");
+            graph
+                .edge(graph.node(node).in_edge().expect("no producer"))
+                .command()
+        };
+        assert_eq!(command(b"a"), b"top");
+        assert_eq!(command(b"b"), b"sub");
+    }
+
+    #[test]
+    fn phony() {
+        let ninja =
+            b"rule cc\n    command = cc\nbuild a : cc\nbuild b : cc\nbuild all : phony a b\n";
+        let (graph, _) = graph(ninja).expect("failed to build graph");
+        let all = graph.lookup(b"all").expect("no all");
+        let edge = graph.edge(graph.node(all).in_edge().expect("no producer"));
+        assert!(edge.is_phony());
+        assert!(edge.command().is_empty());
+        assert_eq!(edge.inputs().len(), 2);
+
+        let a = graph.lookup(b"a").expect("no a");
+        assert!(!graph
+            .edge(graph.node(a).in_edge().expect("no producer"))
+            .is_phony());
+    }
+
+    #[test]
+    fn console() {
+        let ninja = b"rule run\n    command = run\n    pool = console\nbuild out : run\n";
+        let (graph, arena) = graph(ninja).expect("failed to build graph");
+        let out = graph.lookup(b"out").expect("no out");
+        let edge = graph.edge(graph.node(out).in_edge().expect("no producer"));
+        assert_eq!(edge.pool(), Some(graph.console()));
+        let console = graph.pool(graph.console());
+        assert_eq!(console.name().name(&arena), b"console");
+        assert_eq!(console.depth(), 1);
+    }
+
+    #[test]
+    fn validations() {
+        let ninja = b"rule cc\n    command = cc\n\
+                      build out : cc in |@ check\n\
+                      build check : cc in |@ lint\n\
+                      build lint : cc\n";
+        let (graph, _) = graph(ninja).expect("failed to build graph");
+        let out = graph.lookup(b"out").expect("no out");
+        let check = graph.lookup(b"check").expect("no check");
+        let lint = graph.lookup(b"lint").expect("no lint");
+
+        let edge = graph.node(out).in_edge().expect("no producer");
+        assert_eq!(graph.edge(edge).validations(), &[check]);
+        assert!(graph.edge(edge).all_inputs().all(|input| input != check));
+        assert_eq!(graph.node(check).validation_out_edges(), &[edge]);
+        assert!(graph.node(check).out_edges().is_empty());
+
+        assert_eq!(graph.roots(), vec![out]);
+        assert_eq!(graph.with_validations(&[out]), vec![out, check, lint]);
+        assert_eq!(graph.with_validations(&[lint]), vec![lint]);
+    }
+
+    #[test]
+    fn canonical_paths() {
+        let ninja = b"rule cc\n    command = cc $in -o $out\n\
+                      build foo/./bar.o: cc ./src//bar.c\n\
+                      build app: cc foo/bar.o x/../foo/bar.o\n\
+                      default ./app\n";
+        let (graph, _) = graph(ninja).expect("failed to build graph");
+        let bar = graph.lookup(b"foo/bar.o").expect("no foo/bar.o");
+        assert_eq!(graph.lookup(b"./foo//bar.o"), Some(bar));
+        assert_eq!(path(&graph, bar), b"foo/bar.o");
+        assert_eq!(graph.node(bar).path_decanonicalized().view(), b"foo/bar.o");
+        assert!(graph.lookup(b"src/bar.c").is_some());
+
+        let app = graph.lookup(b"app").expect("no app");
+        let link = graph.edge(graph.node(app).in_edge().expect("no producer"));
+        assert_eq!(link.inputs(), &[bar, bar]);
+        assert_eq!(graph.defaults(), &[app]);
+
+        let compile = graph.edge(graph.node(bar).in_edge().expect("no producer"));
+        assert_eq!(compile.command(), b"cc src/bar.c -o foo/bar.o");
+    }
+
+    #[test]
+    fn implicit_inputs() {
+        let ninja = b"rule cc\n    command = cc $in -o $out\n\
+                      build a.o: cc a.c | a.h || gen.h\n";
+        let (mut graph, _) = graph(ninja).expect("failed to build graph");
+        let out = graph.lookup(b"a.o").expect("no a.o");
+        let edge = graph.node(out).in_edge().expect("no producer");
+        let discovered = [&b"a.c"[..], b"a.h", b"b.h", b"gen.h", b"./b.h", b"c.h"];
+        let discovered = discovered
+            .iter()
+            .map(|path| Blob::new(path))
+            .collect::<Vec<_>>();
+        graph.add_implicit_inputs(edge, &discovered);
+
+        let paths = |ids: &[arena::Id<Node>]| {
+            ids.iter()
+                .map(|id| String::from_utf8_lossy(path(&graph, *id)).into_owned())
+                .collect::<Vec<_>>()
+        };
+        let compile = graph.edge(edge);
+        assert_eq!(
+            paths(compile.implicit_inputs()),
+            ["a.h", "b.h", "gen.h", "c.h"]
+        );
+        assert!(compile.order_inputs().is_empty());
+        assert_eq!(compile.all_inputs().count(), 5);
+        let gen = graph.lookup(b"gen.h").expect("no gen.h");
+        assert_eq!(graph.node(gen).out_edges(), &[edge]);
+        let b = graph.lookup(b"b.h").expect("no b.h");
+        assert_eq!(graph.node(b).out_edges(), &[edge]);
+    }
+}
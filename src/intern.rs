@@ -1,11 +1,15 @@
 use crate::blob;
+use std::rc::Rc;
 
-#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Symbol(usize);
 
+/// Interns byte strings, handing out a `Symbol` for each distinct one.
+/// Each string is allocated once and shared between the lookup map and
+/// the symbol-indexed list.
 pub struct Table {
-    hash: std::collections::HashMap<blob::Blob, Symbol>,
-    ids: Vec<blob::Blob>,
+    hash: std::collections::HashMap<Rc<blob::View>, Symbol>,
+    ids: Vec<Rc<blob::View>>,
 }
 
 impl Table {
@@ -20,12 +24,40 @@ impl Table {
             Some(id) => *id,
             None => {
                 let id = Symbol(self.ids.len());
-                self.hash.insert(blob::Blob::new(bytes), id);
-                self.ids.push(blob::Blob::new(bytes));
+                let bytes: Rc<blob::View> = Rc::from(bytes);
+                self.hash.insert(Rc::clone(&bytes), id);
+                self.ids.push(bytes);
                 id
             }
         }
     }
+
+    pub fn resolve(&self, symbol: Symbol) -> &blob::View {
+        &self.ids[symbol.0]
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Iterates over every interned symbol, in the order they were
+    /// first inserted.
+    pub fn iter(&self) -> impl Iterator<Item = (Symbol, &blob::View)> {
+        self.ids
+            .iter()
+            .enumerate()
+            .map(|(index, bytes)| (Symbol(index), &**bytes))
+    }
+}
+
+impl Default for Table {
+    fn default() -> Table {
+        Table::new()
+    }
 }
 
 #[cfg(test)]
@@ -53,4 +85,34 @@ mod tests {
         let ids: std::collections::HashSet<Symbol> = ids.iter().cloned().collect();
         assert!(ids.len() == 2);
     }
+
+    #[test]
+    fn resolve() {
+        let mut arena = Table::new();
+        let a = arena.insert(b"aaaaa");
+        let b = arena.insert(b"bbbbb");
+        assert_eq!(arena.resolve(a), b"aaaaa");
+        assert_eq!(arena.resolve(b), b"bbbbb");
+        assert_eq!(arena.insert(b"aaaaa"), a);
+        assert_eq!(arena.len(), 2);
+    }
+
+    #[test]
+    fn iteration() {
+        let mut arena = Table::new();
+        assert!(arena.is_empty());
+        let names: &[&blob::View] = &[b"c", b"a", b"b", b"a"];
+        let symbols: Vec<Symbol> = names.iter().map(|name| arena.insert(name)).collect();
+        assert_eq!(arena.len(), 3);
+
+        let interned: Vec<(Symbol, &blob::View)> = arena.iter().collect();
+        assert_eq!(
+            interned,
+            vec![
+                (symbols[0], &b"c"[..]),
+                (symbols[1], &b"a"[..]),
+                (symbols[2], &b"b"[..]),
+            ]
+        );
+    }
 }
@@ -0,0 +1,506 @@
+//! Sharing job slots with make through the GNU make jobserver protocol.
+//!
+//! A jobserver is a pipe or FIFO holding one byte, a token, for each job
+//! slot beyond the first. Every process in a build may always run one
+//! job, and must read a token for each other job it runs at the same
+//! time, writing it back when the job finishes. Make tells the commands
+//! it runs where the tokens are in `MAKEFLAGS`, with
+//! `--jobserver-auth=fifo:PATH` or, in older versions and for pipes,
+//! `--jobserver-auth=R,W` naming inherited file descriptors.
+//!
+//! A [`Client`] takes tokens from a jobserver, such as one started by a
+//! make that runs this build. A [`Server`] starts a jobserver for the
+//! commands of a build to share. Only make 4.4 and later, and ninja,
+//! understand a FIFO, so a server can also use a pipe, whose descriptors
+//! every command inherits.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Where the tokens of a jobserver are.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Auth {
+    Fifo(PathBuf),
+    /// File descriptors inherited from the process that started the
+    /// jobserver.
+    Pipe {
+        read: i32,
+        write: i32,
+    },
+}
+
+/// Finds the jobserver named in a `MAKEFLAGS` value. When several are
+/// named, make uses the last.
+pub fn parse_makeflags(makeflags: &str) -> Option<Auth> {
+    let mut auth = None;
+    for flag in makeflags.split_whitespace() {
+        let value = match flag
+            .strip_prefix("--jobserver-auth=")
+            .or_else(|| flag.strip_prefix("--jobserver-fds="))
+        {
+            Some(value) => value,
+            None => continue,
+        };
+        auth = match value.strip_prefix("fifo:") {
+            Some(path) => Some(Auth::Fifo(PathBuf::from(path))),
+            None => parse_fds(value),
+        };
+    }
+    auth
+}
+
+/// Parses `R,W`. Make passes negative descriptors to commands that it
+/// does not think are makes, which have no jobserver.
+fn parse_fds(value: &str) -> Option<Auth> {
+    let mut fds = value.splitn(2, ',');
+    let read = fds.next()?.parse().ok()?;
+    let write = fds.next()?.parse().ok()?;
+    if read < 0 || write < 0 {
+        return None;
+    }
+    Some(Auth::Pipe { read, write })
+}
+
+/// Takes tokens from a jobserver. Tokens still held when it is dropped
+/// are given back.
+#[derive(Debug)]
+pub struct Client {
+    reader: File,
+    writer: File,
+    tokens: Vec<u8>,
+}
+
+impl Client {
+    /// Connects to the jobserver at `auth`.
+    pub fn open(auth: &Auth) -> io::Result<Client> {
+        match auth {
+            Auth::Fifo(path) => {
+                let file = open_nonblocking(path, true)?;
+                let writer = file.try_clone()?;
+                Ok(Client {
+                    reader: file,
+                    writer,
+                    tokens: vec![],
+                })
+            }
+            Auth::Pipe { read, write } => {
+                check_pipe(*read)?;
+                check_pipe(*write)?;
+                // Reopening the descriptors, rather than taking them over,
+                // makes reads nonblocking without affecting other users
+                // of the pipe.
+                let reader = open_nonblocking(&fd_path(*read), false)?;
+                let writer = OpenOptions::new().write(true).open(fd_path(*write))?;
+                Ok(Client {
+                    reader,
+                    writer,
+                    tokens: vec![],
+                })
+            }
+        }
+    }
+
+    /// Connects to the jobserver named in the `MAKEFLAGS` environment
+    /// variable, if there is one.
+    pub fn from_env() -> Option<io::Result<Client>> {
+        let makeflags = std::env::var("MAKEFLAGS").ok()?;
+        let auth = parse_makeflags(&makeflags)?;
+        Some(Client::open(&auth))
+    }
+
+    /// Takes a token if one is free, without waiting for one.
+    pub fn try_acquire(&mut self) -> io::Result<bool> {
+        let mut token = [0];
+        loop {
+            match self.reader.read(&mut token) {
+                Ok(0) => return Ok(false),
+                Ok(_) => {
+                    self.tokens.push(token[0]);
+                    return Ok(true);
+                }
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => (),
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Gives back one of the tokens held.
+    pub fn release(&mut self) -> io::Result<()> {
+        if let Some(token) = self.tokens.pop() {
+            if let Err(error) = self.writer.write_all(&[token]) {
+                self.tokens.push(token);
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    /// The number of tokens held.
+    pub fn held(&self) -> usize {
+        self.tokens.len()
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        while !self.tokens.is_empty() {
+            if self.release().is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// A jobserver with a fixed number of slots, in a FIFO that is removed
+/// when the server is dropped or in a pipe.
+#[derive(Debug)]
+pub struct Server {
+    auth: Auth,
+    jobs: usize,
+    /// Keeps the FIFO or pipe open, since its tokens would be lost if
+    /// nothing had it open. Tokens are written to the first file.
+    files: Vec<File>,
+}
+
+impl Server {
+    /// Creates a FIFO at `path` holding tokens for `jobs` slots, one of
+    /// which is implicit. A FIFO already at `path`, left by an earlier
+    /// process, is replaced.
+    pub fn new(path: &Path, jobs: usize) -> io::Result<Server> {
+        remove_fifo(path)?;
+        mkfifo(path)?;
+        let file = match open_nonblocking(path, true) {
+            Ok(file) => file,
+            Err(error) => {
+                let _ = std::fs::remove_file(path);
+                return Err(error);
+            }
+        };
+        Server::start(Auth::Fifo(path.to_path_buf()), jobs, vec![file])
+    }
+
+    /// Creates a pipe holding tokens for `jobs` slots, one of which is
+    /// implicit. Its descriptors are inherited by every process started
+    /// while the server exists.
+    pub fn pipe(jobs: usize) -> io::Result<Server> {
+        let (read, write) = pipe()?;
+        let auth = Auth::Pipe {
+            read: raw_fd(&read),
+            write: raw_fd(&write),
+        };
+        Server::start(auth, jobs, vec![write, read])
+    }
+
+    fn start(auth: Auth, jobs: usize, files: Vec<File>) -> io::Result<Server> {
+        let server = Server { auth, jobs, files };
+        let tokens = vec![b'+'; jobs.saturating_sub(1)];
+        (&server.files[0]).write_all(&tokens)?;
+        Ok(server)
+    }
+
+    /// The `MAKEFLAGS` value that tells commands to use this server. A
+    /// pipe is named with both the option of make 4.2 and later and the
+    /// older `--jobserver-fds`, since make ignores options it does not
+    /// know in `MAKEFLAGS`.
+    pub fn makeflags(&self) -> String {
+        match &self.auth {
+            Auth::Fifo(path) => format!("-j{} --jobserver-auth=fifo:{}", self.jobs, path.display()),
+            Auth::Pipe { read, write } => format!(
+                "-j{} --jobserver-fds={},{} --jobserver-auth={},{}",
+                self.jobs, read, write, read, write
+            ),
+        }
+    }
+
+    /// Connects a client to this server, for the build running the
+    /// commands to take its own slots from.
+    pub fn client(&self) -> io::Result<Client> {
+        Client::open(&self.auth)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        if let Auth::Fifo(path) = &self.auth {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+fn fd_path(fd: i32) -> PathBuf {
+    PathBuf::from(format!("/dev/fd/{}", fd))
+}
+
+/// Checks that `fd` is an open pipe. Make only passes its jobserver to
+/// commands it thinks are makes, so for others the descriptors named in
+/// `MAKEFLAGS` may be closed, or reused for unrelated files.
+#[cfg(unix)]
+fn check_pipe(fd: i32) -> io::Result<()> {
+    let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+    if unsafe { libc::fstat(fd, stat.as_mut_ptr()) } != 0 {
+        let message = format!("file descriptor {} is not open", fd);
+        return Err(io::Error::other(message));
+    }
+    let stat = unsafe { stat.assume_init() };
+    if stat.st_mode & libc::S_IFMT != libc::S_IFIFO {
+        let message = format!("file descriptor {} is not a pipe", fd);
+        return Err(io::Error::other(message));
+    }
+    Ok(())
+}
+
+/// Removes the FIFO at `path`, if there is one. Any other file is left
+/// for `mkfifo` to fail on.
+#[cfg(unix)]
+fn remove_fifo(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_fifo() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+/// Creates a pipe whose descriptors, unlike those of the standard
+/// library's pipes, are inherited by child processes.
+#[cfg(unix)]
+fn pipe() -> io::Result<(File, File)> {
+    use std::os::unix::io::FromRawFd;
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+#[cfg(unix)]
+fn raw_fd(file: &File) -> i32 {
+    use std::os::unix::io::AsRawFd;
+    file.as_raw_fd()
+}
+
+/// Creates a FIFO at `path` that only the user may use.
+#[cfg(unix)]
+fn mkfifo(path: &Path) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::mkfifo(path.as_ptr(), 0o600) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Opens `path` for reading, and optionally writing, such that reads
+/// fail with `WouldBlock` rather than waiting for data.
+#[cfg(unix)]
+fn open_nonblocking(path: &Path, write: bool) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new()
+        .read(true)
+        .write(write)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+}
+
+#[cfg(not(unix))]
+fn check_pipe(_: i32) -> io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(unix))]
+fn remove_fifo(_: &Path) -> io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(unix))]
+fn pipe() -> io::Result<(File, File)> {
+    Err(unsupported())
+}
+
+#[cfg(not(unix))]
+fn raw_fd(_: &File) -> i32 {
+    -1
+}
+
+#[cfg(not(unix))]
+fn mkfifo(_: &Path) -> io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(unix))]
+fn open_nonblocking(_: &Path, _: bool) -> io::Result<File> {
+    Err(unsupported())
+}
+
+#[cfg(not(unix))]
+fn unsupported() -> io::Error {
+    let message = "jobservers are only supported on unix";
+    io::Error::new(io::ErrorKind::Unsupported, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fifo(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ninja-rs-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn makeflags() {
+        assert_eq!(parse_makeflags(""), None);
+        assert_eq!(parse_makeflags(" -j8"), None);
+        assert_eq!(
+            parse_makeflags(" -j8 --jobserver-auth=3,4"),
+            Some(Auth::Pipe { read: 3, write: 4 })
+        );
+        assert_eq!(
+            parse_makeflags("-j --jobserver-fds=5,6 -Otarget"),
+            Some(Auth::Pipe { read: 5, write: 6 })
+        );
+        assert_eq!(
+            parse_makeflags("-j4 --jobserver-auth=fifo:/tmp/GMfifo123"),
+            Some(Auth::Fifo(PathBuf::from("/tmp/GMfifo123")))
+        );
+        assert_eq!(
+            parse_makeflags("--jobserver-auth=3,4 --jobserver-auth=fifo:/tmp/x"),
+            Some(Auth::Fifo(PathBuf::from("/tmp/x")))
+        );
+        assert_eq!(parse_makeflags("-j4 --jobserver-auth=-2,-2"), None);
+        assert_eq!(parse_makeflags("--jobserver-auth=3"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn server_tokens() {
+        let path = fifo("jobserver-tokens");
+        let server = Server::new(&path, 3).expect("failed to start server");
+        assert_eq!(
+            server.makeflags(),
+            format!("-j3 --jobserver-auth=fifo:{}", path.display())
+        );
+
+        let mut client = server.client().expect("failed to connect");
+        assert!(client.try_acquire().unwrap());
+        assert!(client.try_acquire().unwrap());
+        assert!(!client.try_acquire().unwrap());
+        assert_eq!(client.held(), 2);
+
+        client.release().unwrap();
+        assert_eq!(client.held(), 1);
+        assert!(client.try_acquire().unwrap());
+        assert!(!client.try_acquire().unwrap());
+
+        drop(client);
+        drop(server);
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stale_fifo() {
+        let path = fifo("jobserver-stale");
+        mkfifo(&path).expect("failed to create FIFO");
+        let server = Server::new(&path, 2).expect("failed to start server");
+        let mut client = server.client().expect("failed to connect");
+        assert!(client.try_acquire().unwrap());
+        assert!(!client.try_acquire().unwrap());
+        drop(client);
+        drop(server);
+        assert!(!path.exists());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn pipe_server() {
+        let server = Server::pipe(3).expect("failed to start server");
+        let (read, write) = match server.auth {
+            Auth::Pipe { read, write } => (read, write),
+            _ => panic!("not a pipe"),
+        };
+        assert_eq!(
+            server.makeflags(),
+            format!(
+                "-j3 --jobserver-fds={},{} --jobserver-auth={},{}",
+                read, write, read, write
+            )
+        );
+        assert_eq!(
+            parse_makeflags(&server.makeflags()),
+            Some(Auth::Pipe { read, write })
+        );
+
+        // A command inherits the descriptors, and can take a token.
+        let output = std::process::Command::new("/bin/sh")
+            .arg("-c")
+            .arg(format!("dd bs=1 count=1 <&{} 2>/dev/null", read))
+            .output()
+            .expect("failed to run command");
+        assert_eq!(output.stdout, b"+");
+
+        let mut client = server.client().expect("failed to connect");
+        assert!(client.try_acquire().unwrap());
+        assert!(!client.try_acquire().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn clients_share_tokens() {
+        let path = fifo("jobserver-share");
+        let server = Server::new(&path, 2).expect("failed to start server");
+        let auth = parse_makeflags(&server.makeflags()).expect("no jobserver");
+
+        let mut first = Client::open(&auth).expect("failed to connect");
+        let mut second = Client::open(&auth).expect("failed to connect");
+        assert!(first.try_acquire().unwrap());
+        assert!(!second.try_acquire().unwrap());
+        drop(first);
+        assert!(second.try_acquire().unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn pipe() {
+        use std::os::unix::io::AsRawFd;
+        let (reader, mut writer) = io::pipe().expect("failed to create pipe");
+        writer.write_all(b"+").unwrap();
+        let auth = parse_makeflags(&format!(
+            "-j2 --jobserver-auth={},{}",
+            reader.as_raw_fd(),
+            writer.as_raw_fd()
+        ))
+        .expect("no jobserver");
+
+        let mut client = Client::open(&auth).expect("failed to connect");
+        assert!(client.try_acquire().unwrap());
+        assert!(!client.try_acquire().unwrap());
+        drop(client);
+
+        let mut returned = [0];
+        let mut reader = reader;
+        reader.read_exact(&mut returned).unwrap();
+        assert_eq!(&returned, b"+");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_non_pipes() {
+        use std::os::unix::io::AsRawFd;
+        let path = fifo("jobserver-file");
+        let file = File::create(&path).unwrap();
+        let fd = file.as_raw_fd();
+        let auth = Auth::Pipe {
+            read: fd,
+            write: fd,
+        };
+        let error = Client::open(&auth).expect_err("connected to a file");
+        assert_eq!(
+            error.to_string(),
+            format!("file descriptor {} is not a pipe", fd)
+        );
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,5 +1,6 @@
 use crate::blob;
 use crate::intern;
+use std::fmt;
 
 #[derive(Clone, Copy, Hash, Eq, PartialEq)]
 pub struct Identifier {
@@ -11,6 +12,10 @@ impl Identifier {
         let id = arena.insert(name);
         Identifier { id }
     }
+
+    pub fn name<'a>(&self, arena: &'a intern::Table) -> &'a blob::View {
+        arena.resolve(self.id)
+    }
 }
 
 pub struct Value {
@@ -32,6 +37,7 @@ pub enum ValuePart {
 pub enum TokenKind {
     Pipe,
     PipePipe,
+    PipeAt,
     Equal,
     Colon,
 
@@ -39,6 +45,25 @@ pub enum TokenKind {
 
     Newline,
     Indent,
+    /// Only lexed in lossless mode, which keeps comments.
+    Comment,
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            TokenKind::Pipe => "'|'",
+            TokenKind::PipePipe => "'||'",
+            TokenKind::PipeAt => "'|@'",
+            TokenKind::Equal => "'='",
+            TokenKind::Colon => "':'",
+            TokenKind::Identifier => "identifier",
+            TokenKind::Newline => "newline",
+            TokenKind::Indent => "indent",
+            TokenKind::Comment => "comment",
+        };
+        f.write_str(name)
+    }
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -65,10 +90,6 @@ impl SourceLocation {
     fn range(&self) -> std::ops::Range<usize> {
         self.range.0..self.range.1
     }
-
-    pub fn line(&self) -> usize {
-        self.line
-    }
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -93,45 +114,286 @@ enum Lexed {
     Comment,
 }
 
+/// What a [`Piece`] of input is. `$` sequences in paths and values are
+/// escapes, variable references or line continuations.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PieceKind {
+    Token(TokenKind),
+    Text,
+    Escape,
+    Variable,
+    Whitespace,
+    /// `$` and a line ending, with the indentation that follows.
+    Continuation,
+    /// Input skipped by [`Lexer::skip_line`].
+    Error,
+}
+
+/// A span of input, recorded by a lexer in lossless mode. Together, the
+/// pieces cover every byte the lexer has consumed, in order.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Piece {
+    kind: PieceKind,
+    range: std::ops::Range<usize>,
+}
+
+impl Piece {
+    pub fn kind(&self) -> PieceKind {
+        self.kind
+    }
+
+    pub fn range(&self) -> std::ops::Range<usize> {
+        self.range.clone()
+    }
+}
+
+/// A position in a named input, as reported in diagnostics. Lines
+/// and columns count from 1.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Location {
+    file: blob::Blob,
+    line: usize,
+    column: usize,
+    span: std::ops::Range<usize>,
+    text: blob::Blob,
+}
+
+impl Location {
+    /// The location of `span` within `input`, which was read from `file`.
+    pub(crate) fn new(
+        file: &blob::View,
+        input: &blob::View,
+        span: std::ops::Range<usize>,
+    ) -> Location {
+        let before = &input[..span.start];
+        let start = match before.iter().rposition(|b| *b == b'\n') {
+            Some(newline) => newline + 1,
+            None => 0,
+        };
+        let end = match input[start..].iter().position(|b| *b == b'\n') {
+            Some(newline) => start + newline,
+            None => input.len(),
+        };
+        let mut text = &input[start..end];
+        if let Some(b'\r') = text.last() {
+            text = &text[..text.len() - 1];
+        }
+        Location {
+            file: blob::Blob::new(file),
+            line: 1 + before.iter().filter(|b| **b == b'\n').count(),
+            column: 1 + span.start - start,
+            span,
+            text: blob::Blob::new(text),
+        }
+    }
+
+    pub fn file(&self) -> &blob::View {
+        &self.file
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    pub fn span(&self) -> std::ops::Range<usize> {
+        self.span.clone()
+    }
+
+    /// Writes `message` in the style of ninja's diagnostics, followed
+    /// by the offending line and a caret pointing into it.
+    pub fn report(&self, f: &mut fmt::Formatter, message: &dyn fmt::Display) -> fmt::Result {
+        writeln!(
+            f,
+            "{}:{}: {}",
+            String::from_utf8_lossy(&self.file),
+            self.line,
+            message
+        )?;
+        writeln!(f, "{}", String::from_utf8_lossy(&self.text))?;
+        write!(f, "{:width$}^ near here", "", width = self.column - 1)
+    }
+}
+
 #[derive(Debug)]
 pub enum LexError {
-    UnknownToken,
-    InvalidDeclStart,
+    UnknownToken { location: Location },
+    InvalidDeclStart { location: Location },
 }
 
+impl LexError {
+    pub fn location(&self) -> &Location {
+        match self {
+            LexError::UnknownToken { location } => location,
+            LexError::InvalidDeclStart { location } => location,
+        }
+    }
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            LexError::UnknownToken { .. } => "lexing error",
+            LexError::InvalidDeclStart { .. } => "expected a declaration",
+        };
+        self.location().report(f, &message)
+    }
+}
+
+impl std::error::Error for LexError {}
+
 pub struct Lexer<'input> {
+    file: &'input blob::View,
     input: &'input blob::View,
     current: std::ops::Range<usize>,
     line: usize,
+    pieces: Option<Vec<Piece>>,
 }
 
 impl<'input> Lexer<'input> {
-    pub fn new(input: &'input blob::View) -> Lexer<'input> {
+    pub fn new(file: &'input blob::View, input: &'input blob::View) -> Lexer<'input> {
         Lexer {
+            file,
             input,
             current: 0..0,
             line: 1,
+            pieces: None,
+        }
+    }
+
+    /// A lexer that records every byte it consumes as a [`Piece`], for
+    /// the concrete syntax tree. Comments are lexed as tokens rather
+    /// than skipped.
+    pub fn lossless(file: &'input blob::View, input: &'input blob::View) -> Lexer<'input> {
+        let mut lexer = Lexer::new(file, input);
+        lexer.pieces = Some(vec![]);
+        lexer
+    }
+
+    /// The pieces recorded since they were last taken.
+    pub fn take_pieces(&mut self) -> Vec<Piece> {
+        match &mut self.pieces {
+            Some(pieces) => std::mem::take(pieces),
+            None => vec![],
+        }
+    }
+
+    /// In lossless mode, records the rest of a line that failed to lex
+    /// as an error piece, stopping before its line ending.
+    pub fn skip_line(&mut self) {
+        while !self.at_line_end() {
+            self.advance()
+        }
+        self.commit(PieceKind::Error);
+    }
+
+    /// Resolves a token's position for use in a diagnostic.
+    pub fn locate<Kind>(&self, token: Token<Kind>) -> Location {
+        self.location(token.location.range())
+    }
+
+    /// Resolves a position previously returned by `mark`.
+    pub fn resolve(&self, location: SourceLocation) -> Location {
+        self.location(location.range())
+    }
+
+    /// The position of the next unlexed byte, or of the end of input.
+    pub fn here(&self) -> Location {
+        self.resolve(self.mark())
+    }
+
+    /// Like `here`, but cheap to compute. Resolving the position into a
+    /// `Location` is deferred until it is needed for a diagnostic.
+    pub fn mark(&self) -> SourceLocation {
+        let end = self.current.end;
+        SourceLocation {
+            range: (end, end + 1),
+            line: self.line,
         }
     }
 
+    fn location(&self, span: std::ops::Range<usize>) -> Location {
+        Location::new(self.file, self.input, span)
+    }
+
     pub fn lexeme<Kind>(&self, token: Token<Kind>) -> &'input blob::View {
         &self.input[token.location.range()]
     }
 
+    /// Whether the next line is indented. Like ninja, comment lines are
+    /// skipped first, so that they can sit between bindings.
     pub fn try_indent(&mut self) -> bool {
-        matches!(self.peek(), Some(b' '))
+        loop {
+            let rest = &self.input[self.current.end..];
+            let spaces = rest.iter().take_while(|b| **b == b' ').count();
+            if rest.get(spaces) != Some(&b'#') {
+                return spaces > 0;
+            }
+            match rest.iter().position(|b| *b == b'\n') {
+                Some(newline) => {
+                    self.current.end += newline + 1;
+                    self.line += 1;
+                    self.start_next_token();
+                }
+                None => return false,
+            }
+        }
+    }
+
+    /// Skips to the start of the next unindented line, where
+    /// `lex_decl` can find the next declaration after an error. The
+    /// line holding `start`, where the failed declaration began, is
+    /// always skipped, as are escaped newlines.
+    pub fn resync(&mut self, start: SourceLocation) {
+        let end = self.current.end;
+        let mut line_start = end > start.range.0 && self.input[end - 1] == b'\n';
+        loop {
+            if line_start && self.peek() != Some(b' ') {
+                break;
+            }
+            loop {
+                match self.peek() {
+                    None => {
+                        self.start_next_token();
+                        return;
+                    }
+                    Some(b'\n') => {
+                        self.advance();
+                        self.line += 1;
+                        break;
+                    }
+                    Some(b'$') => {
+                        self.advance();
+                        if self.peek() == Some(b'\n') {
+                            self.line += 1;
+                        }
+                        if self.peek().is_some() {
+                            self.advance();
+                        }
+                    }
+                    Some(_) => self.advance(),
+                }
+            }
+            line_start = true;
+        }
+        self.start_next_token();
     }
 
     fn lex_dollar(&mut self, arena: &mut intern::Table) -> Result<ValuePart, LexError> {
         match self.peek() {
-            None => Err(LexError::UnknownToken),
+            None => self.unknown_token(),
             Some(b) => match b {
                 b' ' | b':' | b'$' => {
                     self.advance();
+                    self.commit(PieceKind::Escape);
                     Ok(ValuePart::Text(blob::Blob::new(&[b])))
                 }
                 b'\n' => {
                     self.advance();
+                    self.continue_line();
                     self.skip_whitespace()?;
                     Ok(ValuePart::Text(blob::Blob::new(b"")))
                 }
@@ -140,10 +402,11 @@ impl<'input> Lexer<'input> {
                     match self.peek() {
                         Some(b'\n') => {
                             self.advance();
+                            self.continue_line();
                             self.skip_whitespace()?;
                             Ok(ValuePart::Text(blob::Blob::new(b"")))
                         }
-                        _ => Err(LexError::UnknownToken),
+                        _ => self.unknown_token(),
                     }
                 }
                 b'{' => {
@@ -159,9 +422,10 @@ impl<'input> Lexer<'input> {
                                 self.advance();
                                 break;
                             }
-                            _ => return Err(LexError::UnknownToken),
+                            _ => return self.unknown_token(),
                         }
                     }
+                    self.commit(PieceKind::Variable);
                     let variable = Identifier::new(arena, &variable);
                     Ok(ValuePart::Variable(variable))
                 }
@@ -178,11 +442,12 @@ impl<'input> Lexer<'input> {
                             _ => break,
                         }
                     }
+                    self.commit(PieceKind::Variable);
                     let variable = variable.blob();
                     let variable = Identifier::new(arena, &variable);
                     Ok(ValuePart::Variable(variable))
                 }
-                _ => Err(LexError::UnknownToken),
+                _ => self.unknown_token(),
             },
         }
     }
@@ -191,7 +456,7 @@ impl<'input> Lexer<'input> {
         let mut parts = vec![];
         loop {
             match self.peek() {
-                None => return Err(LexError::UnknownToken),
+                None => return self.unknown_token(),
                 Some(b) => match b {
                     b'\n' => break,
 
@@ -203,6 +468,7 @@ impl<'input> Lexer<'input> {
 
                     b => {
                         self.advance();
+                        self.commit(PieceKind::Text);
                         parts.push(ValuePart::Text(blob::Blob::new(&[b])))
                     }
                 },
@@ -218,7 +484,7 @@ impl<'input> Lexer<'input> {
         let mut parts = vec![];
         loop {
             match self.peek() {
-                None => return Err(LexError::UnknownToken),
+                None => return self.unknown_token(),
                 Some(b) => match b {
                     b'|' | b':' | b' ' | b'\n' => break,
 
@@ -230,6 +496,7 @@ impl<'input> Lexer<'input> {
 
                     b => {
                         self.advance();
+                        self.commit(PieceKind::Text);
                         parts.push(ValuePart::Text(blob::Blob::new(&[b])))
                     }
                 },
@@ -264,7 +531,9 @@ impl<'input> Lexer<'input> {
         match self.lex()? {
             Some(token) => match self.decl(token) {
                 Some(decl) => Ok(Some(decl)),
-                None => Err(LexError::InvalidDeclStart),
+                None => Err(LexError::InvalidDeclStart {
+                    location: self.locate(token),
+                }),
             },
             None => Ok(None),
         }
@@ -289,6 +558,10 @@ impl<'input> Lexer<'input> {
                             self.advance();
                             Ok(self.token(TokenKind::PipePipe))
                         }
+                        Some(b'@') => {
+                            self.advance();
+                            Ok(self.token(TokenKind::PipeAt))
+                        }
                         _ => Ok(self.token(TokenKind::Pipe)),
                     }
                 }
@@ -313,6 +586,12 @@ impl<'input> Lexer<'input> {
                         self.advance()
                     }
 
+                    // Like ninja, skip an indented comment along with its
+                    // indentation.
+                    if self.pieces.is_none() && self.peek() == Some(b'#') {
+                        return self.lex_one();
+                    }
+
                     Ok(self.token(TokenKind::Indent))
                 }
 
@@ -323,7 +602,7 @@ impl<'input> Lexer<'input> {
                             self.advance();
                             Ok(self.token(TokenKind::Newline))
                         }
-                        _ => self.error(LexError::UnknownToken),
+                        _ => self.unknown_token(),
                     }
                 }
 
@@ -334,24 +613,25 @@ impl<'input> Lexer<'input> {
 
                 b'#' => {
                     self.advance();
-
-                    loop {
-                        match self.peek() {
-                            Some(b'\n') => {
-                                self.line += 1;
-                                self.advance();
-                                self.start_next_token();
-                                break;
-                            }
-                            None => return self.error(LexError::UnknownToken),
-                            _ => self.advance(),
-                        }
+                    while !self.at_line_end() {
+                        self.advance()
+                    }
+                    if self.pieces.is_some() {
+                        return Ok(self.token(TokenKind::Comment));
                     }
 
+                    match self.peek() {
+                        None => return self.unknown_token(),
+                        Some(b'\r') => self.advance(),
+                        Some(_) => (),
+                    }
+                    self.line += 1;
+                    self.advance();
+                    self.start_next_token();
                     Ok(Lexed::Comment)
                 }
 
-                _ => self.error(LexError::UnknownToken),
+                _ => self.unknown_token(),
             },
         }
     }
@@ -371,15 +651,18 @@ impl<'input> Lexer<'input> {
             self.line += 1
         }
         let location = SourceLocation { range, line };
-        self.start_next_token();
+        self.commit(PieceKind::Token(kind));
         Lexed::Token(Token { kind, location })
     }
 
-    fn error<T>(&self, error: LexError) -> Result<T, LexError> {
-        Err(error)
+    fn unknown_token<T>(&self) -> Result<T, LexError> {
+        Err(LexError::UnknownToken {
+            location: self.here(),
+        })
     }
 
-    fn decl(&mut self, token: Token<TokenKind>) -> Option<Token<DeclKind>> {
+    /// The declaration a token starts, if it can start one.
+    pub fn decl(&self, token: Token<TokenKind>) -> Option<Token<DeclKind>> {
         let kind = match token.kind() {
             TokenKind::Newline => DeclKind::Newline,
             TokenKind::Identifier => self.keyword(token),
@@ -414,36 +697,71 @@ impl<'input> Lexer<'input> {
         self.current.start = self.current.end
     }
 
+    /// Ends the current piece of input, recording it in lossless mode.
+    /// Adjacent text is recorded as one piece.
+    fn commit(&mut self, kind: PieceKind) {
+        let range = self.current.clone();
+        self.start_next_token();
+        let pieces = match &mut self.pieces {
+            Some(pieces) if !range.is_empty() => pieces,
+            _ => return,
+        };
+        match pieces.last_mut() {
+            Some(last)
+                if kind == PieceKind::Text
+                    && last.kind == PieceKind::Text
+                    && last.range.end == range.start =>
+            {
+                last.range.end = range.end
+            }
+            _ => pieces.push(Piece { kind, range }),
+        }
+    }
+
+    fn at_line_end(&self) -> bool {
+        match self.peek() {
+            None | Some(b'\n') => true,
+            Some(b'\r') => self.input.get(self.current.end + 1) == Some(&b'\n'),
+            _ => false,
+        }
+    }
+
+    /// Ends a line continuation, whose `$` and line ending have been
+    /// consumed, after the indentation of the next line.
+    fn continue_line(&mut self) {
+        while let Some(b' ') = self.peek() {
+            self.advance()
+        }
+        self.commit(PieceKind::Continuation);
+    }
+
+    /// Skips spaces and escaped newlines. Like ninja, an escaped space is
+    /// not whitespace but the start of the next value.
     fn skip_whitespace(&mut self) -> Result<(), LexError> {
         loop {
             match self.peek() {
-                Some(b' ') => self.advance(),
+                Some(b' ') => {
+                    while let Some(b' ') = self.peek() {
+                        self.advance()
+                    }
+                    self.commit(PieceKind::Whitespace);
+                }
                 Some(b'$') => {
                     let dollar = self.current.end;
                     self.advance();
-                    match self.peek() {
-                        Some(b'\n') => self.advance(),
-                        Some(b' ') => self.advance(),
-                        Some(b'\r') => {
-                            self.advance();
-                            match self.peek() {
-                                Some(b'\n') => self.advance(),
-                                _ => {
-                                    self.current.end = dollar;
-                                    break;
-                                }
-                            }
-                        }
-                        _ => {
-                            self.current.end = dollar;
-                            break;
-                        }
+                    if self.peek() == Some(b'\r') {
+                        self.advance();
+                    }
+                    if self.peek() != Some(b'\n') {
+                        self.current.end = dollar;
+                        break;
                     }
+                    self.advance();
+                    self.continue_line();
                 }
                 _ => break,
             }
         }
-        self.start_next_token();
         Ok(())
     }
 }
@@ -470,34 +788,46 @@ mod tests {
 
     #[test]
     fn construction() {
-        let _lexer = Lexer::new(b"");
+        let _lexer = Lexer::new(b"build.ninja", b"");
     }
 
     #[test]
     fn empty() {
-        let mut lexer = Lexer::new(b"");
+        let mut lexer = Lexer::new(b"build.ninja", b"");
         let tokens = lex_all(&mut lexer).expect("failed to lex empty string");
         assert_eq!(tokens, vec![]);
     }
 
     #[test]
     fn bare_dollar_sign() {
-        let mut lexer = Lexer::new(b"$");
+        let mut lexer = Lexer::new(b"build.ninja", b"$");
         match lexer.lex() {
-            Err(LexError::UnknownToken) => (),
+            Err(LexError::UnknownToken { .. }) => (),
             _ => panic!("incorrectly lexed a bare dollar sign"),
         }
     }
 
     #[test]
     fn cr_without_newline() {
-        let mut lexer = Lexer::new(b"\r");
+        let mut lexer = Lexer::new(b"build.ninja", b"\r");
         match lex_all(&mut lexer) {
-            Err(LexError::UnknownToken) => (),
+            Err(LexError::UnknownToken { .. }) => (),
             _ => panic!("incorrectly lexed a carriage return"),
         }
     }
 
+    #[test]
+    fn indented_comment() {
+        let mut lexer = Lexer::new(b"build.ninja", b"  # comment\n  x\n");
+        let tokens = lex_all(&mut lexer).expect("failed to lex");
+        let kinds = tokens.iter().map(|token| token.kind()).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![TokenKind::Indent, TokenKind::Identifier, TokenKind::Newline]
+        );
+        assert_eq!(tokens[1].location.line, 2);
+    }
+
     #[test]
     fn unknown_characters() {
         for unknown in [
@@ -506,17 +836,94 @@ mod tests {
         ]
         .iter()
         {
-            let mut lexer = Lexer::new(*unknown);
+            let mut lexer = Lexer::new(b"build.ninja", *unknown);
             match lexer.lex() {
-                Err(LexError::UnknownToken) => (),
+                Err(LexError::UnknownToken { .. }) => (),
                 _ => panic!("incorrectly lexed an invalid character"),
             }
         }
     }
 
+    #[test]
+    fn lossless() {
+        let input = b"x = a$ $y # b$\n  c\n  # comment\nbuild o: r $\n    i ~\n";
+        let mut lexer = Lexer::lossless(b"build.ninja", input);
+        let mut arena = intern::Table::new();
+        let take = |lexer: &mut Lexer| {
+            lexer
+                .take_pieces()
+                .into_iter()
+                .map(|piece| (piece.kind(), String::from_utf8_lossy(&input[piece.range()])))
+                .collect::<Vec<_>>()
+        };
+        lexer.lex().expect("failed to lex");
+        lexer.lex().expect("failed to lex");
+        lexer.lex_value(&mut arena).expect("failed to lex value");
+        lexer.lex().expect("failed to lex");
+        let pieces = take(&mut lexer);
+        assert_eq!(
+            pieces,
+            vec![
+                (PieceKind::Token(TokenKind::Identifier), "x".into()),
+                (PieceKind::Whitespace, " ".into()),
+                (PieceKind::Token(TokenKind::Equal), "=".into()),
+                (PieceKind::Whitespace, " ".into()),
+                (PieceKind::Text, "a".into()),
+                (PieceKind::Escape, "$ ".into()),
+                (PieceKind::Variable, "$y".into()),
+                (PieceKind::Text, " # b".into()),
+                (PieceKind::Continuation, "$\n  ".into()),
+                (PieceKind::Text, "c".into()),
+                (PieceKind::Token(TokenKind::Newline), "\n".into()),
+            ]
+        );
+
+        while let Ok(Some(_)) = lexer.lex() {}
+        lexer.skip_line();
+        lexer.lex().expect("failed to lex");
+        let pieces = take(&mut lexer);
+        let kinds = pieces.iter().map(|(kind, _)| *kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                PieceKind::Token(TokenKind::Indent),
+                PieceKind::Token(TokenKind::Comment),
+                PieceKind::Token(TokenKind::Newline),
+                PieceKind::Token(TokenKind::Identifier),
+                PieceKind::Whitespace,
+                PieceKind::Token(TokenKind::Identifier),
+                PieceKind::Token(TokenKind::Colon),
+                PieceKind::Whitespace,
+                PieceKind::Token(TokenKind::Identifier),
+                PieceKind::Whitespace,
+                PieceKind::Continuation,
+                PieceKind::Token(TokenKind::Identifier),
+                PieceKind::Whitespace,
+                PieceKind::Error,
+                PieceKind::Token(TokenKind::Newline),
+            ]
+        );
+        let text = pieces
+            .iter()
+            .map(|(_, text)| text.as_ref())
+            .collect::<String>();
+        assert_eq!(text, "  # comment\nbuild o: r $\n    i ~\n");
+    }
+
+    #[test]
+    fn pipes() {
+        let mut lexer = Lexer::new(b"build.ninja", b"| || |@");
+        let tokens = lex_all(&mut lexer).expect("failed to lex pipes");
+        let kinds = tokens.iter().map(|token| token.kind()).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![TokenKind::Pipe, TokenKind::PipePipe, TokenKind::PipeAt]
+        );
+    }
+
     #[test]
     fn one_line() {
-        let mut lexer = Lexer::new(b"builddir = b\n");
+        let mut lexer = Lexer::new(b"build.ninja", b"builddir = b\n");
         let tokens = lex_all(&mut lexer).expect("failed to lex");
         assert_eq!(
             tokens,
@@ -555,7 +962,7 @@ mod tests {
 
     #[test]
     fn escaped_newline() {
-        let mut lexer = Lexer::new(b"builddir = $\n    b\n");
+        let mut lexer = Lexer::new(b"build.ninja", b"builddir = $\n    b\n");
         let tokens = lex_all(&mut lexer).expect("failed to lex");
         assert_eq!(
             tokens,
@@ -594,7 +1001,7 @@ mod tests {
 
     #[test]
     fn escaped_cr_newline() {
-        let mut lexer = Lexer::new(b"builddir = $\r\nb\n");
+        let mut lexer = Lexer::new(b"build.ninja", b"builddir = $\r\nb\n");
         let tokens = lex_all(&mut lexer).expect("failed to lex");
         assert_eq!(
             tokens,
@@ -633,7 +1040,7 @@ mod tests {
 
     #[test]
     fn multiple_lines() {
-        let mut lexer = Lexer::new(b"a\na\na\na\n");
+        let mut lexer = Lexer::new(b"build.ninja", b"a\na\na\na\n");
         let tokens = lex_all(&mut lexer).expect("failed to lex");
         assert_eq!(
             tokens,
@@ -701,7 +1108,7 @@ mod tests {
     #[test]
     fn one_line_value() {
         let mut arena = intern::Table::new();
-        let mut lexer = Lexer::new(b"builddir = $BUILDDIR\n");
+        let mut lexer = Lexer::new(b"build.ninja", b"builddir = $BUILDDIR\n");
 
         let identifier = lexer
             .lex_decl()
@@ -734,7 +1141,7 @@ mod tests {
     fn build() {
         let mut arena = intern::Table::new();
 
-        let mut lexer = Lexer::new(b"build output1 output2 | implicit_output1 implicit_output2: rulename input1 input2 | implicit_input1 implicit_input2 || order_input1 order_input2\n");
+        let mut lexer = Lexer::new(b"build.ninja", b"build output1 output2 | implicit_output1 implicit_output2: rulename input1 input2 | implicit_input1 implicit_input2 || order_input1 order_input2\n");
 
         let build = lexer
             .lex_decl()
@@ -839,7 +1246,7 @@ mod tests {
         let mut arena = intern::Table::new();
         let inputs: &[&blob::View] = &[b"${my_variable}\n", b"$my_variable\n"];
         for input in inputs.iter() {
-            let mut lexer = Lexer::new(input);
+            let mut lexer = Lexer::new(b"build.ninja", input);
             let value = lexer
                 .lex_value(&mut arena)
                 .expect("failed to lex value")
@@ -853,7 +1260,7 @@ mod tests {
         let mut arena = intern::Table::new();
         let inputs: &[&blob::View] = &[b"${my_variable}\n", b"$my_variable\n"];
         for input in inputs.iter() {
-            let mut lexer = Lexer::new(input);
+            let mut lexer = Lexer::new(b"build.ninja", input);
             let value = lexer
                 .lex_target(&mut arena)
                 .expect("failed to lex target")
@@ -861,4 +1268,43 @@ mod tests {
             assert_eq!(value.parts.len(), 1);
         }
     }
+
+    #[test]
+    fn escaped_space_is_not_whitespace() {
+        let text = |value: &Value| {
+            let mut text = vec![];
+            for part in value.parts.iter() {
+                match part {
+                    ValuePart::Text(blob) => text.extend_from_slice(blob.view()),
+                    ValuePart::Variable(_) => panic!("unexpected variable"),
+                }
+            }
+            text
+        };
+        let mut arena = intern::Table::new();
+
+        let mut lexer = Lexer::new(b"build.ninja", b"x = $ a\n");
+        let kinds = [TokenKind::Identifier, TokenKind::Equal];
+        for kind in kinds.iter() {
+            let token = lexer.lex().expect("failed to lex").expect("no token");
+            assert_eq!(token.kind(), *kind);
+        }
+        let value = lexer
+            .lex_value(&mut arena)
+            .expect("failed to lex value")
+            .expect("failed to lex value");
+        assert_eq!(text(&value), b" a");
+
+        let mut lexer = Lexer::new(b"build.ninja", b"a $ b\n");
+        let first = lexer
+            .lex_target(&mut arena)
+            .expect("failed to lex target")
+            .expect("failed to lex target");
+        let second = lexer
+            .lex_target(&mut arena)
+            .expect("failed to lex target")
+            .expect("failed to lex target");
+        assert_eq!(text(&first), b"a");
+        assert_eq!(text(&second), b" b");
+    }
 }
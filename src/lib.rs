@@ -1,11 +1,176 @@
-// For now, at least, allow dead code and unnecessary type wraps while
-// we build things out.
-#![allow(dead_code)]
-#![cfg_attr(feature = "cargo-clippy", allow(clippy::unnecessary_wraps))]
-
-mod arena;
-mod ast;
-mod blob;
-mod intern;
+//! Tools for working with ninja build files.
+//!
+//! The simplest entry points are [`parse_file`] and [`parse_bytes`],
+//! which produce an [`ast::File`]. Names in the file are interned in an
+//! [`intern::Table`], and can be recovered with [`Identifier::name`].
+//! Tools that rewrite build files can use [`cst::parse`] instead, which
+//! keeps comments and formatting. [`fmt::format`] prints a file
+//! in a canonical style, and [`writer::Writer`] generates new files.
+//! [`graph::Graph`] links the declarations of a file together, and
+//! [`dirty::Dirty`] works out which of its edges are out of date, using
+//! the commands recorded in a [`log::Log`] and the dependencies recorded
+//! in a [`deps_log::DepsLog`] or listed in depfiles read by
+//! [`depfile::load`]. [`exec::Builder`] runs the commands of dirty
+//! edges, sharing job slots with make through a [`jobserver`] if asked
+//! to.
+
+pub mod arena;
+pub mod ast;
+pub mod blob;
+mod canon;
+pub mod cst;
+pub mod depfile;
+pub mod deps_log;
+pub mod dirty;
+pub mod exec;
+pub mod fmt;
+pub mod graph;
+pub mod intern;
+pub mod jobserver;
 mod lex;
-mod parse;
+pub mod log;
+mod msvc;
+pub mod parse;
+#[cfg(test)]
+mod testing;
+pub mod writer;
+
+pub use lex::{Identifier, LexError, Location, ValuePart};
+
+/// The ways [`parse_file`] and [`parse_bytes`] can fail.
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Parse(parse::ParseError),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Io(error) => error.fmt(f),
+            Error::Parse(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            Error::Parse(error) => Some(error),
+        }
+    }
+}
+
+/// Parses the ninja file at `path`. Like ninja, files named by `include`
+/// and `subninja` are resolved relative to the current directory, as are
+/// the paths in the file, rather than to the directory containing it.
+pub fn parse_file(arena: &mut intern::Table, path: &std::path::Path) -> Result<ast::File, Error> {
+    let input = match std::fs::read(path) {
+        Ok(input) => input,
+        Err(error) => return Err(Error::Io(error)),
+    };
+    let name = blob::from_path(path);
+    let mut loader = parse::DiskLoader::new(std::path::PathBuf::new());
+    let mut parser = parse::Parser::new(&name, &input);
+    match parser.parse(arena, &mut loader) {
+        Ok(file) => Ok(file),
+        Err(error) => Err(Error::Parse(error)),
+    }
+}
+
+/// Parses a ninja file held in memory. Files named by `include` and
+/// `subninja` are resolved relative to the current directory.
+pub fn parse_bytes(arena: &mut intern::Table, input: &blob::View) -> Result<ast::File, Error> {
+    let mut loader = parse::DiskLoader::new(std::path::PathBuf::new());
+    let mut parser = parse::Parser::new(b"build.ninja", input);
+    match parser.parse(arena, &mut loader) {
+        Ok(file) => Ok(file),
+        Err(error) => Err(Error::Parse(error)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accessors() {
+        let mut arena = intern::Table::new();
+        let ninja = b"pool link\n    depth = 2\n\
+                      rule cc\n    command = cc $in -o $out\n\
+                      build a.o | a.d : cc a.c | a.h || gen\n    pool = link\n\
+                      default a.o\n";
+        let file = parse_bytes(&mut arena, ninja).expect("failed to parse");
+        let scopes = file.scopes();
+
+        let rule = file.rules().next().expect("no rule");
+        assert_eq!(rule.name().name(&arena), b"cc");
+
+        let build = file.builds().next().expect("no build");
+        assert_eq!(build.rule().name(&arena), b"cc");
+        let path = |target: &ast::Target| target.evaluate(scopes, build.scope());
+        assert_eq!(path(&build.outputs()[0]).view(), b"a.o");
+        assert_eq!(path(&build.implicit_outputs()[0]).view(), b"a.d");
+        assert_eq!(path(&build.inputs()[0]).view(), b"a.c");
+        assert_eq!(path(&build.implicit_inputs()[0]).view(), b"a.h");
+        assert_eq!(path(&build.order_inputs()[0]).view(), b"gen");
+        let (name, value) = scopes
+            .get_scope(build.scope())
+            .bindings()
+            .next()
+            .expect("no binding");
+        assert_eq!(name.name(&arena), b"pool");
+        assert_eq!(value, b"link");
+
+        let pool = file.pools().next().expect("no pool");
+        assert_eq!(pool.name().name(&arena), b"link");
+        assert_eq!(pool.depth(), 2);
+
+        let default = file.defaults().next().expect("no default");
+        assert_eq!(default.targets().len(), 1);
+    }
+
+    #[test]
+    fn parse_file_includes_relative_to_current_directory() {
+        let dir = testing::temp_dir("parse-file");
+        // Tests cannot change the current directory, so name the included
+        // file by its absolute path; a relative one would be looked up in
+        // the directory the tests run in.
+        let include = format!("include {}\n", dir.join("rules.ninja").display());
+        std::fs::write(dir.join("build.ninja"), include).expect("failed to write build.ninja");
+        std::fs::write(dir.join("rules.ninja"), "rule cc\n    command = cc\n")
+            .expect("failed to write rules.ninja");
+
+        std::fs::write(dir.join("relative.ninja"), "include rules.ninja\n")
+            .expect("failed to write relative.ninja");
+
+        let mut arena = intern::Table::new();
+        let result = parse_file(&mut arena, &dir.join("build.ninja"));
+        let relative = parse_file(&mut arena, &dir.join("relative.ninja"));
+        std::fs::remove_dir_all(&dir).expect("failed to remove directory");
+
+        let file = result.expect("failed to parse");
+        assert_eq!(file.rules().count(), 1);
+        assert!(relative.is_err());
+    }
+
+    #[test]
+    fn parse_file_missing() {
+        let mut arena = intern::Table::new();
+        let path = std::path::Path::new("/nonexistent/build.ninja");
+        match parse_file(&mut arena, path) {
+            Err(Error::Io(_)) => (),
+            _ => panic!("parsed a missing file"),
+        }
+    }
+
+    #[test]
+    fn parse_bytes_error() {
+        let mut arena = intern::Table::new();
+        match parse_bytes(&mut arena, b"build out\n") {
+            Err(Error::Parse(error)) => assert_eq!(error.location().line(), 1),
+            _ => panic!("parsed an invalid file"),
+        }
+    }
+}
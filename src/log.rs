@@ -0,0 +1,435 @@
+//! Reading and writing `.ninja_log`, ninja's record of the commands it
+//! has run.
+//!
+//! The log starts with a `# ninja log vN` line, followed by one line per
+//! output built: start and end times in milliseconds, the output's
+//! modification time, its path, and a hash of the command, separated by
+//! tabs. Entries are only ever appended, so a later entry for an output
+//! replaces earlier ones until the log is recompacted.
+
+use crate::blob;
+use crate::blob::Blob;
+use crate::dirty;
+use crate::dirty::Timestamp;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+/// The oldest log version that records command hashes.
+pub const OLDEST_VERSION: u32 = 5;
+/// The version that new logs are written with.
+pub const CURRENT_VERSION: u32 = 6;
+
+const SIGNATURE: &[u8] = b"# ninja log v";
+
+/// Below this many entries the log is never recompacted.
+const MIN_COMPACTION_ENTRIES: usize = 100;
+/// How many entries per distinct output the log may hold before it is
+/// recompacted.
+const COMPACTION_RATIO: usize = 3;
+
+#[derive(Debug)]
+pub enum LogError {
+    Io(io::Error),
+}
+
+impl fmt::Display for LogError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogError::Io(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for LogError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LogError::Io(error) => Some(error),
+        }
+    }
+}
+
+/// How one output was last built.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub output: Blob,
+    pub command_hash: u64,
+    /// When the command started, in milliseconds since the build began.
+    pub start: u32,
+    /// When the command finished, in milliseconds since the build began.
+    pub end: u32,
+    pub mtime: Timestamp,
+}
+
+/// Hashes a command line as ninja does, with MurmurHash64A.
+pub fn hash_command(command: &blob::View) -> u64 {
+    const SEED: u64 = 0xDECA_FBAD_DECA_FBAD;
+    const M: u64 = 0xc6a4_a793_5bd1_e995;
+    const R: u32 = 47;
+
+    let mut h = SEED ^ (command.len() as u64).wrapping_mul(M);
+    let mut chunks = command.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(chunk);
+        let mut k = u64::from_le_bytes(bytes);
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (index, byte) in tail.iter().enumerate() {
+            h ^= u64::from(*byte) << (8 * index);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+/// The contents of a build log, and optionally the file it is being
+/// appended to.
+#[derive(Default)]
+pub struct Log {
+    entries: HashMap<Blob, Entry>,
+    /// The number of entries read, including ones later replaced.
+    total: usize,
+    version: Option<u32>,
+    file: Option<std::fs::File>,
+}
+
+impl Log {
+    pub fn new() -> Log {
+        Log::default()
+    }
+
+    /// Parses the contents of a log. Lines without every field, such as
+    /// one cut short when a build was interrupted, are skipped. Like
+    /// ninja, a log of an unsupported version, or without a header, is
+    /// read as empty, and needs recompaction to start it over.
+    pub fn parse(input: &blob::View) -> Log {
+        let mut lines = input.split(|b| *b == b'\n');
+        // A missing or garbled header reads as version 0, which no ninja
+        // has written.
+        let version = lines
+            .next()
+            .and_then(|header| header.strip_prefix(SIGNATURE))
+            .and_then(|version| parse_number(version, 10))
+            .map_or(0, |version| version as u32);
+        let mut log = Log::new();
+        log.version = Some(version);
+        if !(OLDEST_VERSION..=CURRENT_VERSION).contains(&version) {
+            return log;
+        }
+        for line in lines {
+            if let Some(entry) = parse_entry(line) {
+                log.total += 1;
+                log.entries.insert(entry.output.clone(), entry);
+            }
+        }
+        log
+    }
+
+    /// Reads the log at `path`. A missing log is empty.
+    pub fn load(path: &Path) -> Result<Log, LogError> {
+        match std::fs::read(path) {
+            Ok(contents) => Ok(Log::parse(&contents)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Log::new()),
+            Err(error) => Err(LogError::Io(error)),
+        }
+    }
+
+    pub fn lookup(&self, output: &blob::View) -> Option<&Entry> {
+        self.entries.get(output)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.values()
+    }
+
+    /// Whether the log was written by another version, or has grown
+    /// with enough superseded entries to be worth rewriting.
+    pub fn needs_recompaction(&self) -> bool {
+        match self.version {
+            Some(version) if version != CURRENT_VERSION => true,
+            _ => {
+                self.total > MIN_COMPACTION_ENTRIES
+                    && self.total > self.entries.len() * COMPACTION_RATIO
+            }
+        }
+    }
+
+    /// Starts appending new entries to `path`, writing a header if the
+    /// file is new.
+    pub fn open(&mut self, path: &Path) -> io::Result<()> {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        if file.metadata()?.len() == 0 {
+            write_header(&mut file)?;
+        }
+        self.file = Some(file);
+        Ok(())
+    }
+
+    /// Records that `entry.output` has been built, appending it to the
+    /// log file if one is open.
+    pub fn record(&mut self, entry: Entry) -> io::Result<()> {
+        if let Some(file) = &mut self.file {
+            write_entry(file, &entry)?;
+        }
+        self.total += 1;
+        self.entries.insert(entry.output.clone(), entry);
+        Ok(())
+    }
+
+    /// Writes the current version of the log, with one entry for each
+    /// output that `keep` accepts.
+    pub fn write(&self, out: &mut dyn Write, keep: &dyn Fn(&blob::View) -> bool) -> io::Result<()> {
+        write_header(out)?;
+        let mut entries = self.entries.values().collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.output.cmp(&b.output));
+        for entry in entries {
+            if keep(&entry.output) {
+                write_entry(out, entry)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrites the log at `path` without superseded entries or outputs
+    /// that `keep` rejects, and continues appending to the new file.
+    pub fn recompact(&mut self, path: &Path, keep: &dyn Fn(&blob::View) -> bool) -> io::Result<()> {
+        self.file = None;
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".recompact");
+        let temp = Path::new(&temp);
+
+        let mut out = io::BufWriter::new(std::fs::File::create(temp)?);
+        self.write(&mut out, keep)?;
+        out.flush()?;
+        drop(out);
+        std::fs::rename(temp, path)?;
+
+        self.entries.retain(|output, _| keep(output));
+        self.total = self.entries.len();
+        self.version = Some(CURRENT_VERSION);
+        self.open(path)
+    }
+}
+
+impl dirty::History for Log {
+    fn mtime(&self, output: &blob::View) -> Option<Timestamp> {
+        self.lookup(output).map(|entry| entry.mtime)
+    }
+
+    fn command_changed(&self, output: &blob::View, command: &blob::View) -> bool {
+        match self.lookup(output) {
+            Some(entry) => entry.command_hash != hash_command(command),
+            None => true,
+        }
+    }
+}
+
+fn parse_number(field: &blob::View, radix: u32) -> Option<u64> {
+    let field = std::str::from_utf8(field).ok()?;
+    u64::from_str_radix(field, radix).ok()
+}
+
+fn parse_entry(line: &blob::View) -> Option<Entry> {
+    let mut fields = line.splitn(5, |b| *b == b'\t');
+    let start = parse_number(fields.next()?, 10)?;
+    let end = parse_number(fields.next()?, 10)?;
+    let mtime = parse_number(fields.next()?, 10)?;
+    let output = fields.next()?;
+    let command_hash = parse_number(fields.next()?, 16)?;
+    Some(Entry {
+        output: Blob::new(output),
+        command_hash,
+        start: start as u32,
+        end: end as u32,
+        mtime,
+    })
+}
+
+fn write_header(out: &mut dyn Write) -> io::Result<()> {
+    out.write_all(SIGNATURE)?;
+    writeln!(out, "{}", CURRENT_VERSION)
+}
+
+fn write_entry(out: &mut dyn Write, entry: &Entry) -> io::Result<()> {
+    let mut line = format!("{}\t{}\t{}\t", entry.start, entry.end, entry.mtime).into_bytes();
+    line.extend_from_slice(&entry.output);
+    line.extend_from_slice(format!("\t{:x}\n", entry.command_hash).as_bytes());
+    out.write_all(&line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dirty::History;
+    use crate::testing;
+
+    fn entry(output: &str, command: &str, mtime: Timestamp) -> Entry {
+        Entry {
+            output: Blob::new(output.as_bytes()),
+            command_hash: hash_command(command.as_bytes()),
+            start: 1,
+            end: 2,
+            mtime,
+        }
+    }
+
+    #[test]
+    fn murmur_hash() {
+        assert_eq!(hash_command(b""), 0x87c2_bc0b_eaf1_d91d);
+        assert_eq!(hash_command(b"a"), 0x90fc_b1ac_a689_663e);
+        assert_eq!(hash_command(b"command"), 0xc34a_d961_9fad_4845);
+        assert_eq!(hash_command(b"cc a.c -o a.o"), 0xf782_8973_4f4f_d0f9);
+        assert_eq!(hash_command(b"0123456789abcdef0"), 0x1996_507e_c1e8_2cfc);
+    }
+
+    #[test]
+    fn parse() {
+        let input = b"# ninja log v5\n\
+                      1\t5\t100\tout\t1234abcd\n\
+                      2\t6\t200\tother out\tff\n\
+                      7\t9\t300\tout\tabc\n";
+        let log = Log::parse(input);
+        assert_eq!(log.entries().count(), 2);
+        let out = log.lookup(b"out").expect("no out");
+        assert_eq!((out.start, out.end, out.mtime), (7, 9, 300));
+        assert_eq!(out.command_hash, 0xabc);
+        assert_eq!(
+            log.lookup(b"other out").expect("no other").command_hash,
+            0xff
+        );
+        assert!(log.needs_recompaction());
+    }
+
+    #[test]
+    fn truncated() {
+        let input = b"# ninja log v6\n1\t5\t100\tout\t1234abcd\n2\t6\t20";
+        let log = Log::parse(input);
+        assert_eq!(log.entries().count(), 1);
+        assert!(log.lookup(b"out").is_some());
+        assert!(!log.needs_recompaction());
+    }
+
+    #[test]
+    fn versions() {
+        let unsupported: &[&blob::View] = &[
+            b"# ninja log v4\n1\t5\t100\tout\n",
+            b"# ninja log v7\n1\t5\t100\tout\t1234abcd\n",
+            b"",
+            b"1\t5\t100\tout\t1234abcd\n",
+            b"# ninja log vX\n1\t5\t100\tout\t1234abcd\n",
+        ];
+        for input in unsupported.iter() {
+            let log = Log::parse(input);
+            assert_eq!(log.entries().count(), 0);
+            assert!(log.needs_recompaction());
+        }
+    }
+
+    #[test]
+    fn history() {
+        let mut log = Log::new();
+        log.record(entry("out", "cc in", 5)).unwrap();
+        assert_eq!(log.mtime(b"out"), Some(5));
+        assert!(!log.command_changed(b"out", b"cc in"));
+        assert!(log.command_changed(b"out", b"cc -g in"));
+        assert!(log.command_changed(b"missing", b"cc in"));
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = testing::temp_dir("log-round-trip");
+        let path = dir.join(".ninja_log");
+        let mut log = Log::new();
+        log.open(&path).unwrap();
+        log.record(entry("a", "cc a", 1)).unwrap();
+        log.record(entry("b", "cc b", 2)).unwrap();
+        log.record(entry("a", "cc -g a", 3)).unwrap();
+        drop(log);
+
+        let mut log = Log::load(&path).expect("failed to load");
+        assert_eq!(log.entries().count(), 2);
+        assert_eq!(log.lookup(b"a"), Some(&entry("a", "cc -g a", 3)));
+        assert_eq!(log.lookup(b"b"), Some(&entry("b", "cc b", 2)));
+
+        log.open(&path).unwrap();
+        log.record(entry("c", "cc c", 4)).unwrap();
+        drop(log);
+        let contents = std::fs::read(&path).unwrap();
+        assert!(contents.starts_with(b"# ninja log v6\n1\t2\t1\ta\t"));
+        assert_eq!(contents.iter().filter(|b| **b == b'\n').count(), 5);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_log() {
+        let dir = testing::temp_dir("log-missing");
+        let log = Log::load(&dir.join(".ninja_log")).expect("failed to load");
+        assert_eq!(log.entries().count(), 0);
+        assert!(!log.needs_recompaction());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn empty_file() {
+        let dir = testing::temp_dir("log-empty");
+        let path = dir.join(".ninja_log");
+        std::fs::write(&path, b"").unwrap();
+        let mut log = Log::load(&path).expect("failed to load");
+        assert!(log.needs_recompaction());
+        log.recompact(&path, &|_| true).unwrap();
+        log.record(entry("a", "cc a", 1)).unwrap();
+        drop(log);
+
+        let log = Log::load(&path).expect("failed to load");
+        assert!(!log.needs_recompaction());
+        assert_eq!(log.lookup(b"a"), Some(&entry("a", "cc a", 1)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recompact() {
+        let dir = testing::temp_dir("log-recompact");
+        let path = dir.join(".ninja_log");
+        let mut log = Log::new();
+        log.open(&path).unwrap();
+        for mtime in 0..MIN_COMPACTION_ENTRIES as Timestamp {
+            log.record(entry("a", "cc a", mtime)).unwrap();
+            log.record(entry("stale", "cc stale", mtime)).unwrap();
+        }
+        drop(log);
+
+        let mut log = Log::load(&path).expect("failed to load");
+        assert!(log.needs_recompaction());
+        log.recompact(&path, &|output| output != b"stale").unwrap();
+        assert!(!log.needs_recompaction());
+        log.record(entry("b", "cc b", 1)).unwrap();
+        drop(log);
+
+        let contents = std::fs::read(&path).unwrap();
+        let last = MIN_COMPACTION_ENTRIES - 1;
+        let expected = format!(
+            "# ninja log v6\n1\t2\t{}\ta\t{:x}\n1\t2\t1\tb\t{:x}\n",
+            last,
+            hash_command(b"cc a"),
+            hash_command(b"cc b")
+        );
+        assert_eq!(String::from_utf8_lossy(&contents), expected);
+        assert!(!dir.join(".ninja_log.recompact").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
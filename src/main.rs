@@ -0,0 +1,293 @@
+use ninja_rs::blob;
+use ninja_rs::exec;
+use ninja_rs::graph::{Edge, Graph};
+use ninja_rs::jobserver;
+use std::io::Write;
+use std::process;
+
+fn usage() -> ! {
+    eprintln!("usage: ninja-rs fmt [--width N] [FILE]");
+    eprintln!(
+        "       ninja-rs build [-f FILE] [-j N] [-k] [-l N] [--jobserver[=fifo|pipe]] [TARGET...]"
+    );
+    process::exit(2)
+}
+
+fn fail(error: &dyn std::fmt::Display) -> ! {
+    eprintln!("ninja-rs: {}", error);
+    process::exit(1)
+}
+
+/// Prints `FILE`, or `build.ninja`, in canonical style.
+fn fmt(args: &[String]) {
+    let mut options = ninja_rs::fmt::Options::default();
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--width" => match args.next().map(|width| width.parse()) {
+                Some(Ok(width)) => options.width = width,
+                _ => usage(),
+            },
+            _ if arg.starts_with('-') => usage(),
+            _ if path.is_none() => path = Some(arg.as_str()),
+            _ => usage(),
+        }
+    }
+    let path = std::path::Path::new(path.unwrap_or("build.ninja"));
+
+    let input = match std::fs::read(path) {
+        Ok(input) => input,
+        Err(error) => fail(&error),
+    };
+    let formatted = match ninja_rs::fmt::format(&input, &options) {
+        Ok(formatted) => formatted,
+        Err(error) => fail(&format!("{}: {}", path.display(), error)),
+    };
+    if let Err(error) = std::io::stdout().write_all(&formatted) {
+        fail(&error)
+    }
+}
+
+/// Prints each command as it finishes, along with its output.
+struct Console<'a> {
+    graph: &'a Graph,
+    planned: usize,
+    finished: usize,
+}
+
+impl<'a> exec::Status for Console<'a> {
+    fn planned(&mut self, commands: usize) {
+        self.planned = commands;
+    }
+
+    fn started(&mut self, _: ninja_rs::arena::Id<Edge>) {}
+
+    fn finished(&mut self, edge: ninja_rs::arena::Id<Edge>, success: bool, output: &blob::View) {
+        self.finished += 1;
+        let edge = self.graph.edge(edge);
+        let mut out = std::io::stdout().lock();
+        let _ = write!(out, "[{}/{}] ", self.finished, self.planned);
+        if !success {
+            let _ = out.write_all(b"FAILED: ");
+            for output in edge.all_outputs() {
+                let _ = out.write_all(self.graph.node(output).path());
+                let _ = out.write_all(b" ");
+            }
+            let _ = out.write_all(b"\n");
+        }
+        let _ = out.write_all(edge.command());
+        let _ = out.write_all(b"\n");
+        let _ = out.write_all(output);
+    }
+}
+
+/// Brings `TARGET`s, or the defaults of `FILE`, up to date. As in ninja,
+/// every path, including those of included files, is relative to the
+/// current directory rather than to `FILE`'s. Unless `-j` is given, job
+/// slots are taken from the jobserver of a make running the build, if
+/// there is one. With `--jobserver`, the build starts a jobserver for its
+/// commands to share its slots, in a FIFO or, for makes older than 4.4,
+/// with `--jobserver=pipe`, in a pipe.
+fn build(args: &[String]) {
+    let mut options = exec::Options::default();
+    let mut jobs_given = false;
+    let mut serve = false;
+    let mut pipe = false;
+    let mut path = "build.ninja";
+    let mut requested = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-f" => match args.next() {
+                Some(file) => path = file.as_str(),
+                None => usage(),
+            },
+            "-j" => match args.next().map(|jobs| jobs.parse()) {
+                Some(Ok(jobs)) => {
+                    options.jobs = jobs;
+                    jobs_given = true;
+                }
+                _ => usage(),
+            },
+            "-k" => options.keep_going = true,
+            // Like ninja, a load of 0 or less means no limit.
+            "-l" => match args.next().map(|load| load.parse::<f64>()) {
+                Some(Ok(load)) => options.max_load = Some(load).filter(|load| *load > 0.0),
+                _ => usage(),
+            },
+            "--jobserver" | "--jobserver=fifo" => serve = true,
+            "--jobserver=pipe" => {
+                serve = true;
+                pipe = true;
+            }
+            _ if arg.starts_with('-') => usage(),
+            _ => requested.push(arg.as_str()),
+        }
+    }
+
+    // An inherited jobserver is connected to before any files are opened,
+    // which could otherwise be given the descriptors that it names.
+    let mut client = None;
+    if !serve && !jobs_given {
+        match jobserver::Client::from_env() {
+            Some(Ok(inherited)) => {
+                options.jobs = 0;
+                client = Some(inherited);
+            }
+            Some(Err(error)) => eprintln!("ninja-rs: warning: ignoring jobserver: {}", error),
+            None => (),
+        }
+    }
+
+    let mut arena = ninja_rs::intern::Table::new();
+    let file = match ninja_rs::parse_file(&mut arena, std::path::Path::new(path)) {
+        Ok(file) => file,
+        Err(error) => fail(&error),
+    };
+    let mut graph = match Graph::new(&mut arena, &file) {
+        Ok(graph) => graph,
+        Err(error) => fail(&error),
+    };
+    let file_system = ninja_rs::dirty::RealFileSystem;
+
+    if let Some(builddir) = file.builddir() {
+        if let Err(error) = std::fs::create_dir_all(blob::to_path(builddir)) {
+            fail(&error)
+        }
+    }
+    let log_path = blob::to_path(&file.state_path(ninja_rs::ast::NINJA_LOG));
+    let mut log = match ninja_rs::log::Log::load(&log_path) {
+        Ok(log) => log,
+        Err(error) => fail(&error),
+    };
+    let opened = if log.needs_recompaction() {
+        log.recompact(&log_path, &|output| is_built(&graph, output))
+    } else {
+        log.open(&log_path)
+    };
+    if let Err(error) = opened {
+        fail(&error)
+    }
+    let deps_path = blob::to_path(&file.state_path(ninja_rs::ast::NINJA_DEPS));
+    let mut deps_log = match ninja_rs::deps_log::DepsLog::load(&deps_path) {
+        Ok(deps_log) => deps_log,
+        Err(error) => fail(&error),
+    };
+    let opened = if deps_log.needs_recompaction() {
+        deps_log.recompact(&deps_path, &|output| has_deps(&graph, output))
+    } else {
+        deps_log.open(&deps_path)
+    };
+    if let Err(error) = opened {
+        fail(&error)
+    }
+
+    let mut targets = vec![];
+    for target in requested {
+        match graph.lookup(target.as_bytes()) {
+            Some(node) => targets.push(node),
+            None => fail(&format!("unknown target '{}'", target)),
+        }
+    }
+    if targets.is_empty() {
+        targets = match graph.defaults() {
+            [] => graph.roots(),
+            defaults => defaults.to_vec(),
+        };
+    }
+    let targets = graph.with_validations(&targets);
+    let dirty = match ninja_rs::dirty::Dirty::compute(
+        &mut graph,
+        &file_system,
+        Some(&log),
+        Some(&deps_log),
+        &targets,
+    ) {
+        Ok(dirty) => dirty,
+        Err(error) => fail(&error),
+    };
+
+    let mut runner = exec::ProcessRunner::new();
+    let mut server = None;
+    if serve {
+        if options.jobs == 0 {
+            usage()
+        }
+        let started = if pipe {
+            jobserver::Server::pipe(options.jobs)
+        } else {
+            let path = std::env::temp_dir().join(format!("ninja-rs-jobserver-{}", process::id()));
+            jobserver::Server::new(&path, options.jobs)
+        };
+        let started = match started {
+            Ok(started) => started,
+            Err(error) => fail(&error),
+        };
+        runner.set_env("MAKEFLAGS", &started.makeflags());
+        client = match started.client() {
+            Ok(client) => Some(client),
+            Err(error) => {
+                drop(started);
+                fail(&error)
+            }
+        };
+        server = Some(started);
+    }
+    let mut console = Console {
+        graph: &graph,
+        planned: 0,
+        finished: 0,
+    };
+    let mut builder = exec::Builder::new(&graph, &file_system, &mut runner, &mut console, options);
+    builder.set_log(&mut log);
+    builder.set_deps_log(&mut deps_log);
+    if let Some(client) = &mut client {
+        builder.set_jobserver(client);
+    }
+    let outcome = builder.build(&dirty, &targets);
+    // Exiting skips destructors, so the jobserver is dropped (removing any FIFO) first.
+    drop(client);
+    drop(server);
+    let outcome = match outcome {
+        Ok(outcome) => outcome,
+        Err(error) => fail(&error),
+    };
+    if !outcome.is_success() {
+        fail(&"build stopped: subcommand failed.")
+    }
+    if console.planned == 0 {
+        println!("ninja-rs: no work to do.");
+    }
+}
+
+/// Whether `output` is still built by an edge, so that its build log
+/// entry is worth keeping.
+fn is_built(graph: &Graph, output: &blob::View) -> bool {
+    match graph.lookup(output) {
+        Some(node) => graph.node(node).in_edge().is_some(),
+        None => false,
+    }
+}
+
+/// Whether `output` is still built by an edge that records its
+/// dependencies in the deps log.
+fn has_deps(graph: &Graph, output: &blob::View) -> bool {
+    let node = match graph.lookup(output) {
+        Some(node) => graph.node(node),
+        None => return false,
+    };
+    match node.in_edge() {
+        Some(edge) => graph.edge(edge).deps().is_some(),
+        None => false,
+    }
+}
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match args.first().map(|command| command.as_str()) {
+        Some("build") => build(&args[1..]),
+        Some("fmt") => fmt(&args[1..]),
+        _ => usage(),
+    }
+}
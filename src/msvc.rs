@@ -0,0 +1,313 @@
+//! Extracting dependencies from the output of the MSVC compiler, for
+//! rules with `deps = msvc`.
+//!
+//! Run with `/showIncludes`, `cl.exe` prints a line such as
+//! `Note: including file: c:\include\stdio.h` for every file it reads.
+//! Localized compilers use a different prefix, which rules give in
+//! `msvc_deps_prefix`. These lines are removed from the output shown to
+//! the user, as is the name of the source file that `cl.exe` echoes
+//! before compiling it. Like ninja's `IncludesNormalize`, absolute
+//! include paths are made relative to the build directory where they
+//! can be, so that they match the paths in the build file.
+
+use crate::blob;
+use crate::blob::Blob;
+use crate::canon;
+use std::collections::BTreeSet;
+
+/// The prefix that an English `cl.exe` uses.
+pub const DEFAULT_PREFIX: &blob::View = b"Note: including file: ";
+
+/// The compiler output, split into the files it included and the rest.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct ShowIncludes {
+    includes: BTreeSet<Blob>,
+    output: Vec<u8>,
+}
+
+impl ShowIncludes {
+    /// The canonical paths of the files that were included, relative to
+    /// the build directory where possible, in sorted order and without
+    /// system headers.
+    pub fn includes(&self) -> impl Iterator<Item = &blob::View> {
+        self.includes.iter().map(|include| include.view())
+    }
+
+    /// The output without the include notes, with lines ending in `\n`.
+    pub fn output(&self) -> &blob::View {
+        &self.output
+    }
+}
+
+/// Splits the output of `cl.exe /showIncludes`. `prefix` is the rule's
+/// `msvc_deps_prefix`, if it has one, and `build_dir` is the absolute
+/// path of the directory the command ran in.
+pub fn parse(
+    output: &blob::View,
+    prefix: Option<&blob::View>,
+    build_dir: &blob::View,
+) -> ShowIncludes {
+    let (build_dir, _) = canon::canonicalize_with(build_dir, true);
+    let prefix = prefix.unwrap_or(DEFAULT_PREFIX);
+    let mut parsed = ShowIncludes::default();
+    let mut seen_includes = false;
+    let mut rest = output;
+    while !rest.is_empty() {
+        let end = rest
+            .iter()
+            .position(|b| *b == b'\r' || *b == b'\n')
+            .unwrap_or(rest.len());
+        let line = &rest[..end];
+        rest = &rest[end..];
+        if let Some(b'\r') = rest.first() {
+            rest = &rest[1..];
+        }
+        if let Some(b'\n') = rest.first() {
+            rest = &rest[1..];
+        }
+
+        if let Some(include) = filter_include(line, prefix) {
+            seen_includes = true;
+            if !is_system_include(include) {
+                parsed.includes.insert(normalize(include, &build_dir));
+            }
+        } else if !seen_includes && is_source_file(line) {
+            // The echoed name of the file being compiled.
+        } else {
+            parsed.output.extend_from_slice(line);
+            parsed.output.push(b'\n');
+        }
+    }
+    parsed
+}
+
+/// The path in an include note, or `None` if `line` is not one.
+fn filter_include<'a>(line: &'a blob::View, prefix: &blob::View) -> Option<&'a blob::View> {
+    if line.len() <= prefix.len() {
+        return None;
+    }
+    let path = line.strip_prefix(prefix)?;
+    let start = path.iter().position(|b| *b != b' ')?;
+    Some(&path[start..])
+}
+
+/// Canonicalizes `include`, treating backslashes as separators, and makes
+/// it relative to the canonical `build_dir` if it is an absolute path on
+/// the same drive.
+fn normalize(include: &blob::View, build_dir: &blob::View) -> Blob {
+    let (include, _) = canon::canonicalize_with(include, true);
+    if !is_absolute(&include) || !is_absolute(build_dir) {
+        return include;
+    }
+    let path = components(&include);
+    let base = components(build_dir);
+    let drive = |components: &[&[u8]]| match components.first() {
+        Some(first) if first.ends_with(b":") => first.to_ascii_lowercase(),
+        _ => vec![],
+    };
+    if drive(&path) != drive(&base) {
+        return include;
+    }
+
+    let common = path
+        .iter()
+        .zip(base.iter())
+        .take_while(|(a, b)| a.eq_ignore_ascii_case(b))
+        .count();
+    let mut relative = vec![&b".."[..]; base.len() - common];
+    relative.extend_from_slice(&path[common..]);
+    if relative.is_empty() {
+        return Blob::new(b".");
+    }
+    Blob::from(relative.join(&b'/'))
+}
+
+fn components(path: &blob::View) -> Vec<&blob::View> {
+    path.split(|b| *b == b'/')
+        .filter(|component| !component.is_empty())
+        .collect()
+}
+
+/// Whether `path`, which has only forward slashes, starts at a root or
+/// drive letter.
+fn is_absolute(path: &blob::View) -> bool {
+    match path {
+        [b'/', ..] => true,
+        [drive, b':', b'/', ..] => drive.is_ascii_alphabetic(),
+        _ => false,
+    }
+}
+
+/// Whether `path` is in a compiler or SDK directory, which are left out
+/// of the dependencies since they rarely change. Like ninja, this is a
+/// guess based on the path.
+fn is_system_include(path: &blob::View) -> bool {
+    let path = path.to_ascii_lowercase();
+    let contains = |needle: &[u8]| path.windows(needle.len()).any(|window| window == needle);
+    contains(b"program files") || contains(b"microsoft visual studio")
+}
+
+/// Whether `line` looks like the name of a C or C++ source file.
+fn is_source_file(line: &blob::View) -> bool {
+    let line = line.to_ascii_lowercase();
+    [&b".c"[..], b".cc", b".cxx", b".cpp", b".c++"]
+        .iter()
+        .any(|extension| line.ends_with(extension))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn includes(parsed: &ShowIncludes) -> Vec<String> {
+        parsed
+            .includes()
+            .map(|include| String::from_utf8_lossy(include).into_owned())
+            .collect()
+    }
+
+    fn output(parsed: &ShowIncludes) -> String {
+        String::from_utf8_lossy(parsed.output()).into_owned()
+    }
+
+    #[test]
+    fn show_includes() {
+        let filter = |line: &str| {
+            filter_include(line.as_bytes(), DEFAULT_PREFIX)
+                .map(|path| String::from_utf8_lossy(path).into_owned())
+        };
+        assert_eq!(filter(""), None);
+        assert_eq!(filter("Sample compiler output"), None);
+        assert_eq!(
+            filter("Note: including file: c:\\Some Files\\foobar.h").as_deref(),
+            Some("c:\\Some Files\\foobar.h")
+        );
+        assert_eq!(
+            filter("Note: including file:   c:\\initspaces.h").as_deref(),
+            Some("c:\\initspaces.h")
+        );
+        assert_eq!(filter("Note: including file: "), None);
+
+        let prefix = b"Non-default prefix: inc file:";
+        let line = b"Non-default prefix: inc file:    c:\\initspaces.h";
+        assert_eq!(filter_include(line, prefix), Some(&b"c:\\initspaces.h"[..]));
+    }
+
+    #[test]
+    fn source_files() {
+        assert!(is_source_file(b"foobar.cc"));
+        assert!(is_source_file(b"foo bar.cc"));
+        assert!(is_source_file(b"baz.c"));
+        assert!(is_source_file(b"FOOBAR.CC"));
+        assert!(!is_source_file(
+            b"src\\cl_helper.cc(166) : fatal error C1075: end of file found before the left brace"
+        ));
+    }
+
+    #[test]
+    fn simple() {
+        let parsed = parse(
+            b"foo\r\nNote: inc file prefix:  foo.h\r\nbar\r\n",
+            Some(b"Note: inc file prefix:"),
+            b"",
+        );
+        assert_eq!(output(&parsed), "foo\nbar\n");
+        assert_eq!(includes(&parsed), &["foo.h"]);
+    }
+
+    #[test]
+    fn localized_prefix() {
+        let text = "Remarque\u{a0}: inclusion du fichier\u{a0}:  c:/src/foo.h\r\n\
+                    Note: including file: c:/src/bar.h\r\n";
+        let prefix = "Remarque\u{a0}: inclusion du fichier\u{a0}:";
+        let parsed = parse(text.as_bytes(), Some(prefix.as_bytes()), b"");
+        assert_eq!(includes(&parsed), &["c:/src/foo.h"]);
+        assert_eq!(output(&parsed), "Note: including file: c:/src/bar.h\n");
+    }
+
+    #[test]
+    fn filename_filter() {
+        let parsed = parse(b"foo.cc\r\ncl: warning\r\n", None, b"");
+        assert_eq!(output(&parsed), "cl: warning\n");
+
+        let parsed = parse(
+            b"foo.cc\r\nNote: including file: foo.h\r\nsomething something foo.cc\r\n",
+            None,
+            b"",
+        );
+        assert_eq!(output(&parsed), "something something foo.cc\n");
+    }
+
+    #[test]
+    fn system_includes() {
+        let parsed = parse(
+            b"Note: including file: c:\\Program Files\\foo.h\r\n\
+              Note: including file: d:\\Microsoft Visual Studio\\bar.h\r\n\
+              Note: including file: path.h\r\n",
+            None,
+            b"",
+        );
+        assert_eq!(includes(&parsed), &["path.h"]);
+        assert_eq!(output(&parsed), "");
+    }
+
+    #[test]
+    fn duplicated_headers() {
+        let parsed = parse(
+            b"Note: including file: foo.h\r\n\
+              Note: including file: bar.h\r\n\
+              Note: including file: foo.h\r\n\
+              Note: including file: sub/./foo.h\r\n\
+              Note: including file: sub/foo.h\n",
+            None,
+            b"",
+        );
+        assert_eq!(includes(&parsed), &["bar.h", "foo.h", "sub/foo.h"]);
+    }
+
+    #[test]
+    fn unterminated_line() {
+        let parsed = parse(b"Note: including file: foo.h\nerror C2065", None, b"");
+        assert_eq!(includes(&parsed), &["foo.h"]);
+        assert_eq!(output(&parsed), "error C2065\n");
+    }
+
+    #[test]
+    fn relative_to_build_dir() {
+        let parsed = parse(
+            b"Note: including file: c:\\src\\gen\\foo.h\r\n\
+              Note: including file: C:\\SRC\\bar.h\r\n\
+              Note: including file: c:\\other\\baz.h\r\n\
+              Note: including file: d:\\lib\\qux.h\r\n\
+              Note: including file: sub\\.\\x.h\r\n",
+            None,
+            b"c:\\src",
+        );
+        assert_eq!(
+            includes(&parsed),
+            &[
+                "../other/baz.h",
+                "bar.h",
+                "d:/lib/qux.h",
+                "gen/foo.h",
+                "sub/x.h"
+            ]
+        );
+
+        let relative = |include: &str, build_dir: &str| {
+            let path = normalize(include.as_bytes(), build_dir.as_bytes());
+            String::from_utf8_lossy(&path).into_owned()
+        };
+        assert_eq!(
+            relative("/root/crate/gen/foo.h", "/root/crate"),
+            "gen/foo.h"
+        );
+        assert_eq!(relative("/root/crate", "/root/crate"), ".");
+        assert_eq!(
+            relative("/usr/include/x.h", "/home/me"),
+            "../../usr/include/x.h"
+        );
+        assert_eq!(relative("c:/src/foo.h", "/root/crate"), "c:/src/foo.h");
+        assert_eq!(relative("/abs/foo.h", ""), "/abs/foo.h");
+    }
+}
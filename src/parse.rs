@@ -3,112 +3,443 @@ use crate::ast;
 use crate::blob;
 use crate::intern;
 use crate::lex;
-use crate::lex::{DeclKind, LexError, Lexer, Token, TokenKind};
+use crate::lex::{DeclKind, LexError, Lexer, Location, Token, TokenKind};
+use std::fmt;
 
 #[derive(Debug)]
 pub enum ParseError {
     LexError(LexError),
-    AstError(ast::AstError),
-    MissingNewline,
-    UnexpectedToken { got: TokenKind },
-    UnexpectedEof,
-    InvalidValue,
-    PoolDepthInvalid,
-    Expected { expected: TokenKind, got: TokenKind },
+    AstError {
+        error: ast::AstError,
+        location: Location,
+    },
+    MissingNewline {
+        location: Location,
+    },
+    UnexpectedToken {
+        got: TokenKind,
+        location: Location,
+    },
+    UnexpectedEof {
+        location: Location,
+    },
+    InvalidValue {
+        location: Location,
+    },
+    PoolDepthInvalid {
+        location: Location,
+    },
+    Expected {
+        expected: TokenKind,
+        got: TokenKind,
+        location: Location,
+    },
+    LoadError {
+        path: blob::Blob,
+        error: std::io::Error,
+        location: Location,
+    },
+}
+
+impl ParseError {
+    pub fn location(&self) -> &Location {
+        match self {
+            ParseError::LexError(error) => error.location(),
+            ParseError::AstError { location, .. } => location,
+            ParseError::MissingNewline { location } => location,
+            ParseError::UnexpectedToken { location, .. } => location,
+            ParseError::UnexpectedEof { location } => location,
+            ParseError::InvalidValue { location } => location,
+            ParseError::PoolDepthInvalid { location } => location,
+            ParseError::Expected { location, .. } => location,
+            ParseError::LoadError { location, .. } => location,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let location = self.location();
+        match self {
+            ParseError::LexError(error) => error.fmt(f),
+            ParseError::AstError { error, .. } => {
+                location.report(f, error)?;
+                match error.previous() {
+                    Some(previous) => {
+                        writeln!(f)?;
+                        previous.report(f, &"previous definition")
+                    }
+                    None => Ok(()),
+                }
+            }
+            ParseError::MissingNewline { .. } => location.report(f, &"expected newline"),
+            ParseError::UnexpectedToken { got, .. } => {
+                location.report(f, &format_args!("unexpected {}", got))
+            }
+            ParseError::UnexpectedEof { .. } => location.report(f, &"unexpected EOF"),
+            ParseError::InvalidValue { .. } => location.report(f, &"expected a value"),
+            ParseError::PoolDepthInvalid { .. } => location.report(f, &"invalid pool depth"),
+            ParseError::Expected { expected, got, .. } => {
+                location.report(f, &format_args!("expected {}, got {}", expected, got))
+            }
+            ParseError::LoadError { path, error, .. } => location.report(
+                f,
+                &format_args!("loading '{}': {}", String::from_utf8_lossy(path), error),
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseError::LexError(error) => Some(error),
+            ParseError::LoadError { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Checks that the pool a build is in has been declared, as far as that
+/// can be known before the build's variables are evaluated. As in
+/// ninja, a pool must be declared before the builds that use it.
+fn check_pool(
+    build: &ast::Build,
+    declarations: &ast::Declarations,
+    scopes: &ast::Scopes,
+    arena: &mut intern::Table,
+) -> Result<(), ast::AstError> {
+    let identifier = lex::Identifier::new(arena, b"pool");
+    let pool = match scopes.get_scope(build.scope()).get(identifier) {
+        Some(pool) => pool,
+        None => match declarations
+            .find_rule(scopes, build)
+            .map(|rule| rule.pool())
+        {
+            Some(ast::Setting::Literal(Some(pool))) => pool,
+            _ => return Ok(()),
+        },
+    };
+    if pool.is_empty() || pool == ast::CONSOLE {
+        return Ok(());
+    }
+    match declarations.pool(lex::Identifier::new(arena, pool)) {
+        Some(_) => Ok(()),
+        None => {
+            let pool = blob::Blob::new(pool);
+            Err(ast::AstError::UnknownPool { pool })
+        }
+    }
+}
+
+/// Supplies the contents of files named by `include` and `subninja`
+/// declarations.
+pub trait Loader {
+    fn load(&mut self, path: &blob::View) -> std::io::Result<blob::Blob>;
+}
+
+/// Loads files from disk. Like ninja, paths are resolved relative to
+/// the build directory rather than to the including file.
+pub struct DiskLoader {
+    root: std::path::PathBuf,
+}
+
+impl DiskLoader {
+    pub fn new(root: std::path::PathBuf) -> DiskLoader {
+        DiskLoader { root }
+    }
+}
+
+impl Loader for DiskLoader {
+    fn load(&mut self, path: &blob::View) -> std::io::Result<blob::Blob> {
+        let path = self.root.join(blob::to_path(path));
+        let bytes = std::fs::read(path)?;
+        Ok(blob::Blob::from(bytes))
+    }
+}
+
+/// Loads files from memory, for tests and for tools that generate
+/// ninja files without writing them out.
+pub struct MemoryLoader {
+    files: std::collections::HashMap<blob::Blob, blob::Blob>,
+}
+
+impl MemoryLoader {
+    pub fn new() -> MemoryLoader {
+        let files = std::collections::HashMap::new();
+        MemoryLoader { files }
+    }
+
+    pub fn insert(&mut self, path: &blob::View, contents: &blob::View) {
+        self.files
+            .insert(blob::Blob::new(path), blob::Blob::new(contents));
+    }
+}
+
+impl Default for MemoryLoader {
+    fn default() -> MemoryLoader {
+        MemoryLoader::new()
+    }
+}
+
+impl Loader for MemoryLoader {
+    fn load(&mut self, path: &blob::View) -> std::io::Result<blob::Blob> {
+        match self.files.get(path) {
+            Some(contents) => Ok(contents.clone()),
+            None => Err(std::io::ErrorKind::NotFound.into()),
+        }
+    }
 }
 
 pub struct Parser<'input> {
     lexer: Lexer<'input>,
+    recover: bool,
+    errors: Vec<ParseError>,
 }
 
 impl<'input> Parser<'input> {
-    pub fn new(input: &'input blob::View) -> Parser {
-        let lexer = Lexer::new(input);
-        Parser { lexer }
+    pub fn new(file: &'input blob::View, input: &'input blob::View) -> Parser<'input> {
+        let lexer = Lexer::new(file, input);
+        Parser {
+            lexer,
+            recover: false,
+            errors: vec![],
+        }
+    }
+
+    /// Parses the whole input, stopping at the first error.
+    pub fn parse(
+        &mut self,
+        arena: &mut intern::Table,
+        loader: &mut dyn Loader,
+    ) -> Result<ast::File, ParseError> {
+        let file = self.parse_all(arena, loader);
+        match self.errors.pop() {
+            Some(error) => Err(error),
+            None => Ok(file),
+        }
+    }
+
+    /// Parses the whole input, skipping past any declaration that fails
+    /// to parse to the next unindented line. Returns the declarations
+    /// that did parse along with every error, for tools that want to
+    /// report all the problems in a file at once.
+    pub fn parse_recovering(
+        &mut self,
+        arena: &mut intern::Table,
+        loader: &mut dyn Loader,
+    ) -> (ast::File, Vec<ParseError>) {
+        self.recover = true;
+        let file = self.parse_all(arena, loader);
+        let errors = std::mem::take(&mut self.errors);
+        (file, errors)
     }
 
-    pub fn parse(&mut self, arena: &mut intern::Table) -> Result<ast::File, ParseError> {
+    fn parse_all(&mut self, arena: &mut intern::Table, loader: &mut dyn Loader) -> ast::File {
         let mut declarations = ast::Declarations::new();
         let mut scopes = ast::Scopes::new();
+        let top = scopes.top();
+
+        self.parse_declarations(&mut declarations, &mut scopes, top, arena, loader);
+
+        let builddir = lex::Identifier::new(arena, ast::BUILDDIR);
+        let builddir = match scopes.get(top, builddir) {
+            Some(builddir) if !builddir.is_empty() => Some(blob::Blob::new(builddir)),
+            _ => None,
+        };
 
+        ast::File::new(declarations, scopes, builddir)
+    }
+
+    /// A parser for an included file, which inherits the error mode.
+    fn nested<'nested>(
+        &self,
+        file: &'nested blob::View,
+        input: &'nested blob::View,
+    ) -> Parser<'nested> {
+        let mut parser = Parser::new(file, input);
+        parser.recover = self.recover;
+        parser
+    }
+
+    /// Parses declarations into `scope` until the end of input. Errors
+    /// are collected in `self.errors`.
+    fn parse_declarations(
+        &mut self,
+        declarations: &mut ast::Declarations,
+        scopes: &mut ast::Scopes,
+        scope: arena::Id<ast::Scope>,
+        arena: &mut intern::Table,
+        loader: &mut dyn Loader,
+    ) {
         loop {
-            match self.advance_decl()? {
-                None => break,
-                Some(token) => match token.kind() {
-                    DeclKind::Rule => {
-                        let rule = self.parse_rule(&mut scopes, arena)?;
-                        match declarations.add_rule(rule) {
-                            Ok(()) => (),
-                            Err(error) => return Err(ParseError::AstError(error)),
-                        }
-                    }
+            let start = self.lexer.mark();
+            let result = match self.advance_decl() {
+                Ok(None) => break,
+                Ok(Some(token)) => {
+                    self.parse_declaration(token, declarations, scopes, scope, arena, loader)
+                }
+                Err(error) => Err(error),
+            };
+            if let Err(error) = result {
+                self.errors.push(error);
+                if self.recover {
+                    self.lexer.resync(start);
+                }
+            }
+            if !self.recover && !self.errors.is_empty() {
+                break;
+            }
+        }
+    }
 
-                    DeclKind::Build => {
-                        let build = self.parse_build(&mut scopes, arena)?;
-                        match declarations.add_build(build) {
-                            Ok(()) => (),
-                            Err(error) => return Err(ParseError::AstError(error)),
-                        }
-                    }
+    fn parse_declaration(
+        &mut self,
+        token: Token<DeclKind>,
+        declarations: &mut ast::Declarations,
+        scopes: &mut ast::Scopes,
+        scope: arena::Id<ast::Scope>,
+        arena: &mut intern::Table,
+        loader: &mut dyn Loader,
+    ) -> Result<(), ParseError> {
+        match token.kind() {
+            DeclKind::Rule => {
+                let rule = self.parse_rule(scope, arena)?;
+                let location = rule.location().clone();
+                if let Err(error) = declarations.add_rule(rule) {
+                    return Err(ParseError::AstError { error, location });
+                }
+            }
 
-                    DeclKind::Default => {
-                        let default = self.parse_default(arena)?;
-                        match declarations.add_default(default) {
-                            Ok(()) => (),
-                            Err(error) => return Err(ParseError::AstError(error)),
-                        }
-                    }
+            DeclKind::Build => {
+                let build = self.parse_build(scopes, scope, arena)?;
+                if let Err(error) = check_pool(&build, declarations, scopes, arena) {
+                    return Err(self.ast_error(error, token));
+                }
+                if let Err(error) = declarations.add_build(build) {
+                    return Err(self.ast_error(error, token));
+                }
+            }
 
-                    DeclKind::Subninja => {
-                        todo!()
-                    }
+            DeclKind::Default => {
+                let default = self.parse_default(scope, arena)?;
+                if let Err(error) = declarations.add_default(default) {
+                    return Err(self.ast_error(error, token));
+                }
+            }
 
-                    DeclKind::Include => {
-                        todo!()
-                    }
+            DeclKind::Subninja => {
+                let location = self.lexer.mark();
+                let path = self.parse_path(scopes, scope, arena)?;
+                let child = scopes.new_scope(scope, vec![]);
+                let input = self.load(&path, location, loader)?;
+                let mut parser = self.nested(&path, &input);
+                parser.parse_declarations(declarations, scopes, child, arena, loader);
+                self.errors.append(&mut parser.errors);
+            }
 
-                    DeclKind::Pool => {
-                        let pool = self.parse_pool(&mut scopes, arena)?;
-                        match declarations.add_pool(pool) {
-                            Ok(()) => (),
-                            Err(error) => return Err(ParseError::AstError(error)),
-                        }
-                    }
+            DeclKind::Include => {
+                let location = self.lexer.mark();
+                let path = self.parse_path(scopes, scope, arena)?;
+                let input = self.load(&path, location, loader)?;
+                let mut parser = self.nested(&path, &input);
+                parser.parse_declarations(declarations, scopes, scope, arena, loader);
+                self.errors.append(&mut parser.errors);
+            }
 
-                    DeclKind::Identifier => {
-                        let identifier = lex::Identifier::new(arena, self.lexer.lexeme(token));
-                        let binding =
-                            self.parse_top_level_binding(&mut scopes, arena, identifier)?;
-                        let top = scopes.top();
-                        match scopes.get_scope_mut(top).push(binding) {
-                            Err(error) => return Err(ParseError::AstError(error)),
-                            Ok(()) => (),
-                        }
-                    }
+            DeclKind::Pool => {
+                let pool = self.parse_pool(scopes, scope, arena)?;
+                let location = pool.location().clone();
+                if let Err(error) = declarations.add_pool(pool) {
+                    return Err(ParseError::AstError { error, location });
+                }
+            }
 
-                    DeclKind::Newline => (),
-                },
+            DeclKind::Identifier => {
+                let identifier = lex::Identifier::new(arena, self.lexer.lexeme(token));
+                let location = token.location();
+                let binding = self.parse_top_level_binding(arena, identifier, location)?;
+                scopes.bind(scope, binding);
+                if identifier == lex::Identifier::new(arena, ast::REQUIRED_VERSION) {
+                    let required = scopes.get(scope, identifier).unwrap_or(b"");
+                    if let Err(error) = ast::check_version(required) {
+                        return Err(self.ast_error(error, token));
+                    }
+                }
             }
+
+            DeclKind::Newline => (),
         }
 
-        Ok(ast::File::new(declarations, scopes))
+        Ok(())
+    }
+
+    fn load(
+        &self,
+        path: &blob::View,
+        location: lex::SourceLocation,
+        loader: &mut dyn Loader,
+    ) -> Result<blob::Blob, ParseError> {
+        match loader.load(path) {
+            Ok(input) => Ok(input),
+            Err(error) => {
+                let path = blob::Blob::new(path);
+                let location = self.lexer.resolve(location);
+                Err(ParseError::LoadError {
+                    path,
+                    error,
+                    location,
+                })
+            }
+        }
     }
 
     fn parse_rule(
         &mut self,
-        scopes: &mut ast::Scopes,
+        parent: arena::Id<ast::Scope>,
         arena: &mut intern::Table,
     ) -> Result<ast::Rule, ParseError> {
+        let name_location = self.lexer.here();
         let name = self.parse_identifier(arena)?;
+        if name == lex::Identifier::new(arena, ast::PHONY) {
+            let error = ast::AstError::BuiltinRule;
+            let location = name_location;
+            return Err(ParseError::AstError { error, location });
+        }
         let _newline = self.consume(TokenKind::Newline)?;
-        let scope = self.parse_scope(scopes, arena)?;
+        let bindings = self.parse_bindings(arena)?;
 
-        Ok(ast::Rule::new(name, scope))
+        let mut reserved = vec![];
+        for binding in bindings {
+            match ast::ReservedBinding::from_name(binding.name().name(arena)) {
+                Some(key) => {
+                    if let Err(error) = key.check(binding.value()) {
+                        let location = self.lexer.resolve(binding.location());
+                        return Err(ParseError::AstError { error, location });
+                    }
+                    reserved.push((key, binding))
+                }
+                None => {
+                    let error = ast::AstError::UnknownRuleBinding;
+                    let location = self.lexer.resolve(binding.location());
+                    return Err(ParseError::AstError { error, location });
+                }
+            }
+        }
+
+        match ast::Rule::new(name, reserved, parent, name_location.clone()) {
+            Ok(rule) => Ok(rule),
+            Err(error) => {
+                let location = name_location;
+                Err(ParseError::AstError { error, location })
+            }
+        }
     }
 
     fn parse_build(
         &mut self,
         scopes: &mut ast::Scopes,
+        parent: arena::Id<ast::Scope>,
         arena: &mut intern::Table,
     ) -> Result<ast::Build, ParseError> {
         let mut outputs = vec![];
@@ -116,12 +447,13 @@ impl<'input> Parser<'input> {
         let mut inputs = vec![];
         let mut implicit_inputs = vec![];
         let mut order_inputs = vec![];
+        let mut validations = vec![];
 
         while let Some(output) = self.parse_target(arena)? {
             outputs.push(output)
         }
         let _colon = match self.advance()? {
-            None => return Err(ParseError::UnexpectedEof),
+            None => return Err(self.unexpected_eof()),
             Some(token) => match token.kind() {
                 TokenKind::Pipe => {
                     while let Some(implicit_output) = self.parse_target(arena)? {
@@ -130,46 +462,44 @@ impl<'input> Parser<'input> {
                     self.consume(TokenKind::Colon)?
                 }
                 TokenKind::Colon => token,
-                got => return Err(ParseError::UnexpectedToken { got }),
+                got => {
+                    let location = self.lexer.locate(token);
+                    return Err(ParseError::Expected {
+                        expected: TokenKind::Colon,
+                        got,
+                        location,
+                    });
+                }
             },
         };
         let rule = self.parse_identifier(arena)?;
         while let Some(input) = self.parse_target(arena)? {
             inputs.push(input)
         }
-        let _newline = match self.advance()? {
-            None => return Err(ParseError::UnexpectedEof),
-            Some(token) => match token.kind() {
-                TokenKind::Newline => token,
-                TokenKind::Pipe => {
-                    while let Some(implicit_input) = self.parse_target(arena)? {
-                        implicit_inputs.push(implicit_input)
-                    }
-                    match self.advance()? {
-                        None => return Err(ParseError::UnexpectedEof),
-                        Some(token) => match token.kind() {
-                            TokenKind::Newline => token,
-                            TokenKind::PipePipe => {
-                                while let Some(order_input) = self.parse_target(arena)? {
-                                    order_inputs.push(order_input)
-                                }
-                                self.consume(TokenKind::Newline)?
-                            }
-                            got => return Err(ParseError::UnexpectedToken { got }),
-                        },
-                    }
-                }
-                TokenKind::PipePipe => {
-                    while let Some(order_input) = self.parse_target(arena)? {
-                        order_inputs.push(order_input)
-                    }
-                    self.consume(TokenKind::Newline)?
-                }
-                got => return Err(ParseError::UnexpectedToken { got }),
-            },
-        };
+        let mut token = self.expect_token()?;
+        if token.kind() == TokenKind::Pipe {
+            while let Some(implicit_input) = self.parse_target(arena)? {
+                implicit_inputs.push(implicit_input)
+            }
+            token = self.expect_token()?;
+        }
+        if token.kind() == TokenKind::PipePipe {
+            while let Some(order_input) = self.parse_target(arena)? {
+                order_inputs.push(order_input)
+            }
+            token = self.expect_token()?;
+        }
+        if token.kind() == TokenKind::PipeAt {
+            while let Some(validation) = self.parse_target(arena)? {
+                validations.push(validation)
+            }
+            token = self.expect_token()?;
+        }
+        if token.kind() != TokenKind::Newline {
+            return Err(self.unexpected_token(token));
+        }
 
-        let scope = self.parse_scope(scopes, arena)?;
+        let scope = self.parse_scope(scopes, parent, arena)?;
 
         Ok(ast::Build::new(
             outputs,
@@ -178,35 +508,46 @@ impl<'input> Parser<'input> {
             inputs,
             implicit_inputs,
             order_inputs,
+            validations,
             scope,
         ))
     }
 
-    fn parse_default(&mut self, arena: &mut intern::Table) -> Result<ast::Default, ParseError> {
+    fn parse_default(
+        &mut self,
+        scope: arena::Id<ast::Scope>,
+        arena: &mut intern::Table,
+    ) -> Result<ast::Default, ParseError> {
         let mut targets = vec![];
         while let Some(target) = self.parse_target(arena)? {
             targets.push(target)
         }
         self.consume(TokenKind::Newline)?;
-        Ok(ast::Default::new(targets))
+        Ok(ast::Default::new(targets, scope))
     }
 
     fn parse_pool(
         &mut self,
         scopes: &mut ast::Scopes,
+        parent: arena::Id<ast::Scope>,
         arena: &mut intern::Table,
     ) -> Result<ast::Pool, ParseError> {
+        let location = self.lexer.here();
         let name = self.parse_identifier(arena)?;
+        if name == lex::Identifier::new(arena, ast::CONSOLE) {
+            let error = ast::AstError::BuiltinPool;
+            return Err(ParseError::AstError { error, location });
+        }
         let _newline = self.consume(TokenKind::Newline)?;
-        let scope_id = self.parse_scope(scopes, arena)?;
+        let scope_id = self.parse_scope(scopes, parent, arena)?;
         let scope = scopes.get_scope(scope_id);
 
         if scope.size() != 1 {
-            return Err(ParseError::PoolDepthInvalid);
+            return Err(ParseError::PoolDepthInvalid { location });
         }
         let depth = lex::Identifier::new(arena, b"depth");
         let depth = match scope.get(depth) {
-            None => return Err(ParseError::PoolDepthInvalid),
+            None => return Err(ParseError::PoolDepthInvalid { location }),
             Some(depth) => depth,
         };
         let depth = if depth == b"" {
@@ -214,15 +555,15 @@ impl<'input> Parser<'input> {
         } else {
             let depth = match String::from_utf8(depth.to_vec()) {
                 Ok(depth) => depth,
-                _ => return Err(ParseError::PoolDepthInvalid),
+                _ => return Err(ParseError::PoolDepthInvalid { location }),
             };
             match depth.parse() {
-                Err(_) => return Err(ParseError::PoolDepthInvalid),
+                Err(_) => return Err(ParseError::PoolDepthInvalid { location }),
                 Ok(depth) => depth,
             }
         };
 
-        Ok(ast::Pool::new(name, depth))
+        Ok(ast::Pool::new(name, depth, location))
     }
 
     fn parse_target(
@@ -236,61 +577,79 @@ impl<'input> Parser<'input> {
         }
     }
 
+    fn parse_path(
+        &mut self,
+        scopes: &ast::Scopes,
+        scope: arena::Id<ast::Scope>,
+        arena: &mut intern::Table,
+    ) -> Result<blob::Blob, ParseError> {
+        let path = match self.parse_target(arena)? {
+            Some(path) => path,
+            None => return Err(self.invalid_value()),
+        };
+        let _newline = self.consume(TokenKind::Newline)?;
+
+        Ok(path.evaluate(scopes, scope))
+    }
+
     fn parse_scope(
         &mut self,
         scopes: &mut ast::Scopes,
+        parent: arena::Id<ast::Scope>,
         arena: &mut intern::Table,
     ) -> Result<arena::Id<ast::Scope>, ParseError> {
+        let bindings = self.parse_bindings(arena)?;
+        Ok(scopes.new_scope(parent, bindings))
+    }
+
+    fn parse_bindings(
+        &mut self,
+        arena: &mut intern::Table,
+    ) -> Result<Vec<ast::Binding>, ParseError> {
         let mut bindings = vec![];
+        let mut names = std::collections::HashSet::new();
 
         while self.lexer.try_indent() {
             let _indent = self.consume(TokenKind::Indent);
-            let binding = self.parse_binding(scopes, arena)?;
+            let binding = self.parse_binding(arena)?;
+            if !names.insert(binding.name()) {
+                let error = ast::AstError::DuplicateBinding;
+                let location = self.lexer.resolve(binding.location());
+                return Err(ParseError::AstError { error, location });
+            }
             bindings.push(binding)
         }
 
-        match scopes.new_scope(bindings) {
-            Ok(id) => Ok(id),
-            Err(error) => Err(ParseError::AstError(error)),
-        }
+        Ok(bindings)
     }
 
     fn parse_top_level_binding(
         &mut self,
-        scopes: &mut ast::Scopes,
         arena: &mut intern::Table,
         identifier: lex::Identifier,
+        location: lex::SourceLocation,
     ) -> Result<ast::Binding, ParseError> {
         let _equal = self.consume(TokenKind::Equal)?;
         let value = match self.parse_value(arena)? {
             Some(value) => value,
-            None => return Err(ParseError::InvalidValue),
+            None => return Err(self.invalid_value()),
         };
         let _newline = self.consume(TokenKind::Newline)?;
 
-        let top = scopes.top();
-        let bytes = scopes.get_scope(top).evaluate(&value);
-
-        Ok(ast::Binding::new(identifier, bytes))
+        Ok(ast::Binding::new(identifier, value, location))
     }
 
-    fn parse_binding(
-        &mut self,
-        scopes: &mut ast::Scopes,
-        arena: &mut intern::Table,
-    ) -> Result<ast::Binding, ParseError> {
+    fn parse_binding(&mut self, arena: &mut intern::Table) -> Result<ast::Binding, ParseError> {
+        let location = self.lexer.mark();
         let identifier = self.parse_identifier(arena)?;
         let _equal = self.consume(TokenKind::Equal)?;
         let value = match self.parse_value(arena)? {
             Some(value) => value,
-            None => return Err(ParseError::InvalidValue),
+            None => return Err(self.invalid_value()),
         };
         let _newline = self.consume(TokenKind::Newline)?;
 
-        let top = scopes.top();
-        let bytes = scopes.get_scope(top).evaluate(&value);
-
-        Ok(ast::Binding::new(identifier, bytes))
+        Ok(ast::Binding::new(identifier, value, location))
     }
 
     fn parse_identifier(
@@ -310,6 +669,27 @@ impl<'input> Parser<'input> {
         }
     }
 
+    fn ast_error(&self, error: ast::AstError, token: Token<DeclKind>) -> ParseError {
+        let location = self.lexer.locate(token);
+        ParseError::AstError { error, location }
+    }
+
+    fn unexpected_token(&self, token: Token<TokenKind>) -> ParseError {
+        let got = token.kind();
+        let location = self.lexer.locate(token);
+        ParseError::UnexpectedToken { got, location }
+    }
+
+    fn unexpected_eof(&self) -> ParseError {
+        let location = self.lexer.here();
+        ParseError::UnexpectedEof { location }
+    }
+
+    fn invalid_value(&self) -> ParseError {
+        let location = self.lexer.here();
+        ParseError::InvalidValue { location }
+    }
+
     fn advance(&mut self) -> Result<Option<Token<TokenKind>>, ParseError> {
         match self.lexer.lex() {
             Ok(token) => Ok(token),
@@ -324,15 +704,27 @@ impl<'input> Parser<'input> {
         }
     }
 
+    fn expect_token(&mut self) -> Result<Token<TokenKind>, ParseError> {
+        match self.advance()? {
+            None => Err(self.unexpected_eof()),
+            Some(token) => Ok(token),
+        }
+    }
+
     fn consume(&mut self, expected: TokenKind) -> Result<Token<TokenKind>, ParseError> {
         match self.advance()? {
-            None => Err(ParseError::UnexpectedEof),
+            None => Err(self.unexpected_eof()),
             Some(token) => {
                 let got = token.kind();
                 if got == expected {
                     Ok(token)
                 } else {
-                    Err(ParseError::Expected { expected, got })
+                    let location = self.lexer.locate(token);
+                    Err(ParseError::Expected {
+                        expected,
+                        got,
+                        location,
+                    })
                 }
             }
         }
@@ -344,9 +736,31 @@ mod tests {
     use super::*;
 
     fn parse(input: &blob::View) -> Result<ast::File, ParseError> {
+        parse_with_files(input, &[]).map(|(file, _)| file)
+    }
+
+    fn parse_with_files(
+        input: &blob::View,
+        files: &[(&str, &str)],
+    ) -> Result<(ast::File, intern::Table), ParseError> {
         let mut arena = intern::Table::new();
-        let mut parser = Parser::new(input);
-        parser.parse(&mut arena)
+        let mut loader = MemoryLoader::new();
+        for (path, contents) in files.iter() {
+            loader.insert(path.as_bytes(), contents.as_bytes());
+        }
+        let mut parser = Parser::new(b"build.ninja", input);
+        let file = parser.parse(&mut arena, &mut loader)?;
+        Ok((file, arena))
+    }
+
+    fn lookup<'a>(
+        file: &'a ast::File,
+        arena: &mut intern::Table,
+        name: &blob::View,
+    ) -> Option<&'a blob::View> {
+        let scopes = file.scopes();
+        let identifier = lex::Identifier::new(arena, name);
+        scopes.get(scopes.top(), identifier)
     }
 
     #[test]
@@ -412,11 +826,26 @@ mod tests {
         }
     }
 
+    #[test]
+    fn build_validations() {
+        let ninja =
+            b"build out : cc in | dep || order |@ check1 check2\nbuild out2 : cc |@ check3\n";
+        let file = parse(ninja).expect("failed to parse validations");
+        let builds = file.builds().collect::<Vec<_>>();
+        assert_eq!(builds[0].implicit_inputs().len(), 1);
+        assert_eq!(builds[0].order_inputs().len(), 1);
+        assert_eq!(builds[0].validations().len(), 2);
+        assert_eq!(builds[1].inputs().len(), 0);
+        assert_eq!(builds[1].validations().len(), 1);
+    }
+
     #[test]
     fn invalid_build() {
         let invalid_builds: &[&blob::View] = &[
             b"build output = input\n",
             b"build output : rulename :\n",
+            b"build output : rulename |@ a | b\n",
+            b"build output : rulename |@ a || b\n",
             b"build",
         ];
         for ninja in invalid_builds.iter() {
@@ -455,4 +884,517 @@ mod tests {
         let ninja = b"build mything : myrule myinput\n    pool =\n";
         parse(ninja).expect("failed to parse pool");
     }
+
+    #[test]
+    fn include() {
+        let ninja = b"include rules.ninja\nbuild out : cc in\n";
+        let files = [("rules.ninja", "cflags = -O2\nrule cc\n    command = cc\n")];
+        let (file, mut arena) = parse_with_files(ninja, &files).expect("failed to parse include");
+        assert_eq!(file.declarations().count(), 2);
+        assert_eq!(lookup(&file, &mut arena, b"cflags"), Some(&b"-O2"[..]));
+    }
+
+    #[test]
+    fn rebinding() {
+        let ninja = b"cflags = -O2\ncflags = $cflags -g\ninclude flags.ninja\n";
+        let files = [("flags.ninja", "cflags = $cflags -Wall\n")];
+        let (file, mut arena) = parse_with_files(ninja, &files).expect("failed to parse");
+        assert_eq!(
+            lookup(&file, &mut arena, b"cflags"),
+            Some(&b"-O2 -g -Wall"[..])
+        );
+    }
+
+    #[test]
+    fn include_evaluates_path() {
+        let ninja = b"dir = sub\ninclude $dir/rules.ninja\n";
+        let files = [("sub/rules.ninja", "rule cc\n    command = cc\n")];
+        let (file, _) = parse_with_files(ninja, &files).expect("failed to parse include");
+        assert_eq!(file.declarations().count(), 1);
+    }
+
+    #[test]
+    fn include_missing_file() {
+        let ninja = b"include missing.ninja\n";
+        match parse(ninja) {
+            Err(ParseError::LoadError { path, .. }) => assert_eq!(path.view(), b"missing.ninja"),
+            _ => panic!("included a missing file"),
+        }
+    }
+
+    #[test]
+    fn subninja() {
+        let ninja = b"cflags = -O2\nsubninja sub.ninja\n";
+        let files = [(
+            "sub.ninja",
+            "cflags = -O0\nldflags = -s\nbuild out : cc in\n",
+        )];
+        let (file, mut arena) = parse_with_files(ninja, &files).expect("failed to parse subninja");
+        assert_eq!(file.declarations().count(), 1);
+        assert_eq!(lookup(&file, &mut arena, b"cflags"), Some(&b"-O2"[..]));
+        assert_eq!(lookup(&file, &mut arena, b"ldflags"), None);
+    }
+
+    #[test]
+    fn subninja_sees_parent_scope() {
+        let ninja = b"name = inner\nsubninja sub.ninja\n";
+        let files = [
+            ("sub.ninja", "include $name.ninja\n"),
+            ("inner.ninja", "build out : cc in\n"),
+        ];
+        let (file, _) = parse_with_files(ninja, &files).expect("failed to parse subninja");
+        assert_eq!(file.declarations().count(), 1);
+    }
+
+    fn command(ninja: &blob::View) -> Result<blob::Blob, ast::AstError> {
+        let (file, mut arena) = parse_with_files(ninja, &[]).expect("failed to parse");
+        let build = file
+            .declarations()
+            .iter()
+            .find_map(|declaration| match declaration {
+                ast::Declaration::Build(build) => Some(build),
+                _ => None,
+            })
+            .expect("no build");
+        let rule = file.rule(build).expect("unknown rule");
+        let command = lex::Identifier::new(&mut arena, b"command");
+        let env = ast::Env::new(&mut arena, file.scopes(), build, rule);
+        env.get(command)
+    }
+
+    #[test]
+    fn rule_in_out() {
+        let ninja = b"rule cc\n    command = cc $in -o $out\nbuild a.o b.o : cc a.c b.c | a.h\n";
+        let command = command(ninja).expect("failed to evaluate command");
+        assert_eq!(command.view(), &b"cc a.c b.c -o a.o b.o"[..]);
+    }
+
+    #[test]
+    fn rule_in_newline() {
+        let ninja = b"rule cat\n    command = cat $in_newline\nbuild out : cat a b\n";
+        let command = command(ninja).expect("failed to evaluate command");
+        assert_eq!(command.view(), &b"cat a\nb"[..]);
+    }
+
+    #[test]
+    fn rule_shell_escapes_paths() {
+        let ninja = b"rule cc\n    command = cc $in\nbuild out : cc it's$ here\n";
+        let command = command(ninja).expect("failed to evaluate command");
+        assert_eq!(command.view(), &b"cc 'it'\\''s here'"[..]);
+    }
+
+    #[test]
+    fn rule_lookup_order() {
+        let ninja = b"flags = file\ndescription = file\ndepfile = file\n\
+                      rule cc\n    command = $flags $description $depfile\n    \
+                      description = rule\n    depfile = rule\n\
+                      build out : cc in\n    depfile = build\n";
+        let command = command(ninja).expect("failed to evaluate command");
+        assert_eq!(command.view(), &b"file rule build"[..]);
+    }
+
+    #[test]
+    fn rule_binding_refers_to_rule_binding() {
+        let ninja =
+            b"rule cc\n    command = cc $depfile\n    depfile = $out.d\nbuild out : cc in\n";
+        let command = command(ninja).expect("failed to evaluate command");
+        assert_eq!(command.view(), &b"cc out.d"[..]);
+    }
+
+    #[test]
+    fn rule_variable_cycle() {
+        let ninja = b"rule cc\n    command = $deps\n    deps = $dyndep\n    dyndep = $deps\nbuild out : cc in\n";
+        match command(ninja) {
+            Err(ast::AstError::VariableCycle) => (),
+            _ => panic!("evaluated a cycle of rule variables"),
+        }
+    }
+
+    #[test]
+    fn build_bindings_evaluated_in_file_scope() {
+        let ninja = b"rule cc\n    command = cc $flags\nx = 1\nbuild out : cc in\n    x = 2\n    flags = $x\n";
+        let command = command(ninja).expect("failed to evaluate command");
+        assert_eq!(command.view(), &b"cc 1"[..]);
+    }
+
+    #[test]
+    fn error_display() {
+        let error = match parse(b"build out\n") {
+            Err(error) => error,
+            Ok(_) => panic!("parsed a build without a rule"),
+        };
+        assert_eq!(
+            error.to_string(),
+            "build.ninja:1: expected ':', got newline\nbuild out\n         ^ near here"
+        );
+    }
+
+    #[test]
+    fn error_location() {
+        let error = match parse(b"x = 1\ny = 2\nbuild out : cc in\n    x\n") {
+            Err(error) => error,
+            Ok(_) => panic!("parsed a binding without a value"),
+        };
+        let location = error.location();
+        assert_eq!(location.file(), b"build.ninja");
+        assert_eq!(location.line(), 4);
+        assert_eq!(location.column(), 6);
+        assert_eq!(location.span(), 35..36);
+    }
+
+    #[test]
+    fn lex_error_location() {
+        match parse(b"x = 1\nx~\n") {
+            Err(ParseError::LexError(error)) => {
+                assert_eq!(error.location().line(), 2);
+                assert_eq!(error.location().column(), 2);
+            }
+            _ => panic!("lexed an invalid character"),
+        }
+    }
+
+    #[test]
+    fn included_error_location() {
+        let ninja = b"x = 1\ninclude rules.ninja\n";
+        let files = [("rules.ninja", "rule cc\n    command = cc\npool\n")];
+        let error = match parse_with_files(ninja, &files) {
+            Err(error) => error,
+            Ok(_) => panic!("parsed a pool without a name"),
+        };
+        assert_eq!(error.location().file(), b"rules.ninja");
+        assert_eq!(error.location().line(), 3);
+    }
+
+    #[test]
+    fn duplicate_binding_location() {
+        let error = match parse(b"rule cc\n    command = a\n    command = b\n") {
+            Err(error) => error,
+            Ok(_) => panic!("parsed a duplicate binding"),
+        };
+        match &error {
+            ParseError::AstError {
+                error: ast::AstError::DuplicateBinding,
+                location,
+            } => assert_eq!(location.line(), 3),
+            _ => panic!("unexpected error {}", error),
+        }
+    }
+
+    #[test]
+    fn unknown_rule_binding() {
+        match parse(b"rule cc\n    command = cc\n    flags = -O2\n") {
+            Err(ParseError::AstError {
+                error: ast::AstError::UnknownRuleBinding,
+                location,
+            }) => {
+                assert_eq!(location.line(), 3);
+                assert_eq!(location.column(), 5);
+            }
+            _ => panic!("parsed an unknown rule variable"),
+        }
+    }
+
+    #[test]
+    fn rule_missing_command() {
+        match parse(b"rule cc\n    description = CC $out\n") {
+            Err(ParseError::AstError {
+                error: ast::AstError::MissingCommand,
+                location,
+            }) => assert_eq!(location.line(), 1),
+            _ => panic!("parsed a rule without a command"),
+        }
+    }
+
+    #[test]
+    fn rule_rspfile_mismatch() {
+        match parse(b"rule cc\n    command = cc @$out.rsp\n    rspfile = $out.rsp\n") {
+            Err(ParseError::AstError {
+                error: ast::AstError::RspfileMismatch,
+                ..
+            }) => (),
+            _ => panic!("parsed an rspfile without content"),
+        }
+    }
+
+    #[test]
+    fn rule_reserved_bindings() {
+        let ninja = b"rule cc\n    command = cc $in\n    description = CC $out\n    restat = 1\n";
+        let (file, _) = parse_with_files(ninja, &[]).expect("failed to parse");
+        let rule = file.rules().next().expect("no rule");
+        assert!(rule.description().is_some());
+        assert_eq!(rule.restat(), ast::Setting::Literal(true));
+        assert_eq!(rule.generator(), ast::Setting::Literal(false));
+        assert!(rule.depfile().is_none());
+        assert_eq!(rule.pool(), ast::Setting::Literal(None));
+    }
+
+    #[test]
+    fn rule_comments() {
+        let ninja =
+            b"rule cc\n    # The compiler.\n    command = cc\n# Its log.\n    description = CC\n    # Done.\n  # Top level.\nbuild a: cc\n";
+        let (file, _) = parse_with_files(ninja, &[]).expect("failed to parse");
+        let rule = file.rules().next().expect("no rule");
+        assert!(rule.description().is_some());
+    }
+
+    #[test]
+    fn rule_settings() {
+        let ninja = b"pool link\n    depth = 1\n\
+                      rule cc\n    command = cc\n    deps = msvc\n    pool = link\n\
+                      rule gen\n    command = gen\n    deps = $style\n    generator = $gen\n";
+        let (file, _) = parse_with_files(ninja, &[]).expect("failed to parse");
+        let mut rules = file.rules();
+        let cc = rules.next().expect("no rule");
+        assert_eq!(cc.deps(), ast::Setting::Literal(Some(ast::DepsType::Msvc)));
+        assert_eq!(cc.pool(), ast::Setting::Literal(Some(&b"link"[..])));
+        let gen = rules.next().expect("no rule");
+        assert_eq!(gen.deps(), ast::Setting::Deferred);
+        assert_eq!(gen.generator(), ast::Setting::Deferred);
+    }
+
+    #[test]
+    fn rule_unknown_deps() {
+        match parse(b"rule cc\n    command = cc\n    deps = clang\n") {
+            Err(ParseError::AstError {
+                error: ast::AstError::UnknownDeps { deps },
+                location,
+            }) => {
+                assert_eq!(deps.view(), b"clang");
+                assert_eq!(location.line(), 3);
+            }
+            _ => panic!("parsed an unknown deps type"),
+        }
+    }
+
+    #[test]
+    fn build_unknown_pool() {
+        let rule = b"rule cc\n    command = cc\n    pool = link\n";
+        for ninja in [
+            &b"rule cc\n    command = cc\nbuild a : cc\n    pool = link\n"[..],
+            &[&rule[..], b"build a : cc\npool link\n    depth = 1\n"].concat(),
+        ]
+        .iter()
+        {
+            match parse(ninja) {
+                Err(ParseError::AstError {
+                    error: ast::AstError::UnknownPool { pool },
+                    ..
+                }) => assert_eq!(pool.view(), b"link"),
+                _ => panic!("parsed a build in an unknown pool"),
+            }
+        }
+        parse(&[&b"pool link\n    depth = 1\n"[..], rule, b"build a : cc\n"].concat())
+            .expect("failed to parse a declared pool");
+        parse(b"rule cc\n    command = cc\n    pool = console\nbuild a : cc\n")
+            .expect("failed to parse the console pool");
+    }
+
+    #[test]
+    fn required_version() {
+        for version in ["1.0", "1.11", "1.11.1", "1.11.5.git", "1"].iter() {
+            let ninja = format!("ninja_required_version = {}\n", version);
+            parse(ninja.as_bytes()).expect("rejected a supported version");
+        }
+        for version in ["1.12", "2.0", "1.100"].iter() {
+            let ninja = format!("x = 1\nninja_required_version = {}\n", version);
+            match parse(ninja.as_bytes()) {
+                Err(ParseError::AstError {
+                    error: ast::AstError::IncompatibleVersion { required },
+                    location,
+                }) => {
+                    assert_eq!(required.view(), version.as_bytes());
+                    assert_eq!(location.line(), 2);
+                }
+                _ => panic!("accepted a newer version"),
+            }
+        }
+    }
+
+    #[test]
+    fn required_version_message() {
+        let error = match parse(b"ninja_required_version = 9.0\n") {
+            Err(error) => error,
+            Ok(_) => panic!("accepted a newer version"),
+        };
+        let message = format!(
+            "build.ninja:1: ninja version ({}) incompatible with build file \
+             ninja_required_version version (9.0)",
+            ast::VERSION
+        );
+        assert!(error.to_string().starts_with(&message));
+    }
+
+    #[test]
+    fn builddir() {
+        let file = parse(b"out = obj\nbuilddir = $out/state\n").expect("failed to parse");
+        assert_eq!(file.builddir(), Some(&b"obj/state"[..]));
+        assert_eq!(
+            file.state_path(ast::NINJA_LOG).view(),
+            b"obj/state/.ninja_log"
+        );
+
+        let file = parse(b"builddir =\n").expect("failed to parse");
+        assert_eq!(file.builddir(), None);
+        assert_eq!(file.state_path(ast::NINJA_DEPS).view(), b".ninja_deps");
+    }
+
+    #[test]
+    fn builddir_ignores_subninja() {
+        let files = [("sub.ninja", "builddir = sub\n")];
+        let (file, _) = parse_with_files(b"subninja sub.ninja\n", &files).expect("failed to parse");
+        assert_eq!(file.builddir(), None);
+    }
+
+    fn parse_recovering(
+        input: &blob::View,
+        files: &[(&str, &str)],
+    ) -> (ast::File, Vec<ParseError>) {
+        let mut arena = intern::Table::new();
+        let mut loader = MemoryLoader::new();
+        for (path, contents) in files.iter() {
+            loader.insert(path.as_bytes(), contents.as_bytes());
+        }
+        let mut parser = Parser::new(b"build.ninja", input);
+        parser.parse_recovering(&mut arena, &mut loader)
+    }
+
+    #[test]
+    fn recover_collects_errors() {
+        let ninja = b"rule cc\n    command = cc\n    bogus = 1\n    depfile = d\n\
+                      build a : cc in\n\
+                      build b c\n\
+                      build d : cc $\n    in | a : b\n\
+                      x = 1\n\
+                      ~\n\
+                      rule link\n    command = ld\n\
+                      build e : link d\n";
+        let (file, errors) = parse_recovering(ninja, &[]);
+        let lines = errors
+            .iter()
+            .map(|error| error.location().line())
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec![3, 6, 8, 10]);
+        assert_eq!(file.rules().count(), 1);
+        assert_eq!(file.builds().count(), 2);
+        assert_eq!(file.declarations().count(), 3);
+    }
+
+    #[test]
+    fn recover_at_unindented_line() {
+        let ninja = b"build out\nbuild ok : phony\n";
+        let (file, errors) = parse_recovering(ninja, &[]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(file.builds().count(), 1);
+    }
+
+    #[test]
+    fn recover_in_included_file() {
+        let ninja = b"include bad.ninja\ninclude missing.ninja\nbuild ok : phony\n";
+        let files = [("bad.ninja", "pool\nbuild inner : phony\n")];
+        let (file, errors) = parse_recovering(ninja, &files);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].location().file(), b"bad.ninja");
+        match &errors[1] {
+            ParseError::LoadError { .. } => (),
+            error => panic!("unexpected error {}", error),
+        }
+        assert_eq!(file.builds().count(), 2);
+    }
+
+    #[test]
+    fn strict_stops_in_included_file() {
+        let ninja = b"include bad.ninja\nbuild : cc\n";
+        let files = [("bad.ninja", "pool\n")];
+        match parse_with_files(ninja, &files) {
+            Err(error) => assert_eq!(error.location().file(), b"bad.ninja"),
+            Ok(_) => panic!("parsed a pool without a name"),
+        }
+    }
+
+    #[test]
+    fn builtin_rule() {
+        match parse(b"rule phony\n    command = touch $out\n") {
+            Err(ParseError::AstError {
+                error: ast::AstError::BuiltinRule,
+                location,
+            }) => assert_eq!(location.column(), 6),
+            _ => panic!("redefined the phony rule"),
+        }
+    }
+
+    #[test]
+    fn builtin_pool() {
+        match parse(b"pool console\n    depth = 4\n") {
+            Err(ParseError::AstError {
+                error: ast::AstError::BuiltinPool,
+                ..
+            }) => (),
+            _ => panic!("redefined the console pool"),
+        }
+    }
+
+    #[test]
+    fn duplicate_rule() {
+        let ninja = b"rule cc\n    command = a\n\nrule cc\n    command = b\n";
+        let error = match parse(ninja) {
+            Err(error) => error,
+            Ok(_) => panic!("parsed a duplicate rule"),
+        };
+        match &error {
+            ParseError::AstError {
+                error: ast::AstError::DuplicateRule { previous },
+                location,
+            } => {
+                assert_eq!(location.line(), 4);
+                assert_eq!(previous.line(), 1);
+            }
+            _ => panic!("unexpected error {}", error),
+        }
+        assert_eq!(
+            error.to_string(),
+            "build.ninja:4: duplicate rule\nrule cc\n     ^ near here\n\
+             build.ninja:1: previous definition\nrule cc\n     ^ near here"
+        );
+    }
+
+    #[test]
+    fn duplicate_rule_included() {
+        let ninja = b"rule cc\n    command = a\ninclude rules.ninja\n";
+        let files = [("rules.ninja", "rule cc\n    command = b\n")];
+        match parse_with_files(ninja, &files) {
+            Err(ParseError::AstError {
+                error: ast::AstError::DuplicateRule { previous },
+                location,
+            }) => {
+                assert_eq!(location.file(), b"rules.ninja");
+                assert_eq!(previous.file(), b"build.ninja");
+            }
+            _ => panic!("parsed a duplicate rule"),
+        }
+    }
+
+    #[test]
+    fn subninja_redefines_rule() {
+        let ninja = b"rule cc\n    command = a\nsubninja sub.ninja\n";
+        let files = [("sub.ninja", "rule cc\n    command = b\n")];
+        let (file, _) = parse_with_files(ninja, &files).expect("failed to redefine rule");
+        assert_eq!(file.rules().count(), 2);
+    }
+
+    #[test]
+    fn duplicate_pool() {
+        let ninja = b"pool link\n    depth = 1\nsubninja sub.ninja\n";
+        let files = [("sub.ninja", "pool link\n    depth = 2\n")];
+        match parse_with_files(ninja, &files) {
+            Err(ParseError::AstError {
+                error: ast::AstError::DuplicatePool { previous },
+                location,
+            }) => {
+                assert_eq!(location.file(), b"sub.ninja");
+                assert_eq!(previous.file(), b"build.ninja");
+                assert_eq!(previous.line(), 1);
+            }
+            _ => panic!("parsed a duplicate pool"),
+        }
+    }
 }
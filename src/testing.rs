@@ -0,0 +1,22 @@
+//! Helpers shared by the tests of several modules.
+
+use crate::graph::Graph;
+use crate::intern;
+use std::path::PathBuf;
+
+/// Builds the graph of a ninja file held in memory.
+pub fn graph<Ninja: AsRef<[u8]> + ?Sized>(ninja: &Ninja) -> Graph {
+    let mut arena = intern::Table::new();
+    let file = crate::parse_bytes(&mut arena, ninja.as_ref()).expect("failed to parse");
+    Graph::new(&mut arena, &file).expect("failed to build graph")
+}
+
+/// Creates an empty directory for a test to use, named after `name` and
+/// the process so that tests running at the same time do not share one.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let name = format!("ninja-rs-{}-{}", name, std::process::id());
+    let dir = std::env::temp_dir().join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).expect("failed to create directory");
+    dir
+}
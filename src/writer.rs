@@ -0,0 +1,722 @@
+//! Writing ninja files from Rust values, in the manner of ninja's
+//! `ninja_syntax.py`.
+//!
+//! Paths and variable text are escaped as they are written, and the
+//! [`Writer`] rejects anything the parser would, such as invalid names
+//! or a second rule with the same name, so its output always parses.
+//! Only what it has written itself is known to it, so a pool declared in
+//! an included file cannot be used.
+
+use crate::ast;
+use crate::blob;
+use crate::blob::{Blob, Builder};
+use crate::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum WriteError {
+    Io(io::Error),
+    InvalidName {
+        name: Blob,
+    },
+    /// Paths and values cannot contain newlines, and paths cannot
+    /// contain `|`, since ninja has no way to escape them.
+    InvalidText {
+        text: Blob,
+    },
+    MissingOutput,
+    MissingTarget,
+    DuplicateBinding {
+        name: Blob,
+    },
+    DuplicateRule {
+        name: Blob,
+    },
+    DuplicatePool {
+        name: Blob,
+    },
+    BuiltinRule,
+    BuiltinPool,
+    RspfileMismatch,
+    UnknownDeps {
+        deps: Blob,
+    },
+    UnknownPool {
+        pool: Blob,
+    },
+    IncompatibleVersion {
+        required: Blob,
+    },
+}
+
+impl std::fmt::Display for WriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let show = |blob: &Blob| String::from_utf8_lossy(blob).into_owned();
+        match self {
+            WriteError::Io(error) => error.fmt(f),
+            WriteError::InvalidName { name } => write!(f, "invalid name '{}'", show(name)),
+            WriteError::InvalidText { text } => {
+                write!(f, "cannot escape '{}'", show(text).escape_default())
+            }
+            WriteError::MissingOutput => f.write_str("build has no outputs"),
+            WriteError::MissingTarget => f.write_str("default has no targets"),
+            WriteError::DuplicateBinding { name } => {
+                write!(f, "duplicate variable '{}'", show(name))
+            }
+            WriteError::DuplicateRule { name } => write!(f, "duplicate rule '{}'", show(name)),
+            WriteError::DuplicatePool { name } => write!(f, "duplicate pool '{}'", show(name)),
+            WriteError::BuiltinRule => f.write_str("cannot redefine built-in rule 'phony'"),
+            WriteError::BuiltinPool => f.write_str("cannot redefine built-in pool 'console'"),
+            WriteError::RspfileMismatch => {
+                f.write_str("rspfile and rspfile_content need to be both specified")
+            }
+            WriteError::UnknownDeps { deps } => write!(f, "unknown deps type '{}'", show(deps)),
+            WriteError::UnknownPool { pool } => write!(f, "unknown pool name '{}'", show(pool)),
+            WriteError::IncompatibleVersion { required } => write!(
+                f,
+                "ninja version ({}) incompatible with ninja_required_version version ({})",
+                ast::VERSION,
+                show(required)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for WriteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WriteError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+enum Part {
+    Text(Blob),
+    Variable(Blob),
+}
+
+/// A variable's value: literal text mixed with references to other
+/// variables.
+pub struct Value {
+    parts: Vec<Part>,
+}
+
+impl Value {
+    pub fn new() -> Value {
+        Value { parts: vec![] }
+    }
+
+    /// A value holding only `text`.
+    pub fn literal(text: &blob::View) -> Value {
+        Value::new().text(text)
+    }
+
+    pub fn text(mut self, text: &blob::View) -> Value {
+        self.parts.push(Part::Text(Blob::new(text)));
+        self
+    }
+
+    pub fn variable(mut self, name: &blob::View) -> Value {
+        self.parts.push(Part::Variable(Blob::new(name)));
+        self
+    }
+
+    /// The text of a value that refers to no variables.
+    fn literal_text(&self) -> Option<Blob> {
+        let mut builder = Builder::new();
+        for part in self.parts.iter() {
+            match part {
+                Part::Text(text) => builder.extend(text),
+                Part::Variable(_) => return None,
+            }
+        }
+        Some(builder.blob())
+    }
+}
+
+impl Default for Value {
+    fn default() -> Value {
+        Value::new()
+    }
+}
+
+pub struct Rule {
+    name: Blob,
+    bindings: Vec<(ast::ReservedBinding, Value)>,
+}
+
+impl Rule {
+    pub fn new(name: &blob::View, command: Value) -> Rule {
+        Rule {
+            name: Blob::new(name),
+            bindings: vec![(ast::ReservedBinding::Command, command)],
+        }
+    }
+
+    /// Sets one of the other variables a rule may bind. Setting a
+    /// variable again replaces its value.
+    pub fn bind(mut self, key: ast::ReservedBinding, value: Value) -> Rule {
+        self.bindings.retain(|(existing, _)| *existing != key);
+        self.bindings.push((key, value));
+        self
+    }
+}
+
+pub struct Build {
+    rule: Blob,
+    outputs: Vec<Blob>,
+    implicit_outputs: Vec<Blob>,
+    inputs: Vec<Blob>,
+    implicit_inputs: Vec<Blob>,
+    order_inputs: Vec<Blob>,
+    validations: Vec<Blob>,
+    bindings: Vec<(Blob, Value)>,
+}
+
+impl Build {
+    pub fn new(outputs: &[Blob], rule: &blob::View, inputs: &[Blob]) -> Build {
+        Build {
+            rule: Blob::new(rule),
+            outputs: outputs.to_vec(),
+            implicit_outputs: vec![],
+            inputs: inputs.to_vec(),
+            implicit_inputs: vec![],
+            order_inputs: vec![],
+            validations: vec![],
+            bindings: vec![],
+        }
+    }
+
+    pub fn implicit_outputs(mut self, paths: &[Blob]) -> Build {
+        self.implicit_outputs.extend_from_slice(paths);
+        self
+    }
+
+    pub fn implicit_inputs(mut self, paths: &[Blob]) -> Build {
+        self.implicit_inputs.extend_from_slice(paths);
+        self
+    }
+
+    pub fn order_inputs(mut self, paths: &[Blob]) -> Build {
+        self.order_inputs.extend_from_slice(paths);
+        self
+    }
+
+    pub fn validations(mut self, paths: &[Blob]) -> Build {
+        self.validations.extend_from_slice(paths);
+        self
+    }
+
+    pub fn bind(mut self, name: &blob::View, value: Value) -> Build {
+        self.bindings.push((Blob::new(name), value));
+        self
+    }
+}
+
+const KEYWORDS: [&blob::View; 6] = [
+    b"build",
+    b"default",
+    b"include",
+    b"pool",
+    b"rule",
+    b"subninja",
+];
+
+fn is_identifier(b: u8) -> bool {
+    fmt::is_bare_identifier(b) || b == b'.'
+}
+
+fn check_name(name: &blob::View) -> Result<(), WriteError> {
+    if name.is_empty() || !name.iter().all(|b| is_identifier(*b)) {
+        let name = Blob::new(name);
+        return Err(WriteError::InvalidName { name });
+    }
+    Ok(())
+}
+
+fn check_text(text: &blob::View, path: bool) -> Result<(), WriteError> {
+    if text.iter().any(|b| *b == b'\n' || (path && *b == b'|')) {
+        let text = Blob::new(text);
+        return Err(WriteError::InvalidText { text });
+    }
+    Ok(())
+}
+
+fn path(path: &blob::View) -> Result<Blob, WriteError> {
+    check_text(path, true)?;
+    if path.is_empty() {
+        let text = Blob::empty();
+        return Err(WriteError::InvalidText { text });
+    }
+    Ok(fmt::escape_path(path))
+}
+
+fn value(value: &Value) -> Result<Blob, WriteError> {
+    let mut builder = Builder::new();
+    for (index, part) in value.parts.iter().enumerate() {
+        match part {
+            Part::Text(text) => {
+                check_text(text, false)?;
+                builder.extend(&fmt::escape(text, false, index == 0));
+            }
+            Part::Variable(name) => {
+                check_name(name)?;
+                let ambiguous = match value.parts.get(index + 1) {
+                    Some(Part::Text(text)) => match text.first() {
+                        Some(b) => fmt::is_bare_identifier(*b),
+                        None => false,
+                    },
+                    _ => false,
+                };
+                let bare = name.iter().all(|b| fmt::is_bare_identifier(*b));
+                if bare && !ambiguous {
+                    builder.extend(b"$");
+                    builder.extend(name);
+                } else {
+                    builder.extend(b"${");
+                    builder.extend(name);
+                    builder.extend(b"}");
+                }
+            }
+        }
+    }
+    Ok(builder.blob())
+}
+
+/// Writes declarations to an `io::Write`, one at a time.
+pub struct Writer<W: io::Write> {
+    out: W,
+    width: usize,
+    /// The values of the top-level variables, for checking bindings that
+    /// the parser evaluates as it reads them.
+    variables: std::collections::HashMap<Blob, Blob>,
+    rules: std::collections::HashSet<Blob>,
+    pools: std::collections::HashSet<Blob>,
+}
+
+impl<W: io::Write> Writer<W> {
+    pub fn new(out: W) -> Writer<W> {
+        Writer {
+            out,
+            width: fmt::Options::default().width,
+            variables: std::collections::HashMap::new(),
+            rules: std::collections::HashSet::new(),
+            pools: std::collections::HashSet::new(),
+        }
+    }
+
+    /// Sets the column at which long `build` and `default` lines are
+    /// wrapped.
+    pub fn width(mut self, width: usize) -> Writer<W> {
+        self.width = width;
+        self
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn write(&mut self, builder: Builder) -> Result<(), WriteError> {
+        match self.out.write_all(&builder.blob()) {
+            Ok(()) => Ok(()),
+            Err(error) => Err(WriteError::Io(error)),
+        }
+    }
+
+    fn binding(
+        builder: &mut Builder,
+        indent: &blob::View,
+        name: &blob::View,
+        value: &Value,
+    ) -> Result<(), WriteError> {
+        check_name(name)?;
+        let value = self::value(value)?;
+        builder.extend(indent);
+        builder.extend(name);
+        builder.extend(b" = ");
+        builder.extend(&value);
+        builder.push(b'\n');
+        Ok(())
+    }
+
+    pub fn newline(&mut self) -> Result<(), WriteError> {
+        let mut builder = Builder::new();
+        builder.push(b'\n');
+        self.write(builder)
+    }
+
+    /// Writes `text` as `#` comments, one per line.
+    pub fn comment(&mut self, text: &blob::View) -> Result<(), WriteError> {
+        let mut builder = Builder::new();
+        for line in text.split(|b| *b == b'\n') {
+            builder.extend(b"#");
+            if !line.is_empty() {
+                builder.push(b' ');
+                builder.extend(line);
+            }
+            builder.push(b'\n');
+        }
+        self.write(builder)
+    }
+
+    /// Writes a top-level variable. Setting it again replaces its value,
+    /// which the new value may refer to.
+    pub fn variable(&mut self, name: &blob::View, value: &Value) -> Result<(), WriteError> {
+        if KEYWORDS.contains(&name) {
+            let name = Blob::new(name);
+            return Err(WriteError::InvalidName { name });
+        }
+        let mut builder = Builder::new();
+        Self::binding(&mut builder, b"", name, value)?;
+        let evaluated = self.evaluate(value);
+        if name == ast::REQUIRED_VERSION && ast::check_version(&evaluated).is_err() {
+            return Err(WriteError::IncompatibleVersion {
+                required: evaluated,
+            });
+        }
+        self.write(builder)?;
+        self.variables.insert(Blob::new(name), evaluated);
+        Ok(())
+    }
+
+    /// Evaluates `value` in the top-level scope.
+    fn evaluate(&self, value: &Value) -> Blob {
+        let mut builder = Builder::new();
+        for part in value.parts.iter() {
+            match part {
+                Part::Text(text) => builder.extend(text),
+                Part::Variable(name) => {
+                    if let Some(value) = self.variables.get(name) {
+                        builder.extend(value);
+                    }
+                }
+            }
+        }
+        builder.blob()
+    }
+
+    /// Checks that `pool` is empty, the console or has been written.
+    fn check_pool(&self, pool: &blob::View) -> Result<(), WriteError> {
+        if pool.is_empty() || pool == ast::CONSOLE || self.pools.contains(pool) {
+            return Ok(());
+        }
+        let pool = Blob::new(pool);
+        Err(WriteError::UnknownPool { pool })
+    }
+
+    pub fn pool(&mut self, name: &blob::View, depth: usize) -> Result<(), WriteError> {
+        check_name(name)?;
+        if name == ast::CONSOLE {
+            return Err(WriteError::BuiltinPool);
+        }
+        if !self.pools.insert(Blob::new(name)) {
+            let name = Blob::new(name);
+            return Err(WriteError::DuplicatePool { name });
+        }
+        let mut builder = Builder::new();
+        builder.extend(b"pool ");
+        builder.extend(name);
+        builder.push(b'\n');
+        let depth = depth.to_string();
+        Self::binding(
+            &mut builder,
+            fmt::INDENT,
+            b"depth",
+            &Value::literal(depth.as_bytes()),
+        )?;
+        self.write(builder)
+    }
+
+    pub fn rule(&mut self, rule: &Rule) -> Result<(), WriteError> {
+        check_name(&rule.name)?;
+        if rule.name.view() == ast::PHONY {
+            return Err(WriteError::BuiltinRule);
+        }
+        let has = |key| rule.bindings.iter().any(|(existing, _)| *existing == key);
+        if has(ast::ReservedBinding::Rspfile) != has(ast::ReservedBinding::RspfileContent) {
+            return Err(WriteError::RspfileMismatch);
+        }
+
+        let mut builder = Builder::new();
+        builder.extend(b"rule ");
+        builder.extend(&rule.name);
+        builder.push(b'\n');
+        for (key, value) in rule.bindings.iter() {
+            Self::binding(&mut builder, fmt::INDENT, key.name(), value)?;
+            // Like the parser, only check settings that are known before
+            // a build evaluates them.
+            let text = match value.literal_text() {
+                Some(text) => text,
+                None => continue,
+            };
+            match key {
+                ast::ReservedBinding::Deps if ast::DepsType::parse(&text).is_err() => {
+                    return Err(WriteError::UnknownDeps { deps: text });
+                }
+                ast::ReservedBinding::Pool => self.check_pool(&text)?,
+                _ => (),
+            }
+        }
+        if !self.rules.insert(rule.name.clone()) {
+            let name = rule.name.clone();
+            return Err(WriteError::DuplicateRule { name });
+        }
+        self.write(builder)
+    }
+
+    pub fn build(&mut self, build: &Build) -> Result<(), WriteError> {
+        check_name(&build.rule)?;
+        if build.outputs.is_empty() {
+            return Err(WriteError::MissingOutput);
+        }
+
+        let mut words = vec![Blob::new(b"build")];
+        for output in build.outputs.iter() {
+            words.push(path(output)?);
+        }
+        if !build.implicit_outputs.is_empty() {
+            words.push(Blob::new(b"|"));
+            for output in build.implicit_outputs.iter() {
+                words.push(path(output)?);
+            }
+        }
+        let last = words.len() - 1;
+        let mut colon = words[last].to_vec();
+        colon.push(b':');
+        words[last] = Blob::from(colon);
+        words.push(build.rule.clone());
+        for input in build.inputs.iter() {
+            words.push(path(input)?);
+        }
+        let sections: [(&blob::View, &[Blob]); 3] = [
+            (b"|", &build.implicit_inputs),
+            (b"||", &build.order_inputs),
+            (b"|@", &build.validations),
+        ];
+        for (separator, paths) in sections.iter() {
+            if !paths.is_empty() {
+                words.push(Blob::new(separator));
+                for input in paths.iter() {
+                    words.push(path(input)?);
+                }
+            }
+        }
+
+        let mut builder = Builder::new();
+        fmt::wrap(&mut builder, &words, self.width);
+        let mut names = std::collections::HashSet::new();
+        for (name, value) in build.bindings.iter() {
+            if !names.insert(name) {
+                let name = name.clone();
+                return Err(WriteError::DuplicateBinding { name });
+            }
+            Self::binding(&mut builder, fmt::INDENT, name, value)?;
+            if name.view() == b"pool" {
+                self.check_pool(&self.evaluate(value))?;
+            }
+        }
+        self.write(builder)
+    }
+
+    pub fn default(&mut self, paths: &[Blob]) -> Result<(), WriteError> {
+        if paths.is_empty() {
+            return Err(WriteError::MissingTarget);
+        }
+        let mut words = vec![Blob::new(b"default")];
+        for target in paths.iter() {
+            words.push(path(target)?);
+        }
+        let mut builder = Builder::new();
+        fmt::wrap(&mut builder, &words, self.width);
+        self.write(builder)
+    }
+
+    pub fn include(&mut self, file: &blob::View) -> Result<(), WriteError> {
+        self.file(b"include ", file)
+    }
+
+    pub fn subninja(&mut self, file: &blob::View) -> Result<(), WriteError> {
+        self.file(b"subninja ", file)
+    }
+
+    fn file(&mut self, keyword: &blob::View, file: &blob::View) -> Result<(), WriteError> {
+        let file = path(file)?;
+        let mut builder = Builder::new();
+        builder.extend(keyword);
+        builder.extend(&file);
+        builder.push(b'\n');
+        self.write(builder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intern;
+    use crate::parse::{MemoryLoader, Parser};
+
+    fn paths(paths: &[&str]) -> Vec<Blob> {
+        paths
+            .iter()
+            .map(|path| Blob::new(path.as_bytes()))
+            .collect()
+    }
+
+    fn written(write: impl FnOnce(&mut Writer<Vec<u8>>) -> Result<(), WriteError>) -> String {
+        let mut writer = Writer::new(vec![]);
+        write(&mut writer).expect("failed to write");
+        String::from_utf8(writer.into_inner()).expect("wrote invalid utf8")
+    }
+
+    #[test]
+    fn declarations() {
+        let ninja = written(|writer| {
+            writer.comment(b"generated\n\ndo not edit")?;
+            writer.variable(b"cflags", &Value::literal(b"-O2 -Wall"))?;
+            writer.pool(b"link", 2)?;
+            let command = Value::literal(b"cc ")
+                .variable(b"cflags")
+                .text(b" -c ")
+                .variable(b"in")
+                .text(b" -o ")
+                .variable(b"out");
+            let description = Value::literal(b"CC ").variable(b"out");
+            writer.rule(
+                &Rule::new(b"cc", command)
+                    .bind(ast::ReservedBinding::Description, description)
+                    .bind(
+                        ast::ReservedBinding::Depfile,
+                        Value::new().variable(b"out").text(b".d"),
+                    ),
+            )?;
+            writer.newline()?;
+            let build = Build::new(&paths(&["a.o"]), b"cc", &paths(&["a.c"]))
+                .implicit_inputs(&paths(&["a.h"]))
+                .order_inputs(&paths(&["gen"]))
+                .validations(&paths(&["check"]))
+                .bind(b"pool", Value::literal(b"link"));
+            writer.build(&build)?;
+            writer.default(&paths(&["a.o"]))?;
+            writer.include(b"rules.ninja")?;
+            writer.subninja(b"sub dir/build.ninja")
+        });
+        assert_eq!(
+            ninja,
+            "# generated\n#\n# do not edit\n\
+             cflags = -O2 -Wall\n\
+             pool link\n  depth = 2\n\
+             rule cc\n  command = cc $cflags -c $in -o $out\n  description = CC $out\n  depfile = $out.d\n\
+             \n\
+             build a.o: cc a.c | a.h || gen |@ check\n  pool = link\n\
+             default a.o\n\
+             include rules.ninja\n\
+             subninja sub$ dir/build.ninja\n"
+        );
+    }
+
+    #[test]
+    fn escaping_parses() {
+        let outputs = paths(&["out $dir/a b:c.o"]);
+        let value = b" leading space, $dollar and: colon";
+        let ninja = written(|writer| {
+            writer.variable(b"x", &Value::literal(value))?;
+            writer.rule(&Rule::new(b"r", Value::literal(b"run")))?;
+            writer.build(&Build::new(&outputs, b"r", &[]))
+        });
+
+        let mut arena = intern::Table::new();
+        let mut loader = MemoryLoader::new();
+        let mut parser = Parser::new(b"build.ninja", ninja.as_bytes());
+        let file = parser
+            .parse(&mut arena, &mut loader)
+            .expect("failed to parse written file");
+        let scopes = file.scopes();
+        let x = crate::lex::Identifier::new(&mut arena, b"x");
+        assert_eq!(scopes.get(scopes.top(), x), Some(&value[..]));
+        let build = file.builds().next().expect("no build");
+        let path = build.outputs()[0].evaluate(scopes, build.scope());
+        assert_eq!(path, outputs[0]);
+    }
+
+    #[test]
+    fn rebinding() {
+        let ninja = written(|writer| {
+            writer.variable(b"cflags", &Value::literal(b"-O2"))?;
+            writer.variable(b"cflags", &Value::new().variable(b"cflags").text(b" -g"))
+        });
+        assert_eq!(ninja, "cflags = -O2\ncflags = $cflags -g\n");
+    }
+
+    #[test]
+    fn wraps() {
+        let mut writer = Writer::new(vec![]).width(20);
+        let build = Build::new(&paths(&["out"]), b"phony", &paths(&["input1", "input2"]));
+        writer.build(&build).expect("failed to write build");
+        assert_eq!(
+            writer.into_inner(),
+            b"build out: phony $\n    input1 input2\n"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid() {
+        let mut writer = Writer::new(vec![]);
+        let command = || Value::literal(b"true");
+        let invalid: Vec<Result<(), WriteError>> = vec![
+            writer.variable(b"build", &Value::new()),
+            writer.variable(b"a b", &Value::new()),
+            writer.variable(b"x", &Value::literal(b"line\nbreak")),
+            writer.variable(b"x", &Value::new().variable(b"")),
+            writer.rule(&Rule::new(b"phony", command())),
+            writer.rule(
+                &Rule::new(b"rsp", command())
+                    .bind(ast::ReservedBinding::Rspfile, Value::literal(b"a.rsp")),
+            ),
+            writer.pool(b"console", 1),
+            writer.build(&Build::new(&[], b"phony", &[])),
+            writer.build(&Build::new(&paths(&["a|b"]), b"phony", &[])),
+            writer.build(&Build::new(&paths(&[""]), b"phony", &[])),
+            writer.build(
+                &Build::new(&paths(&["a"]), b"phony", &[])
+                    .bind(b"x", Value::new())
+                    .bind(b"x", Value::new()),
+            ),
+            writer.default(&paths(&["a\nb"])),
+            writer.default(&[]),
+            writer.rule(
+                &Rule::new(b"deps", command())
+                    .bind(ast::ReservedBinding::Deps, Value::literal(b"bogus")),
+            ),
+            writer.rule(
+                &Rule::new(b"pooled", command())
+                    .bind(ast::ReservedBinding::Pool, Value::literal(b"nope")),
+            ),
+            writer.build(
+                &Build::new(&paths(&["a"]), b"phony", &[]).bind(b"pool", Value::literal(b"nope")),
+            ),
+            writer.variable(ast::REQUIRED_VERSION, &Value::literal(b"99.0")),
+        ];
+        for result in invalid.into_iter() {
+            assert!(result.is_err());
+        }
+        assert!(writer.into_inner().is_empty());
+    }
+
+    #[test]
+    fn rejects_duplicates() {
+        let mut writer = Writer::new(vec![]);
+        writer
+            .rule(&Rule::new(b"cc", Value::literal(b"cc")))
+            .expect("failed to write rule");
+        writer.pool(b"link", 1).expect("failed to write pool");
+        match writer.rule(&Rule::new(b"cc", Value::literal(b"cc"))) {
+            Err(WriteError::DuplicateRule { .. }) => (),
+            _ => panic!("wrote a duplicate rule"),
+        }
+        match writer.pool(b"link", 2) {
+            Err(WriteError::DuplicatePool { .. }) => (),
+            _ => panic!("wrote a duplicate pool"),
+        }
+    }
+}